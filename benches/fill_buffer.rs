@@ -0,0 +1,62 @@
+//! Compares the zero-filled reads of DATA blocks with reads in a buffer
+//! that is initialized only once. Run with:
+//!
+//! `cargo bench --bench fill_buffer`
+
+use bytes::BytesMut;
+use futures_lite::future::block_on;
+use futures_lite::io::Cursor;
+use futures_lite::AsyncReadExt;
+use std::time::{Duration, Instant};
+
+const FILE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Zero-fill the block before each read, as `read_into` does.
+fn zeroed(file: &[u8], block_size: usize) -> Duration {
+    let mut reader = Cursor::new(file);
+    let mut buffer = BytesMut::new();
+    let now = Instant::now();
+
+    for _ in 0..FILE_SIZE / block_size {
+        buffer.resize(block_size, 0);
+        let len = block_on(reader.read(&mut buffer[..])).unwrap();
+        buffer.truncate(len);
+        buffer.clear();
+    }
+
+    now.elapsed()
+}
+
+/// Keep track of the initialized part of the buffer, as `ReadBuf` does,
+/// so the block is zero-filled only the first time.
+fn initialized(file: &[u8], block_size: usize) -> Duration {
+    let mut reader = Cursor::new(file);
+    let mut buffer = Vec::new();
+    let now = Instant::now();
+
+    for _ in 0..FILE_SIZE / block_size {
+        if buffer.len() < block_size {
+            buffer.resize(block_size, 0);
+        }
+        block_on(reader.read(&mut buffer[..block_size])).unwrap();
+    }
+
+    now.elapsed()
+}
+
+fn main() {
+    let file = vec![0x55; FILE_SIZE];
+
+    for block_size in [512, 1428, 8192, 65464] {
+        let zeroed = zeroed(&file, block_size);
+        let initialized = initialized(&file, block_size);
+
+        println!(
+            "block size {:>5}: zeroed {:>8.2?}, initialized {:>8.2?} ({:+.1}%)",
+            block_size,
+            zeroed,
+            initialized,
+            (zeroed.as_secs_f64() / initialized.as_secs_f64() - 1.0) * 100.0
+        );
+    }
+}
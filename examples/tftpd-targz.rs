@@ -37,6 +37,7 @@ impl Handler for TftpdTarGzHandler {
         &mut self,
         _client: &SocketAddr,
         path: &std::path::Path,
+        _req: &packet::RwReq,
     ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
         let req_path = strip_path_prefixes(path.into()).to_owned();
 
@@ -70,6 +71,7 @@ impl Handler for TftpdTarGzHandler {
         &mut self,
         _client: &SocketAddr,
         _path: &std::path::Path,
+        _req: &packet::RwReq,
         _size: Option<u64>,
     ) -> Result<Self::Writer, packet::Error> {
         Err(packet::Error::IllegalOperation)
@@ -0,0 +1,133 @@
+use futures_lite::{pin, AsyncRead};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::read_req::*;
+use super::write_req::*;
+use crate::error::Result;
+use crate::packet::Opts;
+
+/// TFTP client.
+///
+/// # Example
+///
+/// ```ignore
+/// use async_tftp::client::TftpClient;
+/// use async_tftp::packet::Opts;
+/// use futures_lite::AsyncReadExt;
+///
+/// let client = TftpClient::new();
+/// let server = "192.168.1.1:69".parse().unwrap();
+///
+/// let mut reader = client.get(server, "pxelinux.0", Opts::default()).await?;
+/// let mut content = Vec::new();
+/// reader.read_to_end(&mut content).await?;
+///
+/// client.put(server, "upload.bin", &content[..], Opts::default()).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct TftpClient {
+    timeout: Duration,
+    max_send_retries: u32,
+}
+
+impl Default for TftpClient {
+    fn default() -> Self {
+        TftpClient::new()
+    }
+}
+
+impl TftpClient {
+    /// Create new client with the default configuration.
+    pub fn new() -> Self {
+        TftpClient {
+            timeout: Duration::from_secs(3),
+            max_send_retries: 100,
+        }
+    }
+
+    /// Set retry timeout.
+    ///
+    /// If the server negotiates the `timeout` option, its value is used
+    /// instead.
+    ///
+    /// **Default:** 3 seconds
+    pub fn timeout(self, timeout: Duration) -> Self {
+        TftpClient {
+            timeout,
+            ..self
+        }
+    }
+
+    /// Set maximum send retries.
+    ///
+    /// **Default:** 100
+    pub fn max_send_retries(self, retries: u32) -> Self {
+        TftpClient {
+            max_send_retries: retries,
+            ..self
+        }
+    }
+
+    /// Read `filename` from `server`.
+    ///
+    /// `opts` are sent in the read request and the ones that the server
+    /// acknowledges are used for the transfer. The transfer is done in
+    /// `octet` mode.
+    ///
+    /// Returns after the first reply of the server, so errors such as a
+    /// missing file are reported here. The rest of the file is received
+    /// while the returned reader is read.
+    pub async fn get(
+        &self,
+        server: SocketAddr,
+        filename: &str,
+        opts: Opts,
+    ) -> Result<impl AsyncRead + Send + Unpin> {
+        let mut read_req = ReadRequest::init(
+            server,
+            filename,
+            opts,
+            self.timeout,
+            self.max_send_retries,
+        )
+        .await?;
+
+        read_req.handshake().await?;
+
+        Ok(read_req.into_reader())
+    }
+
+    /// Write `filename` to `server` with the content of `reader`.
+    ///
+    /// `opts` are sent in the write request and the ones that the server
+    /// acknowledges are used for the transfer. The transfer is done in
+    /// `octet` mode. The `windowsize` option is not supported for write
+    /// requests and it is never sent.
+    pub async fn put(
+        &self,
+        server: SocketAddr,
+        filename: &str,
+        reader: impl AsyncRead,
+        opts: Opts,
+    ) -> Result<()> {
+        pin!(reader);
+
+        let opts = Opts {
+            window_size: None,
+            ..opts
+        };
+
+        let mut write_req = WriteRequest::init(
+            server,
+            filename,
+            &mut reader,
+            opts,
+            self.timeout,
+            self.max_send_retries,
+        )
+        .await?;
+
+        write_req.handle().await
+    }
+}
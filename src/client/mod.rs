@@ -0,0 +1,8 @@
+//! Client side implementation.
+
+#[allow(clippy::module_inception)]
+mod client;
+mod read_req;
+mod write_req;
+
+pub use self::client::*;
@@ -0,0 +1,305 @@
+use async_io::Async;
+use bytes::{Buf, Bytes};
+use futures_lite::{ready, stream, AsyncRead, Stream};
+use log::trace;
+use std::cmp;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, Result};
+use crate::packet::{Mode, Opts, Packet, RwReq, PACKET_DATA_HEADER_LEN};
+use crate::utils::{block_id_add, io_timeout};
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+pub(crate) struct ReadRequest {
+    server: SocketAddr,
+    // Transfer identifier of the server, known after its first reply.
+    peer: Option<SocketAddr>,
+    socket: Async<UdpSocket>,
+    // The last packet that we sent, retransmitted on timeout.
+    last_packet: Bytes,
+    // The last acknowledged block, if `last_packet` is its ACK.
+    acked_block: Option<u16>,
+    // The first block, received during the handshake.
+    pending_block: Option<Bytes>,
+    buffer: Vec<u8>,
+    block_size: usize,
+    window_size: usize,
+    rollover: u16,
+    timeout: Duration,
+    max_send_retries: u32,
+    next_block_id: u16,
+    // Blocks received in the current window.
+    window_len: usize,
+    done: bool,
+}
+
+impl ReadRequest {
+    pub(crate) async fn init(
+        server: SocketAddr,
+        filename: &str,
+        opts: Opts,
+        timeout: Duration,
+        max_send_retries: u32,
+    ) -> Result<ReadRequest> {
+        let addr: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = Async::<UdpSocket>::bind(addr).map_err(Error::Bind)?;
+
+        // Server may not accept the requested block size, so we need
+        // space for at least the default one.
+        let max_block_size = opts
+            .block_size
+            .map(usize::from)
+            .map_or(DEFAULT_BLOCK_SIZE, |b| cmp::max(b, DEFAULT_BLOCK_SIZE));
+
+        let req = RwReq {
+            filename: filename.to_string(),
+            mode: Mode::Octet,
+            opts,
+        };
+
+        Ok(ReadRequest {
+            server,
+            peer: None,
+            socket,
+            last_packet: Packet::Rrq(req).to_bytes(),
+            acked_block: None,
+            pending_block: None,
+            buffer: vec![0u8; PACKET_DATA_HEADER_LEN + max_block_size],
+            block_size: DEFAULT_BLOCK_SIZE,
+            window_size: 1,
+            rollover: 0,
+            timeout,
+            max_send_retries,
+            next_block_id: 1,
+            window_len: 0,
+            done: false,
+        })
+    }
+
+    /// Send the request and wait for the first reply of the server.
+    pub(crate) async fn handshake(&mut self) -> Result<()> {
+        trace!("RRQ (server: {}) - Sending request", &self.server);
+        self.socket.send_to(&self.last_packet, self.server).await?;
+        self.pending_block = self.next_block().await?;
+        Ok(())
+    }
+
+    pub(crate) fn into_reader(self) -> impl AsyncRead + Send + Unpin {
+        let blocks = stream::try_unfold(self, |mut read_req| async move {
+            match read_req.next_block().await {
+                Ok(Some(block)) => Ok(Some((block, read_req))),
+                Ok(None) => Ok(None),
+                Err(Error::Io(e)) => Err(e),
+                Err(e) => Err(io::Error::other(e)),
+            }
+        });
+
+        BlockReader {
+            blocks: Box::pin(blocks),
+            block: Bytes::new(),
+        }
+    }
+
+    /// Receive the next block of the file.
+    ///
+    /// Returns `None` when transfer is completed.
+    async fn next_block(&mut self) -> Result<Option<Bytes>> {
+        if let Some(block) = self.pending_block.take() {
+            return Ok(Some(block));
+        }
+
+        let mut retries = 0;
+        // Ignored packets do not restart the timeout, otherwise a server
+        // that keeps retransmitting would prevent ours.
+        let mut deadline = Instant::now() + self.timeout;
+
+        while !self.done {
+            match self.recv_packet(deadline).await {
+                Ok(len) => {
+                    if let Some(block) = self.handle_packet(len).await? {
+                        return Ok(Some(block));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    if retries == self.max_send_retries {
+                        return Err(Error::MaxSendRetriesReached(
+                            self.peer.unwrap_or(self.server),
+                            self.next_block_id,
+                        ));
+                    }
+
+                    trace!(
+                        "RRQ (server: {}, block_id: {}) - Timeout",
+                        &self.server,
+                        self.next_block_id
+                    );
+
+                    retries += 1;
+                    // Server starts a new window when it receives our
+                    // retransmission.
+                    self.window_len = 0;
+                    deadline = Instant::now() + self.timeout;
+
+                    let peer = self.peer.unwrap_or(self.server);
+                    self.socket.send_to(&self.last_packet, peer).await?;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Handle a received packet.
+    ///
+    /// Returns the payload if the packet is the next Data packet.
+    async fn handle_packet(&mut self, len: usize) -> Result<Option<Bytes>> {
+        let (block_id, block) = match Packet::decode(&self.buffer[..len]) {
+            Ok(Packet::Data(block_id, data))
+                if block_id == self.next_block_id =>
+            {
+                (block_id, Bytes::copy_from_slice(data))
+            }
+            // Server may retransmit OACK if it did not receive our ACK.
+            Ok(Packet::OAck(opts)) if self.next_block_id == 1 => {
+                trace!("RRQ (server: {}) - OACK: {:?}", &self.server, &opts);
+                self.set_opts(&opts);
+                self.send_ack(0).await?;
+                return Ok(None);
+            }
+            // Server retransmits the last acknowledged block if it did not
+            // receive our ACK, so there is no need to wait for a timeout.
+            Ok(Packet::Data(block_id, _))
+                if Some(block_id) == self.acked_block =>
+            {
+                trace!(
+                    "RRQ (server: {}, block_id: {}) - Retransmitting ACK",
+                    &self.server,
+                    block_id
+                );
+                let peer = self.peer.unwrap_or(self.server);
+                self.socket.send_to(&self.last_packet, peer).await?;
+                return Ok(None);
+            }
+            Ok(Packet::Error(e)) => return Err(Error::Packet(e)),
+            // Anything else, including older retransmitted blocks, is
+            // ignored.
+            _ => return Ok(None),
+        };
+
+        let is_last_block = block.len() < self.block_size;
+        self.window_len += 1;
+
+        if is_last_block || self.window_len == self.window_size {
+            self.send_ack(block_id).await?;
+            self.window_len = 0;
+        }
+
+        self.next_block_id = block_id_add(block_id, 1, self.rollover);
+        self.done = is_last_block;
+
+        Ok(Some(block))
+    }
+
+    async fn recv_packet(&mut self, deadline: Instant) -> io::Result<usize> {
+        // We can not use `self` within `io_timeout` because not all
+        // struct members implement `Sync`. So we borrow only what we need.
+        let socket = &mut self.socket;
+        let buf = &mut self.buffer;
+        let server = self.server;
+        let peer = &mut self.peer;
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        io_timeout(timeout, async {
+            loop {
+                let (len, recved_peer) = socket.recv_from(&mut buf[..]).await?;
+
+                match peer {
+                    // Ignore packets of other transfers
+                    Some(peer) if *peer != recved_peer => continue,
+                    Some(_) => {}
+                    // Server replies from a new port, which is the
+                    // transfer identifier that we follow from now on.
+                    None if recved_peer.ip() != server.ip() => continue,
+                    None => *peer = Some(recved_peer),
+                }
+
+                return Ok(len);
+            }
+        })
+        .await
+    }
+
+    async fn send_ack(&mut self, block_id: u16) -> Result<()> {
+        let peer = self.peer.unwrap_or(self.server);
+
+        self.last_packet = Packet::Ack(block_id).to_bytes();
+        self.acked_block = Some(block_id);
+        self.socket.send_to(&self.last_packet, peer).await?;
+
+        Ok(())
+    }
+
+    fn set_opts(&mut self, opts: &Opts) {
+        if let Some(block_size) = opts.block_size {
+            self.block_size = usize::from(block_size);
+
+            let len = PACKET_DATA_HEADER_LEN + self.block_size;
+            if self.buffer.len() < len {
+                self.buffer.resize(len, 0);
+            }
+        }
+
+        if let Some(window_size) = opts.window_size {
+            self.window_size = usize::from(window_size);
+        }
+
+        if let Some(rollover) = opts.rollover {
+            self.rollover = rollover;
+        }
+
+        if let Some(timeout) = opts.timeout {
+            self.timeout = Duration::from_secs(u64::from(timeout));
+        }
+    }
+}
+
+/// Reader over the received blocks.
+struct BlockReader<S> {
+    blocks: Pin<Box<S>>,
+    block: Bytes,
+}
+
+impl<S> AsyncRead for BlockReader<S>
+where
+    S: Stream<Item = io::Result<Bytes>>,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.block.is_empty() {
+            match ready!(self.blocks.as_mut().poll_next(cx)) {
+                Some(Ok(block)) => self.block = block,
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = cmp::min(buf.len(), self.block.len());
+        buf[..len].copy_from_slice(&self.block[..len]);
+        self.block.advance(len);
+
+        Poll::Ready(Ok(len))
+    }
+}
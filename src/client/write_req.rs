@@ -0,0 +1,220 @@
+use async_io::Async;
+use bytes::{Bytes, BytesMut};
+use futures_lite::{AsyncRead, AsyncReadExt};
+use log::trace;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::packet::{self, Mode, Opts, Packet, RwReq, PACKET_DATA_HEADER_LEN};
+use crate::utils::{block_id_add, io_timeout};
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+pub(crate) struct WriteRequest<'r, R>
+where
+    R: AsyncRead + Unpin,
+{
+    server: SocketAddr,
+    // Transfer identifier of the server, known after its first reply.
+    peer: Option<SocketAddr>,
+    socket: Async<UdpSocket>,
+    reader: &'r mut R,
+    req: Option<RwReq>,
+    buffer: BytesMut,
+    recv_buffer: Vec<u8>,
+    block_size: usize,
+    rollover: u16,
+    timeout: Duration,
+    max_send_retries: u32,
+}
+
+enum Reply {
+    Ack,
+    OAck(Opts),
+    Error(packet::Error),
+}
+
+impl<'r, R> WriteRequest<'r, R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub(crate) async fn init(
+        server: SocketAddr,
+        filename: &str,
+        reader: &'r mut R,
+        opts: Opts,
+        timeout: Duration,
+        max_send_retries: u32,
+    ) -> Result<WriteRequest<'r, R>> {
+        let addr: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = Async::<UdpSocket>::bind(addr).map_err(Error::Bind)?;
+
+        let req = RwReq {
+            filename: filename.to_string(),
+            mode: Mode::Octet,
+            opts,
+        };
+
+        Ok(WriteRequest {
+            server,
+            peer: None,
+            socket,
+            reader,
+            req: Some(req),
+            buffer: BytesMut::new(),
+            // Replies are ACK, OACK or ERROR packets.
+            recv_buffer: vec![0u8; PACKET_DATA_HEADER_LEN + DEFAULT_BLOCK_SIZE],
+            block_size: DEFAULT_BLOCK_SIZE,
+            rollover: 0,
+            timeout,
+            max_send_retries,
+        })
+    }
+
+    pub(crate) async fn handle(&mut self) -> Result<()> {
+        if let Some(req) = self.req.take() {
+            let wrq = Packet::Wrq(req).to_bytes();
+
+            // Server replies with ACK(0) or OACK
+            if let Some(opts) = self.send_packet(wrq, 0).await? {
+                trace!("WRQ (server: {}) - OACK: {:?}", &self.server, &opts);
+                self.set_opts(&opts);
+            }
+        }
+
+        let mut block_id: u16 = 1;
+
+        loop {
+            let (packet, is_last_block) =
+                self.fill_data_block(block_id).await?;
+
+            self.send_packet(packet, block_id).await?;
+
+            if is_last_block {
+                break;
+            }
+
+            block_id = block_id_add(block_id, 1, self.rollover);
+        }
+
+        trace!("WRQ (server: {}) - Request served", &self.server);
+        Ok(())
+    }
+
+    /// Read the next block from reader and encode it as a Data packet.
+    ///
+    /// Returns the packet and whether it is the last block.
+    async fn fill_data_block(
+        &mut self,
+        block_id: u16,
+    ) -> Result<(Bytes, bool)> {
+        Packet::encode_data_head(block_id, &mut self.buffer);
+        self.buffer.resize(PACKET_DATA_HEADER_LEN + self.block_size, 0);
+
+        let data_buf = &mut self.buffer[PACKET_DATA_HEADER_LEN..];
+        let mut len = 0;
+
+        while len < data_buf.len() {
+            match self.reader.read(&mut data_buf[len..]).await? {
+                0 => break,
+                x => len += x,
+            }
+        }
+
+        self.buffer.truncate(PACKET_DATA_HEADER_LEN + len);
+        Ok((self.buffer.split().freeze(), len < self.block_size))
+    }
+
+    /// Send packet until it is acknowledged.
+    ///
+    /// Returns the options if server replied with OACK.
+    async fn send_packet(
+        &mut self,
+        packet: Bytes,
+        block_id: u16,
+    ) -> Result<Option<Opts>> {
+        for _ in 0..=self.max_send_retries {
+            let peer = self.peer.unwrap_or(self.server);
+            self.socket.send_to(&packet[..], peer).await?;
+
+            match self.recv_reply(block_id).await {
+                Ok(Reply::Ack) => return Ok(None),
+                Ok(Reply::OAck(opts)) => return Ok(Some(opts)),
+                Ok(Reply::Error(e)) => return Err(Error::Packet(e)),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    trace!(
+                        "WRQ (server: {}, block_id: {}) - Timeout",
+                        &self.server,
+                        block_id
+                    );
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Err(Error::MaxSendRetriesReached(
+            self.peer.unwrap_or(self.server),
+            block_id,
+        ))
+    }
+
+    async fn recv_reply(&mut self, block_id: u16) -> io::Result<Reply> {
+        // We can not use `self` within `io_timeout` because not all
+        // struct members implement `Sync`. So we borrow only what we need.
+        let socket = &mut self.socket;
+        let buf = &mut self.recv_buffer;
+        let server = self.server;
+        let peer = &mut self.peer;
+
+        io_timeout(self.timeout, async {
+            loop {
+                let (len, recved_peer) = socket.recv_from(&mut buf[..]).await?;
+
+                match peer {
+                    // Ignore packets of other transfers
+                    Some(peer) if *peer != recved_peer => continue,
+                    Some(_) => {}
+                    // Server replies from a new port, which is the
+                    // transfer identifier that we follow from now on.
+                    None if recved_peer.ip() != server.ip() => continue,
+                    None => *peer = Some(recved_peer),
+                }
+
+                match Packet::decode(&buf[..len]) {
+                    Ok(Packet::Ack(id)) if id == block_id => {
+                        return Ok(Reply::Ack)
+                    }
+                    Ok(Packet::OAck(opts)) if block_id == 0 => {
+                        return Ok(Reply::OAck(opts))
+                    }
+                    Ok(Packet::Error(e)) => return Ok(Reply::Error(e)),
+                    // Duplicate ACKs are ignored and they do not trigger a
+                    // retransmission, otherwise every block would be sent
+                    // twice from now on (Sorcerer's Apprentice Syndrome).
+                    _ => {}
+                }
+            }
+        })
+        .await
+    }
+
+    fn set_opts(&mut self, opts: &Opts) {
+        if let Some(block_size) = opts.block_size {
+            self.block_size = usize::from(block_size);
+        }
+
+        if let Some(rollover) = opts.rollover {
+            self.rollover = rollover;
+        }
+
+        if let Some(timeout) = opts.timeout {
+            self.timeout = Duration::from_secs(u64::from(timeout));
+        }
+    }
+}
@@ -23,4 +23,20 @@ pub enum Error {
 
     #[error("Max send retries reached (peer: {0},  block id: {1})")]
     MaxSendRetriesReached(std::net::SocketAddr, u16),
+
+    #[error("Transfer was idle for too long (peer: {0})")]
+    IdleTimeout(std::net::SocketAddr),
+
+    #[error("Max total retransmits reached (peer: {0})")]
+    MaxRetransmitsReached(std::net::SocketAddr),
+
+    #[error("Client aborted the transfer (peer: {peer}, error: {code:?})")]
+    ClientAborted {
+        /// Address of the client.
+        peer: std::net::SocketAddr,
+        /// Error that the client sent.
+        code: crate::packet::Error,
+        /// Options of the aborted request.
+        requested: Box<crate::packet::Opts>,
+    },
 }
@@ -1,6 +1,7 @@
 //! Packet definitions.
 use bytes::{BufMut, Bytes, BytesMut};
 use std::convert::From;
+use std::fmt;
 use std::io;
 use std::str;
 
@@ -8,6 +9,8 @@ use crate::error::Result;
 use crate::parse::*;
 
 pub(crate) const PACKET_DATA_HEADER_LEN: usize = 4;
+/// Default limit of the message of an encoded ERROR packet, in bytes.
+pub(crate) const DEFAULT_MAX_ERROR_MESSAGE_LEN: usize = 255;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
@@ -21,7 +24,7 @@ pub(crate) enum PacketType {
 }
 
 /// TFTP protocol error. Should not be confused with `async_tftp::Error`.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     Msg(String),
     UnknownError,
@@ -32,6 +35,66 @@ pub enum Error {
     UnknownTransferId,
     FileAlreadyExists,
     NoSuchUser,
+    OptionNegotiationFailed,
+}
+
+/// Decode a TFTP packet.
+///
+/// This is the parser that the server and the client use, exposed for
+/// tooling and fuzzing. It returns [`Error::InvalidPacket`] for malformed
+/// packets.
+///
+/// [`Error::InvalidPacket`]: crate::Error::InvalidPacket
+pub fn decode(data: &[u8]) -> Result<OwnedPacket> {
+    Packet::decode(data).map(OwnedPacket::from)
+}
+
+/// Decoded TFTP packet that owns its data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedPacket {
+    /// Read request.
+    Rrq(RwReq),
+    /// Write request.
+    Wrq(RwReq),
+    /// Data block with its block number.
+    Data(u16, Vec<u8>),
+    /// Acknowledgment of a block number.
+    Ack(u16),
+    /// Error.
+    Error(Error),
+    /// Option acknowledgment (RFC 2347).
+    OAck(Opts),
+}
+
+impl OwnedPacket {
+    /// Encode the packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let packet = match self {
+            OwnedPacket::Rrq(req) => Packet::Rrq(req.clone()),
+            OwnedPacket::Wrq(req) => Packet::Wrq(req.clone()),
+            OwnedPacket::Data(block_id, data) => Packet::Data(*block_id, data),
+            OwnedPacket::Ack(block_id) => Packet::Ack(*block_id),
+            OwnedPacket::Error(e) => Packet::Error(e.clone()),
+            OwnedPacket::OAck(opts) => Packet::OAck(opts.clone()),
+        };
+
+        packet.to_bytes().to_vec()
+    }
+}
+
+impl From<Packet<'_>> for OwnedPacket {
+    fn from(packet: Packet<'_>) -> Self {
+        match packet {
+            Packet::Rrq(req) => OwnedPacket::Rrq(req),
+            Packet::Wrq(req) => OwnedPacket::Wrq(req),
+            Packet::Data(block_id, data) => {
+                OwnedPacket::Data(block_id, data.to_vec())
+            }
+            Packet::Ack(block_id) => OwnedPacket::Ack(block_id),
+            Packet::Error(e) => OwnedPacket::Error(e),
+            Packet::OAck(opts) => OwnedPacket::OAck(opts),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -44,25 +107,48 @@ pub(crate) enum Packet<'a> {
     OAck(Opts),
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) enum Mode {
+/// Transfer mode of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Text transfer (RFC 1350). Line endings are translated to `\r\n`.
     Netascii,
+    /// Binary transfer.
     Octet,
+    /// Obsolete mail transfer (RFC 1350).
     Mail,
 }
 
-#[derive(Debug, PartialEq)]
-pub(crate) struct RwReq {
-    pub filename: String,
-    pub mode: Mode,
-    pub opts: Opts,
+/// Read or write request, as client sent it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RwReq {
+    pub(crate) filename: String,
+    pub(crate) mode: Mode,
+    pub(crate) opts: Opts,
 }
 
+/// Options of a request (RFC 2347).
+///
+/// `None` means that the option is not requested or acknowledged.
 #[derive(Debug, Clone, Default, PartialEq)]
-pub(crate) struct Opts {
+pub struct Opts {
+    /// Block size (RFC 2348).
     pub block_size: Option<u16>,
+    /// Timeout in seconds (RFC 2349).
     pub timeout: Option<u8>,
+    /// Transfer size (RFC 2349).
     pub transfer_size: Option<u64>,
+    /// Window size (RFC 7440).
+    pub window_size: Option<u16>,
+    /// Block number that follows block 65535.
+    pub rollover: Option<u16>,
+    /// Number of blocks to skip when a read is resumed (vendor `offset`
+    /// option).
+    pub offset: Option<u64>,
+    /// Options that are not recognized, as pairs of name and value.
+    ///
+    /// They are kept only for inspection and they are never encoded, so
+    /// they are not sent in requests and they are not acknowledged in OACK.
+    pub unknown: Vec<(String, String)>,
 }
 
 impl PacketType {
@@ -86,7 +172,7 @@ impl From<PacketType> for u16 {
 }
 
 impl<'a> Packet<'a> {
-    pub(crate) fn decode(data: &[u8]) -> Result<Packet> {
+    pub(crate) fn decode(data: &[u8]) -> Result<Packet<'_>> {
         parse_packet(data)
     }
 
@@ -118,10 +204,7 @@ impl<'a> Packet<'a> {
                 buf.put_u16(*block);
             }
             Packet::Error(error) => {
-                buf.put_u16(PacketType::Error.into());
-                buf.put_u16(error.code());
-                buf.put_slice(error.msg().as_bytes());
-                buf.put_u8(0);
+                Packet::encode_error(error, DEFAULT_MAX_ERROR_MESSAGE_LEN, buf)
             }
             Packet::OAck(opts) => {
                 buf.put_u16(PacketType::OAck.into());
@@ -130,6 +213,40 @@ impl<'a> Packet<'a> {
         }
     }
 
+    /// Encode an ERROR packet with a message of at most `max_len` bytes.
+    ///
+    /// Control characters of the message are replaced with spaces, so a
+    /// NUL does not end it early, and it is truncated at a character
+    /// boundary.
+    pub(crate) fn encode_error(
+        error: &Error,
+        max_len: usize,
+        buf: &mut BytesMut,
+    ) {
+        buf.put_u16(PacketType::Error.into());
+        buf.put_u16(error.code());
+
+        let mut len = 0;
+
+        for c in error.message().chars() {
+            let c = if c.is_control() {
+                ' '
+            } else {
+                c
+            };
+
+            len += c.len_utf8();
+            if len > max_len {
+                break;
+            }
+
+            let mut utf8 = [0u8; 4];
+            buf.put_slice(c.encode_utf8(&mut utf8).as_bytes());
+        }
+
+        buf.put_u8(0);
+    }
+
     pub(crate) fn encode_data_head(block_id: u16, buf: &mut BytesMut) {
         buf.put_u16(PacketType::Data.into());
         buf.put_u16(block_id);
@@ -161,11 +278,58 @@ impl Opts {
             buf.put_slice(transfer_size.to_string().as_bytes());
             buf.put_u8(0);
         }
+
+        if let Some(window_size) = self.window_size {
+            buf.put_slice(&b"windowsize\0"[..]);
+            buf.put_slice(window_size.to_string().as_bytes());
+            buf.put_u8(0);
+        }
+
+        if let Some(rollover) = self.rollover {
+            buf.put_slice(&b"rollover\0"[..]);
+            buf.put_slice(rollover.to_string().as_bytes());
+            buf.put_u8(0);
+        }
+
+        if let Some(offset) = self.offset {
+            buf.put_slice(&b"offset\0"[..]);
+            buf.put_slice(offset.to_string().as_bytes());
+            buf.put_u8(0);
+        }
+    }
+}
+
+impl RwReq {
+    /// Create a request.
+    pub fn new(filename: impl Into<String>, mode: Mode, opts: Opts) -> Self {
+        RwReq {
+            filename: filename.into(),
+            mode,
+            opts,
+        }
+    }
+
+    /// Requested filename.
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    /// Requested transfer mode.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Requested options.
+    ///
+    /// These are the options that client requested, the ones that server
+    /// acknowledges may differ.
+    pub fn opts(&self) -> &Opts {
+        &self.opts
     }
 }
 
 impl Mode {
-    pub(crate) fn to_str(&self) -> &'static str {
+    pub(crate) fn to_str(self) -> &'static str {
         match self {
             Mode::Netascii => "netascii",
             Mode::Octet => "octet",
@@ -185,6 +349,7 @@ impl Error {
             5 => Error::UnknownTransferId,
             6 => Error::FileAlreadyExists,
             7 => Error::NoSuchUser,
+            8 => Error::OptionNegotiationFailed,
             0 | _ => match msg {
                 Some(msg) => Error::Msg(msg.to_string()),
                 None => Error::UnknownError,
@@ -192,7 +357,10 @@ impl Error {
         }
     }
 
-    pub(crate) fn code(&self) -> u16 {
+    /// Error code of the ERROR packet (RFC1350 and RFC2347).
+    ///
+    /// [`Error::Msg`] and [`Error::UnknownError`] are both code 0.
+    pub fn code(&self) -> u16 {
         match self {
             Error::Msg(..) => 0,
             Error::UnknownError => 0,
@@ -203,10 +371,12 @@ impl Error {
             Error::UnknownTransferId => 5,
             Error::FileAlreadyExists => 6,
             Error::NoSuchUser => 7,
+            Error::OptionNegotiationFailed => 8,
         }
     }
 
-    pub(crate) fn msg(&self) -> &str {
+    /// Error message of the ERROR packet.
+    pub fn message(&self) -> &str {
         match self {
             Error::Msg(msg) => msg,
             Error::UnknownError => "Unknown error",
@@ -217,10 +387,19 @@ impl Error {
             Error::UnknownTransferId => "Unknown transfer ID",
             Error::FileAlreadyExists => "File already exists",
             Error::NoSuchUser => "No such user",
+            Error::OptionNegotiationFailed => "Option negotiation failed",
         }
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<Error> for Packet<'_> {
     fn from(inner: Error) -> Self {
         Packet::Error(inner)
@@ -229,6 +408,15 @@ impl From<Error> for Packet<'_> {
 
 impl From<io::Error> for Error {
     fn from(io_err: io::Error) -> Self {
+        Error::from(&io_err)
+    }
+}
+
+/// Maps `NotFound`, `PermissionDenied`, `WriteZero` and `AlreadyExists` to
+/// their TFTP error codes. Other errors are reported with their OS error
+/// code if they have one, or as [`Error::UnknownError`].
+impl From<&io::Error> for Error {
+    fn from(io_err: &io::Error) -> Self {
         match io_err.kind() {
             io::ErrorKind::NotFound => Error::FileNotFound,
             io::ErrorKind::PermissionDenied => Error::PermissionDenied,
@@ -246,11 +434,26 @@ impl From<crate::Error> for Error {
     fn from(err: crate::Error) -> Self {
         match err {
             crate::Error::Packet(e) => e,
+            err => Error::from(&err),
+        }
+    }
+}
+
+impl From<&crate::Error> for Error {
+    fn from(err: &crate::Error) -> Self {
+        match err {
+            crate::Error::Packet(e) => e.clone(),
             crate::Error::Io(e) => e.into(),
             crate::Error::InvalidPacket => Error::IllegalOperation,
             crate::Error::MaxSendRetriesReached(..) => {
                 Error::Msg("Max retries reached".to_string())
             }
+            crate::Error::IdleTimeout(..) => {
+                Error::Msg("Transfer timed out".to_string())
+            }
+            crate::Error::MaxRetransmitsReached(..) => {
+                Error::Msg("Too many retransmissions".to_string())
+            }
             _ => Error::UnknownError,
         }
     }
@@ -6,7 +6,7 @@ use crate::packet::{
     Error as PacketError, Mode, Opts, Packet, PacketType, RwReq,
 };
 
-pub(crate) fn parse_packet(input: &[u8]) -> Result<Packet> {
+pub(crate) fn parse_packet(input: &[u8]) -> Result<Packet<'_>> {
     parse_packet_type(input)
         .and_then(|(packet_type, data)| match packet_type {
             PacketType::Rrq => parse_rrq(data),
@@ -76,6 +76,24 @@ pub(crate) fn parse_opts(mut input: &[u8]) -> Option<Opts> {
             if let Ok(val) = u64::from_str(val) {
                 opts.transfer_size = Some(val);
             }
+        } else if name.eq_ignore_ascii_case("windowsize") {
+            if let Ok(val) = u16::from_str(val) {
+                if val >= 1 {
+                    opts.window_size = Some(val);
+                }
+            }
+        } else if name.eq_ignore_ascii_case("rollover") {
+            if let Ok(val) = u16::from_str(val) {
+                if val <= 1 {
+                    opts.rollover = Some(val);
+                }
+            }
+        } else if name.eq_ignore_ascii_case("offset") {
+            if let Ok(val) = u64::from_str(val) {
+                opts.offset = Some(val);
+            }
+        } else {
+            opts.unknown.push((name.to_owned(), val.to_owned()));
         }
 
         input = rest;
@@ -84,11 +102,15 @@ pub(crate) fn parse_opts(mut input: &[u8]) -> Option<Opts> {
     Some(opts)
 }
 
-fn parse_rrq(input: &[u8]) -> Option<Packet> {
+fn parse_rrq(input: &[u8]) -> Option<Packet<'_>> {
     let (filename, rest) = parse_nul_str(input)?;
     let (mode, rest) = parse_mode(rest)?;
     let opts = parse_opts(rest)?;
 
+    if filename.is_empty() {
+        return None;
+    }
+
     Some(Packet::Rrq(RwReq {
         filename: filename.to_owned(),
         mode,
@@ -96,11 +118,15 @@ fn parse_rrq(input: &[u8]) -> Option<Packet> {
     }))
 }
 
-fn parse_wrq(input: &[u8]) -> Option<Packet> {
+fn parse_wrq(input: &[u8]) -> Option<Packet<'_>> {
     let (filename, rest) = parse_nul_str(input)?;
     let (mode, rest) = parse_mode(rest)?;
     let opts = parse_opts(rest)?;
 
+    if filename.is_empty() {
+        return None;
+    }
+
     Some(Packet::Wrq(RwReq {
         filename: filename.to_owned(),
         mode,
@@ -108,12 +134,12 @@ fn parse_wrq(input: &[u8]) -> Option<Packet> {
     }))
 }
 
-fn parse_data(input: &[u8]) -> Option<Packet> {
+fn parse_data(input: &[u8]) -> Option<Packet<'_>> {
     let (block_nr, rest) = parse_u16_be(input)?;
     Some(Packet::Data(block_nr, rest))
 }
 
-fn parse_ack(input: &[u8]) -> Option<Packet> {
+fn parse_ack(input: &[u8]) -> Option<Packet<'_>> {
     let (block_nr, rest) = parse_u16_be(input)?;
 
     if !rest.is_empty() {
@@ -123,7 +149,7 @@ fn parse_ack(input: &[u8]) -> Option<Packet> {
     Some(Packet::Ack(block_nr))
 }
 
-fn parse_error(input: &[u8]) -> Option<Packet> {
+fn parse_error(input: &[u8]) -> Option<Packet<'_>> {
     let (code, rest) = parse_u16_be(input)?;
     let (msg, rest) = parse_nul_str(rest)?;
 
@@ -134,7 +160,7 @@ fn parse_error(input: &[u8]) -> Option<Packet> {
     Some(Packet::Error(PacketError::from_code(code, Some(msg))))
 }
 
-fn parse_oack(input: &[u8]) -> Option<Packet> {
+fn parse_oack(input: &[u8]) -> Option<Packet<'_>> {
     let opts = parse_opts(input)?;
     Some(Packet::OAck(opts))
 }
@@ -1,15 +1,29 @@
+use async_channel::Sender;
 use async_executor::Executor;
 use async_io::Async;
-use async_lock::Mutex;
-use std::collections::HashSet;
+use async_lock::{Mutex, RwLock, Semaphore};
+use log::warn;
+use socket2::SockRef;
+use std::cmp;
+use std::collections::HashMap;
 use std::net::{SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
-use super::handlers::{DirHandler, DirHandlerMode};
-use super::{Handler, ServerConfig, TftpServer};
+use super::handlers::{DirHandler, DirHandlerMode, ReadFnHandler};
+use super::rate_limit::RateLimiter;
+use super::{
+    AllowClientFn, Events, Handler, RetransmitFn, ServerConfig, ServerEvent,
+    TftpServer, UnexpectedPacketPolicy, MAX_BLOCK_SIZE, MIN_BLOCK_SIZE,
+    UNEXPECTED_REPLY_RATE,
+};
 use crate::error::{Error, Result};
+use crate::packet::{
+    self, DEFAULT_MAX_ERROR_MESSAGE_LEN, PACKET_DATA_HEADER_LEN,
+};
+use crate::utils::{bind_udp, set_socket_opts, SocketOpts};
 
 /// TFTP server builder.
 pub struct TftpServerBuilder<H: Handler> {
@@ -18,9 +32,68 @@ pub struct TftpServerBuilder<H: Handler> {
     socket: Option<Async<UdpSocket>>,
     timeout: Duration,
     block_size_limit: Option<u16>,
+    block_size_min: u16,
+    path_mtu: Option<u16>,
+    auto_mtu_clamp: bool,
+    max_block_size_buffer: Option<usize>,
+    window_size_limit: Option<u16>,
+    default_window_size: Option<u16>,
+    read_ahead: usize,
     max_send_retries: u32,
+    read_max_retries: Option<u32>,
+    write_max_retries: Option<u32>,
+    idle_timeout: Option<Duration>,
+    max_total_retransmits: Option<u64>,
+    on_retransmit: Option<RetransmitFn>,
+    timeout_jitter: Option<Duration>,
+    rate_limit: Option<u64>,
+    per_client_rate_limit: Option<u64>,
+    max_tasks: Option<usize>,
+    max_pending_tasks: Option<usize>,
     ignore_client_timeout: bool,
     ignore_client_block_size: bool,
+    ignore_client_window_size: bool,
+    no_oack: bool,
+    tolerant_transfer_size: bool,
+    tolerant_ack: bool,
+    strict_options: bool,
+    unexpected_packet_policy: UnexpectedPacketPolicy,
+    max_error_message_len: usize,
+    max_filename_len: usize,
+    masked_error: Option<packet::Error>,
+    allow_client: Option<AllowClientFn>,
+    drop_denied_clients: bool,
+    drop_requests_on_shutdown: bool,
+    reply_unknown_tid: bool,
+    single_port: bool,
+    restart_on_new_request: bool,
+    socket_opts: SocketOpts,
+    transfer_port_range: Option<RangeInclusive<u16>>,
+    events: Events,
+}
+
+impl<F> TftpServerBuilder<ReadFnHandler<F>>
+where
+    ReadFnHandler<F>: Handler,
+{
+    /// Create new builder that serves read requests with `read_fn`.
+    ///
+    /// `read_fn` gets the address of the client and the requested path,
+    /// and returns the reader of the file and its size, if it is known.
+    /// Write requests are rejected.
+    ///
+    /// ```ignore
+    /// let tftpd = TftpServerBuilder::serve_read_fn(|_client, path| async move {
+    ///     let content = format!("You asked for {}\n", path.display());
+    ///     let size = content.len() as u64;
+    ///     Ok((Cursor::new(content), Some(size)))
+    /// })
+    /// .build()
+    /// .await?;
+    /// ```
+    pub fn serve_read_fn(read_fn: F) -> Self {
+        TftpServerBuilder::with_handler(ReadFnHandler::new(read_fn))
+    }
 }
 
 impl TftpServerBuilder<DirHandler> {
@@ -63,14 +136,53 @@ impl<H: Handler> TftpServerBuilder<H> {
             socket: None,
             timeout: Duration::from_secs(3),
             block_size_limit: None,
+            block_size_min: MIN_BLOCK_SIZE as u16,
+            path_mtu: None,
+            auto_mtu_clamp: false,
+            max_block_size_buffer: None,
+            window_size_limit: None,
+            default_window_size: None,
+            read_ahead: 0,
             max_send_retries: 100,
+            read_max_retries: None,
+            write_max_retries: None,
+            idle_timeout: None,
+            max_total_retransmits: None,
+            on_retransmit: None,
+            timeout_jitter: None,
+            rate_limit: None,
+            per_client_rate_limit: None,
+            max_tasks: None,
+            max_pending_tasks: None,
             ignore_client_timeout: false,
             ignore_client_block_size: false,
+            ignore_client_window_size: false,
+            no_oack: false,
+            tolerant_transfer_size: false,
+            tolerant_ack: false,
+            strict_options: false,
+            unexpected_packet_policy: UnexpectedPacketPolicy::Ignore,
+            max_error_message_len: DEFAULT_MAX_ERROR_MESSAGE_LEN,
+            max_filename_len: 255,
+            masked_error: None,
+            allow_client: None,
+            drop_denied_clients: false,
+            drop_requests_on_shutdown: false,
+            reply_unknown_tid: false,
+            single_port: false,
+            restart_on_new_request: false,
+            socket_opts: SocketOpts::default(),
+            transfer_port_range: None,
+            events: Events::default(),
         }
     }
 
     /// Set listening address.
     ///
+    /// Both IPv4 and IPv6 addresses are accepted. To serve IPv4 and IPv6
+    /// clients with a single socket, bind to `[::]` and set
+    /// [`dual_stack`](Self::dual_stack).
+    ///
     /// This is ignored if underling socket is set.
     ///
     /// **Default:** `0.0.0.0:69`
@@ -101,8 +213,11 @@ impl<H: Handler> TftpServerBuilder<H> {
 
     /// Set retry timeout.
     ///
-    /// Client can override this (RFC2349). If you want to enforce it you must
-    /// combine it [`ignore_client_timeout`](Self::ignore_client_timeout).
+    /// Client can override this (RFC2349), in which case the timeout of the
+    /// client is acknowledged and used for the transfer. If you want to
+    /// enforce it you must combine it
+    /// [`ignore_client_timeout`](Self::ignore_client_timeout), which leaves
+    /// `timeout` out of OACK.
     ///
     /// This crate allows you to set non-standard timeouts (i.e. timeouts that are less
     /// than a second). However if you choose to do it make sure you test it well in your
@@ -116,6 +231,21 @@ impl<H: Handler> TftpServerBuilder<H> {
         }
     }
 
+    /// Add a random delay of up to `jitter` to each retransmit timeout.
+    ///
+    /// Clients that start at the same time, e.g. machines that boot
+    /// together, otherwise time out at the same time and their
+    /// retransmissions keep colliding. The delay is picked again for every
+    /// wait, and it is not advertised in OACK.
+    ///
+    /// **Default:** No jitter
+    pub fn timeout_jitter(self, jitter: Duration) -> Self {
+        TftpServerBuilder {
+            timeout_jitter: Some(jitter),
+            ..self
+        }
+    }
+
     /// Set maximum block size.
     ///
     /// Client can request a specific block size (RFC2348). Use this option if you
@@ -125,6 +255,10 @@ impl<H: Handler> TftpServerBuilder<H> {
     /// block size of 1468. This works fine if your MTU is 1500 bytes, however if
     /// you are accessing client through a VPN, then transfer will never start. Use
     /// this option to workaround the problem.
+    ///
+    /// Limits outside of the block sizes of RFC2348, i.e. 8 to 65464, are
+    /// raised or lowered to them with a warning. The handler can replace
+    /// this limit per file with [`Handler::max_block_size`].
     pub fn block_size_limit(self, size: u16) -> Self {
         TftpServerBuilder {
             block_size_limit: Some(size),
@@ -132,12 +266,130 @@ impl<H: Handler> TftpServerBuilder<H> {
         }
     }
 
+    /// Set minimum block size.
+    ///
+    /// Client can request block size down to 8 bytes (RFC2348), which some
+    /// embedded clients do not handle well and a malicious client can use
+    /// to slow down the server. Requests of block sizes below the minimum
+    /// are rejected with [`OptionNegotiationFailed`], as are requests that
+    /// [`block_size_limit`](Self::block_size_limit) lowers below it.
+    ///
+    /// **Default:** 8
+    ///
+    /// [`OptionNegotiationFailed`]: crate::packet::Error::OptionNegotiationFailed
+    pub fn block_size_min(self, size: u16) -> Self {
+        TftpServerBuilder {
+            block_size_min: cmp::max(size, MIN_BLOCK_SIZE as u16),
+            ..self
+        }
+    }
+
+    /// Limit block size so that DATA packets fit in the path MTU.
+    ///
+    /// Block size that client requests is limited to `mtu` minus the IP,
+    /// UDP and TFTP headers, so DATA packets are not fragmented. For an MTU
+    /// of 1500 this is 1468 bytes for IPv4 clients and 1448 bytes for IPv6
+    /// clients. This is applied together with
+    /// [`block_size_limit`](Self::block_size_limit).
+    ///
+    /// **Default:** No limit.
+    pub fn path_mtu(self, mtu: u16) -> Self {
+        TftpServerBuilder {
+            path_mtu: Some(mtu),
+            ..self
+        }
+    }
+
+    /// Limit block size to the MTU of the route to each client.
+    ///
+    /// This works like [`path_mtu`](Self::path_mtu), but the MTU is looked
+    /// up for every request. On Linux it is read with `IP_MTU` (`IPV6_MTU`
+    /// for IPv6) from a UDP socket that is connected to the client, which
+    /// gives the MTU of the egress interface, or a lower path MTU that the
+    /// kernel learned from ICMP. On other platforms, or if the lookup
+    /// fails, block size is not limited by it. If `path_mtu` is set too,
+    /// the lower MTU is used.
+    ///
+    /// **Default:** No limit.
+    pub fn auto_mtu_clamp(self) -> Self {
+        TftpServerBuilder {
+            auto_mtu_clamp: true,
+            ..self
+        }
+    }
+
+    /// Set maximum size of the buffer that holds a block.
+    ///
+    /// Every transfer allocates a buffer for a Data packet, which is the
+    /// negotiated block size plus the 4 bytes of its header. Use this option
+    /// to bound the memory of a transfer. Block size that client requests is
+    /// limited to fit in the buffer, the same way as with
+    /// [`block_size_limit`](Self::block_size_limit).
+    ///
+    /// **Default:** Unlimited (i.e. 65468 bytes for the largest block size).
+    pub fn max_block_size_buffer(self, size: usize) -> Self {
+        TftpServerBuilder {
+            max_block_size_buffer: Some(size),
+            ..self
+        }
+    }
+
+    /// Set maximum window size.
+    ///
+    /// Client can request a specific window size (RFC7440). Use this option
+    /// if you want to set a limit.
+    pub fn window_size_limit(self, size: u16) -> Self {
+        TftpServerBuilder {
+            window_size_limit: Some(cmp::max(size, 1)),
+            ..self
+        }
+    }
+
+    /// Set window size of read requests that do not request a window
+    /// size.
+    ///
+    /// The window size is sent in OACK, even though the client did not
+    /// request it. This breaks RFC2347 and clients that do not support
+    /// `windowsize` (RFC7440) reject the OACK or abort the transfer, so use
+    /// it only when all the clients are known to support it. The window
+    /// size is still limited by [`window_size_limit`].
+    ///
+    /// **Default:** Window size of 1, without OACK.
+    ///
+    /// [`window_size_limit`]: Self::window_size_limit
+    pub fn default_window_size(self, size: u16) -> Self {
+        TftpServerBuilder {
+            default_window_size: Some(cmp::max(size, 1)),
+            ..self
+        }
+    }
+
+    /// Set number of blocks that are read ahead of the window.
+    ///
+    /// While the server waits for the ACK of a window, it reads up to
+    /// `blocks` of the next blocks from the reader, so reading the file
+    /// overlaps with the round trip to the client. This helps with slow
+    /// readers and links with high latency, at the cost of `blocks` more
+    /// buffers of the block size per transfer.
+    ///
+    /// **Default:** 0, blocks are read when they are sent.
+    pub fn read_ahead(self, blocks: u16) -> Self {
+        TftpServerBuilder {
+            read_ahead: usize::from(blocks),
+            ..self
+        }
+    }
+
     /// Set maximum send retries for a data block.
     ///
     /// On timeout server will try to send the data block again. When retries are
     /// reached for the specific data block the server closes the connection with
     /// the client.
     ///
+    /// This applies to both read and write requests, unless they are set
+    /// with [`read_max_retries`](Self::read_max_retries) or
+    /// [`write_max_retries`](Self::write_max_retries).
+    ///
     /// Default: 100 retries.
     pub fn max_send_retries(self, retries: u32) -> Self {
         TftpServerBuilder {
@@ -146,6 +398,151 @@ impl<H: Handler> TftpServerBuilder<H> {
         }
     }
 
+    /// Set maximum send retries of read requests.
+    ///
+    /// **Default:** [`max_send_retries`](Self::max_send_retries).
+    pub fn read_max_retries(self, retries: u32) -> Self {
+        TftpServerBuilder {
+            read_max_retries: Some(retries),
+            ..self
+        }
+    }
+
+    /// Set maximum retries of write requests.
+    ///
+    /// When the next block does not arrive in time, the server sends its
+    /// last ACK again. This is the number of times it does so before the
+    /// transfer is aborted.
+    ///
+    /// **Default:** [`max_send_retries`](Self::max_send_retries).
+    pub fn write_max_retries(self, retries: u32) -> Self {
+        TftpServerBuilder {
+            write_max_retries: Some(retries),
+            ..self
+        }
+    }
+
+    /// Set maximum time that a transfer can make no progress.
+    ///
+    /// A transfer is aborted if the client does not acknowledge new data
+    /// (or does not send new data, for write requests) within this time,
+    /// even if the retries of [`max_send_retries`](Self::max_send_retries)
+    /// are not reached yet.
+    ///
+    /// **Default:** Transfers are limited only by `max_send_retries`.
+    pub fn idle_timeout(self, timeout: Duration) -> Self {
+        TftpServerBuilder {
+            idle_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Set maximum number of retransmissions of a whole transfer.
+    ///
+    /// [`max_send_retries`](Self::max_send_retries) limits the retries of
+    /// each block, so a client that acknowledges just often enough keeps
+    /// a transfer alive forever. With this the transfer is aborted once the
+    /// server retransmits more than `max` packets in total, i.e. DATA
+    /// packets of read requests and ACK packets of write requests. These
+    /// are the retransmissions of [`TransferStats::retransmits`].
+    ///
+    /// **Default:** Transfers are limited only by `max_send_retries`.
+    ///
+    /// [`TransferStats::retransmits`]: crate::server::TransferStats::retransmits
+    pub fn max_total_retransmits(self, max: u64) -> Self {
+        TftpServerBuilder {
+            max_total_retransmits: Some(max),
+            ..self
+        }
+    }
+
+    /// Set a callback that is called on every retransmission.
+    ///
+    /// The callback gets the address of the client, the block and the
+    /// number of the attempt, which is 1 for the first retransmission of
+    /// the block. For read requests the block is the first one of the
+    /// window that is sent again, for write requests it is the block that
+    /// the ACK asks for.
+    ///
+    /// The callback runs in the transfer, so it must be cheap.
+    pub fn on_retransmit<F>(self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr, u16, u32) + Send + Sync + 'static,
+    {
+        TftpServerBuilder {
+            on_retransmit: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Limit the rate of DATA packets that read requests send, in bytes
+    /// per second.
+    ///
+    /// The limit is shared by all the transfers of the server, which is
+    /// useful when many PXE clients boot at once and would otherwise
+    /// saturate the uplink. Retransmissions count towards the limit too.
+    ///
+    /// **Default:** No limit.
+    pub fn rate_limit(self, bytes_per_sec: u64) -> Self {
+        TftpServerBuilder {
+            rate_limit: Some(bytes_per_sec),
+            ..self
+        }
+    }
+
+    /// Limit the rate of DATA packets that each read request sends, in
+    /// bytes per second.
+    ///
+    /// Unlike [`rate_limit`](Self::rate_limit), every transfer has its own
+    /// limit, so a fast client can not take all of the bandwidth. If both
+    /// are set, a transfer is bound by both of them.
+    ///
+    /// **Default:** No limit.
+    pub fn per_client_rate_limit(self, bytes_per_sec: u64) -> Self {
+        TftpServerBuilder {
+            per_client_rate_limit: Some(bytes_per_sec),
+            ..self
+        }
+    }
+
+    /// Set maximum number of transfers that run concurrently.
+    ///
+    /// Each transfer runs as a task in server's executor. When the limit is
+    /// reached the server stops accepting new requests until some of the
+    /// transfers are done. In that case new requests are queued by the
+    /// socket receive buffer of the OS. In [`single_port_mode`] the server
+    /// keeps passing datagrams to the transfers, so new requests are
+    /// rejected with an error instead. A limit of 0 is raised to 1.
+    ///
+    /// **Default:** Unlimited.
+    ///
+    /// [`single_port_mode`]: Self::single_port_mode
+    pub fn max_tasks(self, tasks: usize) -> Self {
+        TftpServerBuilder {
+            max_tasks: Some(cmp::max(tasks, 1)),
+            ..self
+        }
+    }
+
+    /// Set maximum number of requests that wait for a transfer to be done
+    /// when the limit of [`max_tasks`] is reached.
+    ///
+    /// With this limit the server keeps accepting requests while
+    /// [`max_tasks`] transfers run. Up to `tasks` new requests wait for a
+    /// free slot and further requests are rejected with an error, instead
+    /// of waiting in the socket receive buffer. It has no effect without
+    /// [`max_tasks`].
+    ///
+    /// **Default:** The server stops accepting requests.
+    ///
+    /// [`max_tasks`]: Self::max_tasks
+    pub fn max_pending_tasks(self, tasks: usize) -> Self {
+        TftpServerBuilder {
+            max_pending_tasks: Some(tasks),
+            ..self
+        }
+    }
+
     /// Ignore client's `timeout` option.
     ///
     /// With this you enforce server's timeout by ignoring client's
@@ -168,29 +565,472 @@ impl<H: Handler> TftpServerBuilder<H> {
         }
     }
 
+    /// Answer any `tsize` option of a read request.
+    ///
+    /// RFC2349 defines that a client must send `tsize` with value of `0`
+    /// for read requests and only then the server replies with the real size.
+    /// With this option any `tsize` value of read request is treated as a
+    /// request for the real size, which is then advertised in OACK.
+    pub fn tolerant_transfer_size(self) -> Self {
+        TftpServerBuilder {
+            tolerant_transfer_size: true,
+            ..self
+        }
+    }
+
+    /// Accept ACK packets with trailing bytes.
+    ///
+    /// Some buggy clients pad their ACK packets up to the negotiated block
+    /// size. By default such packets are invalid and they are ignored, which
+    /// results in needless retransmissions. With this option the trailing
+    /// bytes are ignored instead.
+    pub fn tolerant_ack(self) -> Self {
+        TftpServerBuilder {
+            tolerant_ack: true,
+            ..self
+        }
+    }
+
+    /// Refuse requests with options that the server does not honor as they
+    /// are.
+    ///
+    /// By default a requested `blksize`, `windowsize` or `timeout` that is
+    /// out of the limits of the server is clamped (or left out of OACK if it
+    /// is ignored), as RFC2347 allows. With this option the server replies
+    /// with ERROR [`OptionNegotiationFailed`] instead.
+    ///
+    /// [`OptionNegotiationFailed`]: crate::packet::Error::OptionNegotiationFailed
+    pub fn strict_options(self) -> Self {
+        TftpServerBuilder {
+            strict_options: true,
+            ..self
+        }
+    }
+
+    /// Set how to handle packets that are not requests.
+    ///
+    /// This applies only to valid packets that are received on the listening
+    /// socket but they are neither RRQ nor WRQ (e.g. a stray DATA or ACK).
+    /// Replies of [`UnexpectedPacketPolicy::Error`] are rate limited to a
+    /// few kilobytes per second, what exceeds it is ignored.
+    ///
+    /// **Default:** [`UnexpectedPacketPolicy::Ignore`]
+    pub fn on_unexpected_packet(self, policy: UnexpectedPacketPolicy) -> Self {
+        TftpServerBuilder {
+            unexpected_packet_policy: policy,
+            ..self
+        }
+    }
+
+    /// Set maximum length of the message of ERROR packets, in bytes.
+    ///
+    /// Messages of [`packet::Error::Msg`] that the handler returns can be
+    /// of any length, which may not fit in a datagram or may reveal more
+    /// than you want. Longer messages are truncated at a character
+    /// boundary. Control characters are always replaced with spaces.
+    ///
+    /// **Default:** 255 bytes.
+    ///
+    /// [`packet::Error::Msg`]: crate::packet::Error::Msg
+    pub fn max_error_message_len(self, len: usize) -> Self {
+        TftpServerBuilder {
+            max_error_message_len: len,
+            ..self
+        }
+    }
+
+    /// Set maximum length of the filename of requests, in bytes.
+    ///
+    /// Requests with a longer filename are rejected with
+    /// [`packet::Error::IllegalOperation`] before they reach the handler.
+    ///
+    /// **Default:** 255 bytes.
+    ///
+    /// [`packet::Error::IllegalOperation`]: crate::packet::Error::IllegalOperation
+    pub fn max_filename_len(self, len: usize) -> Self {
+        TftpServerBuilder {
+            max_filename_len: len,
+            ..self
+        }
+    }
+
+    /// Send `error` to clients whose request is rejected by the handler,
+    /// instead of the error that the handler returned.
+    ///
+    /// This applies to the errors of [`Handler::should_serve`],
+    /// [`Handler::read_req_open`] and [`Handler::write_req_open`], e.g.
+    /// with [`packet::Error::FileNotFound`] clients can not tell which
+    /// files exist and which they are not allowed to access. The errors of
+    /// the handler are still logged.
+    ///
+    /// **Default:** Errors are sent as they are.
+    ///
+    /// [`Handler::should_serve`]: crate::server::Handler::should_serve
+    /// [`Handler::read_req_open`]: crate::server::Handler::read_req_open
+    /// [`Handler::write_req_open`]: crate::server::Handler::write_req_open
+    /// [`packet::Error::FileNotFound`]: crate::packet::Error::FileNotFound
+    pub fn mask_errors_as(self, error: packet::Error) -> Self {
+        TftpServerBuilder {
+            masked_error: Some(error),
+            ..self
+        }
+    }
+
+    /// Ignore client's window size option.
+    ///
+    /// With this you can ignore client's `windowsize` option of RFC7440.
+    /// This will enforce window size of 1 that is defined in RFC1350.
+    pub fn ignore_client_window_size(self) -> Self {
+        TftpServerBuilder {
+            ignore_client_window_size: true,
+            ..self
+        }
+    }
+
+    /// Never reply with OACK.
+    ///
+    /// Options of requests are parsed but ignored, so transfers use the
+    /// defaults of RFC1350 (512 bytes blocks, window size of 1) and the
+    /// [`timeout`](Self::timeout) of the server. This is for old clients
+    /// that reject OACK even when they send options. Combined with
+    /// [`strict_options`](Self::strict_options), requests with any option
+    /// are rejected.
+    pub fn no_oack(self) -> Self {
+        TftpServerBuilder {
+            no_oack: true,
+            ..self
+        }
+    }
+
+    /// Set a callback that decides which clients are served.
+    ///
+    /// The callback is called for every request with the address of the
+    /// client, before the request reaches the [`Handler`]. If it returns
+    /// `false` the client gets an ERROR packet with [`PermissionDenied`],
+    /// unless [`drop_denied_clients`](Self::drop_denied_clients) is set.
+    ///
+    /// The callback runs on the loop that accepts requests, so it must be
+    /// cheap.
+    ///
+    /// [`PermissionDenied`]: crate::packet::Error::PermissionDenied
+    pub fn allow_client<F>(self, f: F) -> Self
+    where
+        F: Fn(&SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        TftpServerBuilder {
+            allow_client: Some(Arc::new(f)),
+            ..self
+        }
+    }
+
+    /// Silently ignore requests of clients that are denied by
+    /// [`allow_client`](Self::allow_client).
+    pub fn drop_denied_clients(self) -> Self {
+        TftpServerBuilder {
+            drop_denied_clients: true,
+            ..self
+        }
+    }
+
+    /// Silently ignore requests that arrive after
+    /// [`ShutdownHandle::shutdown`], while the transfers in progress are
+    /// drained.
+    ///
+    /// **Default:** Clients get an ERROR packet with the message "Server is
+    /// shutting down", so they fail without waiting for a timeout.
+    ///
+    /// [`ShutdownHandle::shutdown`]: crate::server::ShutdownHandle::shutdown
+    pub fn drop_requests_on_shutdown(self) -> Self {
+        TftpServerBuilder {
+            drop_requests_on_shutdown: true,
+            ..self
+        }
+    }
+
+    /// Reply with ERROR [`UnknownTransferId`] to datagrams that reach a
+    /// transfer from another address, as RFC1350 specifies. The
+    /// transfer continues.
+    ///
+    /// **Default:** Transfer sockets are connected to the client, so the OS
+    /// drops these datagrams without a reply.
+    ///
+    /// [`UnknownTransferId`]: crate::packet::Error::UnknownTransferId
+    pub fn reply_unknown_tid(self) -> Self {
+        TftpServerBuilder {
+            reply_unknown_tid: true,
+            ..self
+        }
+    }
+
+    /// Serve transfers on the listening socket.
+    ///
+    /// RFC1350 transfers reply from a new port, which some NATs and
+    /// firewalls do not let through. In this mode the server replies from
+    /// the listening port and dispatches the datagrams of each client to
+    /// its transfer by the address of the client. A client can have only
+    /// one transfer at a time, [`transfer_port_range`] and
+    /// [`reply_unknown_tid`] have no effect.
+    ///
+    /// **Default:** Every transfer binds a socket of its own.
+    ///
+    /// [`transfer_port_range`]: Self::transfer_port_range
+    /// [`reply_unknown_tid`]: Self::reply_unknown_tid
+    pub fn single_port_mode(self) -> Self {
+        TftpServerBuilder {
+            single_port: true,
+            ..self
+        }
+    }
+
+    /// Restart the transfer of a client that sends a request again.
+    ///
+    /// A client that crashes and retries from the same address and port
+    /// would otherwise wait until its previous transfer times out. With
+    /// this the previous transfer is cancelled, without calling
+    /// [`Handler::transfer_done`], and the new request is served. Note
+    /// that a request that the client resends because our first reply was
+    /// lost restarts the transfer too.
+    ///
+    /// **Default:** Requests of clients with a transfer in progress are
+    /// handled as resent requests, which makes the transfer resend its
+    /// first reply.
+    ///
+    /// [`Handler::transfer_done`]: crate::server::Handler::transfer_done
+    pub fn restart_on_new_request(self) -> Self {
+        TftpServerBuilder {
+            restart_on_new_request: true,
+            ..self
+        }
+    }
+
+    /// Accept IPv4 clients on an IPv6 listening address.
+    ///
+    /// This clears `IPV6_V6ONLY` of the listening socket and of the
+    /// sockets of the transfers, on platforms that support it. IPv4
+    /// clients are seen as IPv4-mapped IPv6 addresses. It has no effect
+    /// on IPv4 addresses or on a socket set with [`socket`](Self::socket).
+    ///
+    /// **Default:** Use the `IPV6_V6ONLY` default of the OS.
+    pub fn dual_stack(self) -> Self {
+        TftpServerBuilder {
+            socket_opts: SocketOpts {
+                dual_stack: true,
+                ..self.socket_opts
+            },
+            ..self
+        }
+    }
+
+    /// Set the TTL of outgoing datagrams, or the hop limit for IPv6.
+    ///
+    /// This is applied to the listening socket and to the sockets of the
+    /// transfers.
+    ///
+    /// **Default:** Use the default of the OS.
+    pub fn socket_ttl(self, ttl: u32) -> Self {
+        TftpServerBuilder {
+            socket_opts: SocketOpts {
+                ttl: Some(ttl),
+                ..self.socket_opts
+            },
+            ..self
+        }
+    }
+
+    /// Set the DSCP of outgoing datagrams.
+    ///
+    /// Only the 6 lower bits are used. This is applied to the listening
+    /// socket and to the sockets of the transfers. For IPv6 it is
+    /// supported only on Unix platforms.
+    ///
+    /// **Default:** Use the default of the OS.
+    pub fn socket_dscp(self, dscp: u8) -> Self {
+        TftpServerBuilder {
+            socket_opts: SocketOpts {
+                dscp: Some(dscp),
+                ..self.socket_opts
+            },
+            ..self
+        }
+    }
+
+    /// Set the size of the receive buffer (`SO_RCVBUF`) of the sockets.
+    ///
+    /// A larger buffer keeps the OS from dropping ACKs and DATA packets
+    /// while the server is busy. This is applied to the listening socket
+    /// and to the sockets of the transfers. The OS may round the size or
+    /// cap it, e.g. to `net.core.rmem_max` on Linux.
+    ///
+    /// **Default:** Use the default of the OS.
+    pub fn recv_buffer_size(self, size: usize) -> Self {
+        TftpServerBuilder {
+            socket_opts: SocketOpts {
+                recv_buffer_size: Some(size),
+                ..self.socket_opts
+            },
+            ..self
+        }
+    }
+
+    /// Set the size of the send buffer (`SO_SNDBUF`) of the sockets.
+    ///
+    /// This is applied to the listening socket and to the sockets of the
+    /// transfers. The OS may round the size or cap it, e.g. to
+    /// `net.core.wmem_max` on Linux.
+    ///
+    /// **Default:** Use the default of the OS.
+    pub fn send_buffer_size(self, size: usize) -> Self {
+        TftpServerBuilder {
+            socket_opts: SocketOpts {
+                send_buffer_size: Some(size),
+                ..self.socket_opts
+            },
+            ..self
+        }
+    }
+
+    /// Set the range of UDP ports that the sockets of the transfers are
+    /// bound to.
+    ///
+    /// Each transfer needs its own port, so the size of the range limits
+    /// the concurrent transfers. When all ports are taken the request
+    /// fails with an ERROR packet that is sent from the listening socket.
+    ///
+    /// **Default:** Ephemeral ports that are assigned by the OS.
+    pub fn transfer_port_range(self, range: RangeInclusive<u16>) -> Self {
+        TftpServerBuilder {
+            transfer_port_range: Some(range),
+            ..self
+        }
+    }
+
+    /// Send [`ServerEvent`]s of requests and transfers to a channel.
+    ///
+    /// Events are sent without blocking the server, so they are dropped if
+    /// the channel is full. For aborted transfers only
+    /// [`ServerEvent::ClientAborted`] is sent.
+    ///
+    /// **Default:** No events are sent.
+    pub fn events_channel(self, tx: Sender<ServerEvent>) -> Self {
+        TftpServerBuilder {
+            events: Events(Some(tx)),
+            ..self
+        }
+    }
+
     /// Build [`TftpServer`].
     pub async fn build(mut self) -> Result<TftpServer<H>> {
         let socket = match self.socket.take() {
-            Some(socket) => socket,
-            None => Async::<UdpSocket>::bind(self.addr).map_err(Error::Bind)?,
+            Some(socket) => {
+                let ipv6 = socket.get_ref().local_addr()?.is_ipv6();
+                set_socket_opts(
+                    &SockRef::from(socket.get_ref()),
+                    ipv6,
+                    &self.socket_opts,
+                )?;
+                socket
+            }
+            None => {
+                bind_udp(self.addr, &self.socket_opts).map_err(Error::Bind)?
+            }
+        };
+
+        let block_size_buffer_limit = self.max_block_size_buffer.map(|size| {
+            size.saturating_sub(PACKET_DATA_HEADER_LEN)
+                .clamp(MIN_BLOCK_SIZE, usize::from(u16::MAX)) as u16
+        });
+
+        let block_size_limit = match block_size_buffer_limit {
+            Some(max) => {
+                Some(self.block_size_limit.map_or(max, |l| cmp::min(l, max)))
+            }
+            None => self.block_size_limit,
         };
 
+        let block_size_limit = block_size_limit.map(|limit| {
+            let clamped =
+                limit.clamp(MIN_BLOCK_SIZE as u16, MAX_BLOCK_SIZE as u16);
+
+            if clamped != limit {
+                warn!(
+                    "Block size limit {} is out of range, {} is used instead",
+                    limit, clamped
+                );
+            }
+
+            clamped
+        });
+
         let config = ServerConfig {
             timeout: self.timeout,
-            block_size_limit: self.block_size_limit,
-            max_send_retries: self.max_send_retries,
+            block_size_limit,
+            block_size_buffer_limit,
+            block_size_min: self.block_size_min,
+            path_mtu: self.path_mtu,
+            auto_mtu_clamp: self.auto_mtu_clamp,
+            window_size_limit: self.window_size_limit,
+            default_window_size: self.default_window_size,
+            read_ahead: self.read_ahead,
+            read_max_retries: self
+                .read_max_retries
+                .unwrap_or(self.max_send_retries),
+            write_max_retries: self
+                .write_max_retries
+                .unwrap_or(self.max_send_retries),
+            idle_timeout: self.idle_timeout,
+            max_total_retransmits: self.max_total_retransmits,
+            on_retransmit: self.on_retransmit,
+            timeout_jitter: self.timeout_jitter.filter(|j| !j.is_zero()),
+            rate_limiter: self
+                .rate_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate))),
+            per_client_rate_limit: self.per_client_rate_limit,
             ignore_client_timeout: self.ignore_client_timeout,
             ignore_client_block_size: self.ignore_client_block_size,
+            ignore_client_window_size: self.ignore_client_window_size,
+            no_oack: self.no_oack,
+            tolerant_transfer_size: self.tolerant_transfer_size,
+            tolerant_ack: self.tolerant_ack,
+            strict_options: self.strict_options,
+            unexpected_packet_policy: self.unexpected_packet_policy,
+            max_error_message_len: self.max_error_message_len,
+            max_filename_len: self.max_filename_len,
+            masked_error: self.masked_error,
+            allow_client: self.allow_client,
+            drop_denied_clients: self.drop_denied_clients,
+            drop_requests_on_shutdown: self.drop_requests_on_shutdown,
+            reply_unknown_tid: self.reply_unknown_tid,
+            single_port: self.single_port,
+            restart_on_new_request: self.restart_on_new_request,
+            socket_opts: self.socket_opts,
+            transfer_port_range: self.transfer_port_range,
+            events: self.events,
         };
 
         let local_ip = socket.as_ref().local_addr()?.ip();
+        let (shutdown_tx, shutdown_rx) = async_channel::unbounded();
+        let (cancel_tx, cancel_rx) = async_channel::bounded(1);
+        let (socket_tx, socket_rx) = async_channel::bounded(1);
+
         Ok(TftpServer {
-            socket,
+            socket: Arc::new(socket),
             handler: Arc::new(Mutex::new(self.handle)),
-            reqs_in_progress: Arc::new(Mutex::new(HashSet::new())),
-            ex: Executor::new(),
+            reqs_in_progress: Arc::new(Mutex::new(HashMap::new())),
+            ex: Arc::new(Executor::new()),
+            task_slots: self.max_tasks.map(|n| Arc::new(Semaphore::new(n))),
+            pending_slots: self
+                .max_pending_tasks
+                .map(|n| Arc::new(Semaphore::new(n))),
+            transfers: Arc::new(RwLock::new(())),
+            shutdown_tx,
+            shutdown_rx,
+            cancel_tx,
+            cancel_rx,
+            socket_tx,
+            socket_rx,
             config,
             local_ip,
+            unexpected_replies: RateLimiter::new(UNEXPECTED_REPLY_RATE),
         })
     }
 }
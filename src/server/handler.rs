@@ -1,28 +1,291 @@
 use futures_lite::{AsyncRead, AsyncWrite};
 use std::future::Future;
+use std::io;
 use std::net::SocketAddr;
 use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
 
 use crate::packet;
+use crate::server::handlers::BoxedHandler;
 
 /// Trait for implementing advance handlers.
 pub trait Handler: Send {
     type Reader: AsyncRead + Unpin + Send + 'static;
     type Writer: AsyncWrite + Unpin + Send + 'static;
 
+    /// Decide whether a request is served, before anything is opened.
+    ///
+    /// This is called for read and write requests, before
+    /// [`read_req_open`] or [`write_req_open`]. Return an error to reject
+    /// the request, e.g. [`packet::Error::OptionNegotiationFailed`] for
+    /// options that you do not want to serve. The error is sent to the
+    /// client.
+    ///
+    /// [`read_req_open`]: Self::read_req_open
+    /// [`write_req_open`]: Self::write_req_open
+    #[allow(unused_variables)]
+    fn should_serve(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> impl Future<Output = Result<(), packet::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Maximum block size of transfers of `path`.
+    ///
+    /// This is called for read and write requests, after the file is
+    /// opened. The returned limit replaces
+    /// [`TftpServerBuilder::block_size_limit`] for this transfer, so it
+    /// can also raise it. It is still bounded by
+    /// [`TftpServerBuilder::max_block_size_buffer`] and the path MTU.
+    /// Return `None` to use the global limit.
+    ///
+    /// [`TftpServerBuilder::block_size_limit`]: crate::server::TftpServerBuilder::block_size_limit
+    /// [`TftpServerBuilder::max_block_size_buffer`]: crate::server::TftpServerBuilder::max_block_size_buffer
+    #[allow(unused_variables)]
+    fn max_block_size(&self, path: &Path) -> Option<u16> {
+        None
+    }
+
     /// Open `Reader` to serve a read request.
+    ///
+    /// `req` carries the transfer mode and the options that client
+    /// requested. Return [`packet::Error::IllegalOperation`] if you do not
+    /// support the mode.
+    ///
+    /// The returned size is the exact size of the file, which is sent to
+    /// clients that request `tsize` (RFC2349). Return `None` if the size is
+    /// not known in advance (e.g. for a stream), then `tsize` is left out of
+    /// OACK and the rest of the options are acknowledged as usual. Sizes
+    /// that are only estimations must not be returned, since clients may
+    /// reject transfers that do not match.
+    ///
+    /// Reads that fail with [`io::ErrorKind::Interrupted`] are retried and
+    /// [`io::ErrorKind::UnexpectedEof`] ends the transfer as the end of the
+    /// file does. Any other error aborts the transfer and it is sent to the
+    /// client as described in [`packet::Error`]'s `From<io::Error>`.
+    ///
+    /// This is also how a transfer is cancelled, e.g. when the source of
+    /// generated content goes away: return a reader that fails. The blocks
+    /// that are already sent stay with the client, which gets an ERROR
+    /// packet instead of the next block, and [`transfer_done`] is called
+    /// with [`TransferError::Io`].
+    ///
+    /// ```ignore
+    /// /// Wrap `reader` in a reader that fails after the returned flag is set.
+    /// fn abort_reader<R>(reader: R) -> (AbortReader<R>, Arc<AtomicBool>) {
+    ///     let aborted = Arc::new(AtomicBool::new(false));
+    ///     (AbortReader(reader, Arc::clone(&aborted)), aborted)
+    /// }
+    ///
+    /// struct AbortReader<R>(R, Arc<AtomicBool>);
+    ///
+    /// impl<R: AsyncRead + Unpin> AsyncRead for AbortReader<R> {
+    ///     fn poll_read(
+    ///         mut self: Pin<&mut Self>,
+    ///         cx: &mut Context,
+    ///         buf: &mut [u8],
+    ///     ) -> Poll<io::Result<usize>> {
+    ///         if self.1.load(Ordering::Relaxed) {
+    ///             return Poll::Ready(Err(io::ErrorKind::ConnectionAborted.into()));
+    ///         }
+    ///         Pin::new(&mut self.0).poll_read(cx, buf)
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// [`transfer_done`]: Self::transfer_done
     fn read_req_open(
         &mut self,
         client: &SocketAddr,
         path: &Path,
+        req: &packet::RwReq,
     ) -> impl Future<Output = Result<(Self::Reader, Option<u64>), packet::Error>>
            + Send;
 
+    /// Seek `reader` to byte `pos`, to resume a read request with the
+    /// `offset` option.
+    ///
+    /// Return `false` if the reader can not seek, the data before `pos`
+    /// are then read and discarded. This is called only in octet mode and
+    /// for readers of a known size, and `pos` is never past the end.
+    #[allow(unused_variables)]
+    fn read_req_seek(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> impl Future<Output = io::Result<bool>> + Send {
+        async { Ok(false) }
+    }
+
     /// Open `Writer` to serve a write request.
+    ///
+    /// `req` carries the transfer mode and the options that client
+    /// requested. Return [`packet::Error::IllegalOperation`] if you do not
+    /// support the mode.
     fn write_req_open(
         &mut self,
         client: &SocketAddr,
         path: &Path,
+        req: &packet::RwReq,
         size: Option<u64>,
     ) -> impl Future<Output = Result<Self::Writer, packet::Error>> + Send;
+
+    /// Called after all the data of a write request are written and
+    /// flushed, e.g. to call `fsync` on the file.
+    ///
+    /// The client already received the ACK of the last block, so an error
+    /// is not sent to it, but the transfer fails with
+    /// [`TransferError::Io`] in [`transfer_done`].
+    ///
+    /// [`transfer_done`]: Self::transfer_done
+    #[allow(unused_variables)]
+    fn write_req_sync(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        writer: &mut Self::Writer,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Called when the client sends an ERROR packet during a transfer.
+    ///
+    /// Clients may abort a transfer on purpose, e.g. when they reject the
+    /// options of OACK, so this lets you tell such aborts apart from real
+    /// failures. [`transfer_done`] is called afterwards with
+    /// [`TransferError::ClientAborted`]. Requests that only ask for the size
+    /// of a file are reported to [`size_probe`] instead.
+    ///
+    /// [`transfer_done`]: Self::transfer_done
+    /// [`size_probe`]: Self::size_probe
+    #[allow(unused_variables)]
+    fn client_aborted(
+        &mut self,
+        client: &SocketAddr,
+        error: &packet::Error,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when a client aborts a read request right after it gets the
+    /// size of the file in OACK.
+    ///
+    /// Some clients, e.g. firmwares, request a file only to learn its size
+    /// with `tsize` (RFC2349). These requests are not failed transfers, so
+    /// [`client_aborted`] and [`transfer_done`] are not called for them.
+    ///
+    /// [`client_aborted`]: Self::client_aborted
+    /// [`transfer_done`]: Self::transfer_done
+    #[allow(unused_variables)]
+    fn size_probe(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called when a transfer is completed or aborted.
+    ///
+    /// This is called for every request that `read_req_open` or
+    /// `write_req_open` accepted, even if the transfer fails before it
+    /// starts (e.g. because its options are rejected), except for size
+    /// probes that go to [`size_probe`]. It is not called if the open
+    /// fails.
+    ///
+    /// [`size_probe`]: Self::size_probe
+    #[allow(unused_variables)]
+    fn transfer_done(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        result: Result<TransferStats, TransferError>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Box the handler, so handlers of different types can be used in its
+    /// place.
+    fn boxed(self) -> BoxedHandler
+    where
+        Self: Sized + 'static,
+    {
+        BoxedHandler::new(self)
+    }
+}
+
+/// Statistics of a completed transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Bytes of data that were transferred.
+    pub bytes: u64,
+    /// Data blocks that were transferred.
+    pub blocks: u64,
+    /// Packets that were sent again, because of a timeout or because the
+    /// client asked for it.
+    pub retransmits: u64,
+    /// Time that the server spent on the transfer.
+    pub duration: Duration,
+    /// Block size that was used, after the option negotiation.
+    pub block_size: u16,
+    /// Window size that was used, after the option negotiation.
+    pub window_size: u16,
+    /// Timeout of retransmissions that was used, after the option
+    /// negotiation.
+    pub timeout: Duration,
+}
+
+/// Reason of an aborted transfer.
+#[derive(Debug, Error)]
+pub enum TransferError {
+    /// Client aborted the transfer with an ERROR packet (e.g. with
+    /// [`packet::Error::OptionNegotiationFailed`] after receiving OACK).
+    #[error("Client aborted the transfer: {0:?}")]
+    ClientAborted(packet::Error),
+
+    /// Client stopped responding.
+    #[error("Transfer timed out")]
+    Timeout,
+
+    /// IO error of the socket, the reader or the writer.
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl Clone for TransferError {
+    fn clone(&self) -> Self {
+        match self {
+            TransferError::ClientAborted(e) => {
+                TransferError::ClientAborted(e.clone())
+            }
+            TransferError::Timeout => TransferError::Timeout,
+            // `io::Error` can not be cloned, keep its kind and message.
+            TransferError::Io(e) => {
+                TransferError::Io(io::Error::new(e.kind(), e.to_string()))
+            }
+        }
+    }
+}
+
+impl From<crate::Error> for TransferError {
+    fn from(err: crate::Error) -> Self {
+        match err {
+            crate::Error::ClientAborted {
+                code,
+                ..
+            } => TransferError::ClientAborted(code),
+            crate::Error::MaxSendRetriesReached(..)
+            | crate::Error::MaxRetransmitsReached(..)
+            | crate::Error::IdleTimeout(..) => TransferError::Timeout,
+            crate::Error::Io(e) | crate::Error::Bind(e) => TransferError::Io(e),
+            e => TransferError::Io(io::Error::other(e)),
+        }
+    }
 }
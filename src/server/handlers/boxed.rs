@@ -0,0 +1,354 @@
+use futures_lite::{AsyncRead, AsyncWrite};
+use std::any::Any;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::packet;
+use crate::server::{Handler, TransferError, TransferStats};
+
+/// Handler of any type, see [`Handler::boxed`].
+///
+/// All boxed handlers have the same type, so they can be stored together,
+/// e.g. in a `Vec<BoxedHandler>`, or be chosen at runtime.
+///
+/// ```ignore
+/// let handler = if let Some(dir) = dir {
+///     DirHandler::new(dir, DirHandlerMode::ReadOnly)?.boxed()
+/// } else {
+///     MemHandler::new().boxed()
+/// };
+/// ```
+pub struct BoxedHandler(Box<dyn ErasedHandler>);
+
+impl BoxedHandler {
+    /// Box `handler`.
+    pub fn new<H>(handler: H) -> Self
+    where
+        H: Handler + 'static,
+    {
+        BoxedHandler(Box::new(handler))
+    }
+}
+
+/// Reader of [`BoxedHandler`] read requests.
+pub struct BoxedReader(Box<dyn AnyReader>);
+
+/// Writer of [`BoxedHandler`] write requests.
+pub struct BoxedWriter(Box<dyn AnyWriter>);
+
+impl Handler for BoxedHandler {
+    type Reader = BoxedReader;
+    type Writer = BoxedWriter;
+
+    async fn should_serve(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(), packet::Error> {
+        self.0.serve(client, path, req).await
+    }
+
+    fn max_block_size(&self, path: &Path) -> Option<u16> {
+        self.0.block_size(path)
+    }
+
+    async fn read_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        self.0.open_reader(client, path, req).await
+    }
+
+    async fn read_req_seek(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> io::Result<bool> {
+        self.0.seek_reader(client, path, &mut *reader.0, pos).await
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        let writer = self.0.open_writer(client, path, req, size).await?;
+        Ok(BoxedWriter(writer))
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        self.0.sync_writer(client, path, &mut *writer.0).await
+    }
+
+    async fn client_aborted(
+        &mut self,
+        client: &SocketAddr,
+        error: &packet::Error,
+    ) {
+        self.0.aborted(client, error).await
+    }
+
+    async fn size_probe(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) {
+        self.0.probed(client, path, req).await
+    }
+
+    async fn transfer_done(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        result: Result<TransferStats, TransferError>,
+    ) {
+        self.0.done(client, path, result).await
+    }
+
+    fn boxed(self) -> BoxedHandler {
+        self
+    }
+}
+
+impl AsyncRead for BoxedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for BoxedWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+/// Reader that can be given back to the handler that opened it.
+trait AnyReader: AsyncRead + Unpin + Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<R> AnyReader for R
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Writer that can be given back to the handler that opened it.
+trait AnyWriter: AsyncWrite + Unpin + Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<W> AnyWriter for W
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object safe version of [`Handler`].
+trait ErasedHandler: Send {
+    fn serve<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, Result<(), packet::Error>>;
+
+    fn block_size(&self, path: &Path) -> Option<u16>;
+
+    fn open_reader<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, Result<(BoxedReader, Option<u64>), packet::Error>>;
+
+    fn seek_reader<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        reader: &'a mut dyn AnyReader,
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<bool>>;
+
+    fn open_writer<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+        size: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn AnyWriter>, packet::Error>>;
+
+    fn sync_writer<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        writer: &'a mut dyn AnyWriter,
+    ) -> BoxFuture<'a, io::Result<()>>;
+
+    fn aborted<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        error: &'a packet::Error,
+    ) -> BoxFuture<'a, ()>;
+
+    fn probed<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, ()>;
+
+    fn done<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        result: Result<TransferStats, TransferError>,
+    ) -> BoxFuture<'a, ()>;
+}
+
+impl<H> ErasedHandler for H
+where
+    H: Handler + 'static,
+{
+    fn serve<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, Result<(), packet::Error>> {
+        Box::pin(self.should_serve(client, path, req))
+    }
+
+    fn block_size(&self, path: &Path) -> Option<u16> {
+        self.max_block_size(path)
+    }
+
+    fn open_reader<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, Result<(BoxedReader, Option<u64>), packet::Error>> {
+        Box::pin(async move {
+            let (reader, size) = self.read_req_open(client, path, req).await?;
+            Ok((BoxedReader(Box::new(reader)), size))
+        })
+    }
+
+    fn seek_reader<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        reader: &'a mut dyn AnyReader,
+        pos: u64,
+    ) -> BoxFuture<'a, io::Result<bool>> {
+        Box::pin(async move {
+            match reader.as_any_mut().downcast_mut::<H::Reader>() {
+                Some(reader) => {
+                    self.read_req_seek(client, path, reader, pos).await
+                }
+                None => Ok(false),
+            }
+        })
+    }
+
+    fn open_writer<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+        size: Option<u64>,
+    ) -> BoxFuture<'a, Result<Box<dyn AnyWriter>, packet::Error>> {
+        Box::pin(async move {
+            let writer = self.write_req_open(client, path, req, size).await?;
+            Ok(Box::new(writer) as Box<_>)
+        })
+    }
+
+    fn sync_writer<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        writer: &'a mut dyn AnyWriter,
+    ) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            match writer.as_any_mut().downcast_mut::<H::Writer>() {
+                Some(writer) => self.write_req_sync(client, path, writer).await,
+                None => Ok(()),
+            }
+        })
+    }
+
+    fn aborted<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        error: &'a packet::Error,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(self.client_aborted(client, error))
+    }
+
+    fn probed<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        req: &'a packet::RwReq,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(self.size_probe(client, path, req))
+    }
+
+    fn done<'a>(
+        &'a mut self,
+        client: &'a SocketAddr,
+        path: &'a Path,
+        result: Result<TransferStats, TransferError>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(self.transfer_done(client, path, result))
+    }
+}
@@ -0,0 +1,281 @@
+use futures_lite::{AsyncRead, AsyncWrite};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use super::{BoxedHandler, BoxedReader, BoxedWriter};
+use crate::packet;
+use crate::server::{Handler, TransferError, TransferStats};
+
+/// Handler that tries a list of handlers in order.
+///
+/// A request is served by the first handler that does not reject it with
+/// [`packet::Error::FileNotFound`], e.g. a [`DirHandler`] can fall back to
+/// a [`MemHandler`]. Any other error is sent to the client right away and
+/// the rest of the handlers are not tried. Each handler decides with its
+/// own [`Handler::should_serve`], which is called before it opens the file.
+///
+/// The rest of the callbacks of a transfer go to the handler that served
+/// it.
+///
+/// ```ignore
+/// let handler = ChainHandler::new()
+///     .with_handler(DirHandler::new("/srv/tftp", DirHandlerMode::ReadOnly)?)
+///     .with_handler(mem_handler);
+/// ```
+///
+/// [`DirHandler`]: super::DirHandler
+/// [`MemHandler`]: super::MemHandler
+#[derive(Default)]
+pub struct ChainHandler {
+    handlers: Vec<BoxedHandler>,
+    // Handler and id of the transfer of each client. Server runs only one
+    // transfer per client address.
+    serving: Serving,
+    next_id: u64,
+    // Handler that opened the last file, `max_block_size` is called right
+    // after the open and it is not given the client.
+    last_opened: Option<usize>,
+}
+
+type Serving = Arc<Mutex<HashMap<SocketAddr, (usize, u64)>>>;
+
+/// Reader of [`ChainHandler`] read requests.
+pub struct ChainReader {
+    reader: BoxedReader,
+    transfer: Transfer,
+}
+
+/// Writer of [`ChainHandler`] write requests.
+pub struct ChainWriter {
+    writer: BoxedWriter,
+    transfer: Transfer,
+}
+
+/// Transfer of a client, which leaves `serving` when its reader or writer
+/// is dropped, even if the transfer never reaches `transfer_done`.
+struct Transfer {
+    client: SocketAddr,
+    index: usize,
+    id: u64,
+    serving: Serving,
+}
+
+impl ChainHandler {
+    /// Create new handler without any handlers, which rejects all the
+    /// requests with [`packet::Error::FileNotFound`].
+    pub fn new() -> Self {
+        ChainHandler::default()
+    }
+
+    /// Add a handler to the end of the list.
+    pub fn with_handler<H>(mut self, handler: H) -> Self
+    where
+        H: Handler + 'static,
+    {
+        self.handlers.push(handler.boxed());
+        self
+    }
+
+    fn served(&mut self, client: &SocketAddr, index: usize) -> Transfer {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.serving.lock().unwrap().insert(*client, (index, id));
+        self.last_opened = Some(index);
+
+        Transfer {
+            client: *client,
+            index,
+            id,
+            serving: Arc::clone(&self.serving),
+        }
+    }
+
+    fn handler_of(&mut self, client: &SocketAddr) -> Option<&mut BoxedHandler> {
+        let (index, _) = *self.serving.lock().unwrap().get(client)?;
+        self.handlers.get_mut(index)
+    }
+}
+
+impl Handler for ChainHandler {
+    type Reader = ChainReader;
+    type Writer = ChainWriter;
+
+    fn max_block_size(&self, path: &Path) -> Option<u16> {
+        let handler = self.handlers.get(self.last_opened?)?;
+        handler.max_block_size(path)
+    }
+
+    async fn read_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        for (index, handler) in self.handlers.iter_mut().enumerate() {
+            let opened = match handler.should_serve(client, path, req).await {
+                Ok(()) => handler.read_req_open(client, path, req).await,
+                Err(e) => Err(e),
+            };
+
+            match opened {
+                Err(packet::Error::FileNotFound) => continue,
+                Err(e) => return Err(e),
+                Ok((reader, size)) => {
+                    let transfer = self.served(client, index);
+                    return Ok((
+                        ChainReader {
+                            reader,
+                            transfer,
+                        },
+                        size,
+                    ));
+                }
+            }
+        }
+
+        Err(packet::Error::FileNotFound)
+    }
+
+    async fn read_req_seek(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> io::Result<bool> {
+        match self.handlers.get_mut(reader.transfer.index) {
+            Some(handler) => {
+                handler
+                    .read_req_seek(client, path, &mut reader.reader, pos)
+                    .await
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        for (index, handler) in self.handlers.iter_mut().enumerate() {
+            let opened = match handler.should_serve(client, path, req).await {
+                Ok(()) => handler.write_req_open(client, path, req, size).await,
+                Err(e) => Err(e),
+            };
+
+            match opened {
+                Err(packet::Error::FileNotFound) => continue,
+                Err(e) => return Err(e),
+                Ok(writer) => {
+                    let transfer = self.served(client, index);
+                    return Ok(ChainWriter {
+                        writer,
+                        transfer,
+                    });
+                }
+            }
+        }
+
+        Err(packet::Error::FileNotFound)
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        match self.handlers.get_mut(writer.transfer.index) {
+            Some(handler) => {
+                handler.write_req_sync(client, path, &mut writer.writer).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn client_aborted(
+        &mut self,
+        client: &SocketAddr,
+        error: &packet::Error,
+    ) {
+        if let Some(handler) = self.handler_of(client) {
+            handler.client_aborted(client, error).await;
+        }
+    }
+
+    async fn size_probe(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) {
+        if let Some(handler) = self.handler_of(client) {
+            handler.size_probe(client, path, req).await;
+        }
+    }
+
+    async fn transfer_done(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        result: Result<TransferStats, TransferError>,
+    ) {
+        if let Some(handler) = self.handler_of(client) {
+            handler.transfer_done(client, path, result).await;
+        }
+    }
+}
+
+impl AsyncRead for ChainReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChainWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let mut serving = self.serving.lock().unwrap();
+
+        // A restarted transfer may already be replaced by a new one.
+        if serving.get(&self.client).is_some_and(|(_, id)| *id == self.id) {
+            serving.remove(&self.client);
+        }
+    }
+}
@@ -1,10 +1,19 @@
+#[cfg(feature = "gzip")]
+use async_compression::futures::bufread::GzipDecoder;
 use blocking::{unblock, Unblock};
+#[cfg(feature = "gzip")]
+use futures_lite::io::BufReader;
+use futures_lite::{AsyncRead, AsyncSeekExt, AsyncWrite};
 use log::trace;
-use std::fs::{self, File};
-use std::io;
-use std::net::SocketAddr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Component;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
 
 use crate::error::{Error, Result};
 use crate::packet;
@@ -12,10 +21,26 @@ use crate::packet;
 /// Handler that serves read requests for a directory.
 pub struct DirHandler {
     dir: PathBuf,
+    // Directory of write requests, which is `dir` unless it is split.
+    write_dir: PathBuf,
     serve_rrq: bool,
     serve_wrq: bool,
+    preallocate: PreallocateMode,
+    sync_writes: bool,
+    follow_symlinks: bool,
+    client_ip_fallback: bool,
+    decompress_gz: bool,
+    max_upload_size: Option<u64>,
+    require_tsize: bool,
+    atomic_writes: bool,
+    dos_paths: bool,
+    // Lowercase extensions that are served, or all if `None`.
+    extensions: Option<Vec<String>>,
+    remap: Option<RemapFn>,
 }
 
+type RemapFn = Box<dyn Fn(&Path) -> Option<PathBuf> + Send + Sync>;
+
 pub enum DirHandlerMode {
     /// Serve only read requests.
     ReadOnly,
@@ -25,19 +50,30 @@ pub enum DirHandlerMode {
     ReadWrite,
 }
 
+/// How files of write requests are allocated, see
+/// [`DirHandler::preallocate_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreallocateMode {
+    /// File grows as the data are written.
+    None,
+    /// File is extended to its size with `set_len`, which creates a
+    /// sparse file on most filesystems.
+    Sparse,
+    /// Disk space of the file is allocated with `posix_fallocate`, so
+    /// the upload can not run out of space. On platforms without
+    /// `posix_fallocate` this is the same as [`Sparse`].
+    ///
+    /// [`Sparse`]: Self::Sparse
+    Full,
+}
+
 impl DirHandler {
     /// Create new handler for directory.
     pub fn new<P>(dir: P, flags: DirHandlerMode) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let dir = fs::canonicalize(dir.as_ref())?;
-
-        if !dir.is_dir() {
-            return Err(Error::NotDir(dir));
-        }
-
-        trace!("TFTP directory: {}", dir.display());
+        let dir = served_dir(dir.as_ref())?;
 
         let serve_rrq = match flags {
             DirHandlerMode::ReadOnly => true,
@@ -52,64 +88,464 @@ impl DirHandler {
         };
 
         Ok(DirHandler {
+            write_dir: dir.clone(),
             dir,
             serve_rrq,
             serve_wrq,
+            preallocate: PreallocateMode::None,
+            sync_writes: false,
+            follow_symlinks: false,
+            client_ip_fallback: false,
+            decompress_gz: false,
+            max_upload_size: None,
+            require_tsize: false,
+            atomic_writes: false,
+            dos_paths: false,
+            extensions: None,
+            remap: None,
         })
     }
+
+    /// Create new handler that serves read requests for `read_dir` and
+    /// write requests for `write_dir`.
+    ///
+    /// Each request is restricted to its own directory, so files of one
+    /// directory can not be read or written through the other.
+    pub fn split<P, Q>(read_dir: P, write_dir: Q) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let write_dir = served_dir(write_dir.as_ref())?;
+
+        Ok(DirHandler {
+            write_dir,
+            ..DirHandler::new(read_dir, DirHandlerMode::ReadWrite)?
+        })
+    }
+
+    /// Preallocate files of write requests.
+    ///
+    /// If client advertises the size of the file with `tsize`, the file
+    /// is extended to this size before the transfer starts. Note that on
+    /// most filesystems this creates a sparse file, which keeps its size
+    /// even if the transfer fails.
+    ///
+    /// This is the same as [`preallocate_mode`] with
+    /// [`PreallocateMode::Sparse`].
+    ///
+    /// [`preallocate_mode`]: Self::preallocate_mode
+    pub fn preallocate(self) -> Self {
+        self.preallocate_mode(PreallocateMode::Sparse)
+    }
+
+    /// Set how files of write requests are allocated when client
+    /// advertises their size with `tsize`.
+    ///
+    /// **Default:** [`PreallocateMode::None`]
+    pub fn preallocate_mode(self, mode: PreallocateMode) -> Self {
+        DirHandler {
+            preallocate: mode,
+            ..self
+        }
+    }
+
+    /// Sync files of write requests to the disk when their transfer
+    /// completes.
+    ///
+    /// Without this the data are flushed to the OS, which writes them to
+    /// the disk later, so they may be lost if the machine crashes.
+    pub fn sync_writes(self) -> Self {
+        DirHandler {
+            sync_writes: true,
+            ..self
+        }
+    }
+
+    /// Write uploads to a temporary file and rename it to the requested
+    /// name when the transfer completes.
+    ///
+    /// The temporary file is created next to the destination and removed
+    /// if the transfer fails, so an interrupted upload leaves the
+    /// destination untouched.
+    pub fn atomic_writes(self) -> Self {
+        DirHandler {
+            atomic_writes: true,
+            ..self
+        }
+    }
+
+    /// Reject write requests that advertise a larger `tsize` than `size`.
+    ///
+    /// Requests are rejected with [`packet::Error::DiskFull`] before the
+    /// file is created. Uploads without `tsize` are not limited, unless
+    /// [`require_tsize_on_write`] is set.
+    ///
+    /// [`require_tsize_on_write`]: Self::require_tsize_on_write
+    pub fn max_upload_size(self, size: u64) -> Self {
+        DirHandler {
+            max_upload_size: Some(size),
+            ..self
+        }
+    }
+
+    /// Reject write requests that do not advertise their size with `tsize`.
+    pub fn require_tsize_on_write(self) -> Self {
+        DirHandler {
+            require_tsize: true,
+            ..self
+        }
+    }
+
+    /// Follow symlinks that point outside of the served directory.
+    ///
+    /// By default paths are resolved and requests are rejected with
+    /// [`packet::Error::PermissionDenied`] if they end up outside of the
+    /// served directory.
+    ///
+    /// **Default:** `false`
+    pub fn follow_symlinks(self, follow: bool) -> Self {
+        DirHandler {
+            follow_symlinks: follow,
+            ..self
+        }
+    }
+
+    /// Treat `\` in requested paths as a directory separator, as DOS and
+    /// Windows clients send them.
+    ///
+    /// Paths are normalized before they are checked, so `a\..\b` is
+    /// rejected like `a/../b`. Without this, `a\b` is a file with a
+    /// backslash in its name on Unix.
+    pub fn dos_paths(self) -> Self {
+        DirHandler {
+            dos_paths: true,
+            ..self
+        }
+    }
+
+    /// Serve pxelinux configs that match the IP address of the client.
+    ///
+    /// A read request of `pxelinux.cfg/default` is served with the first
+    /// file of the same directory that exists, out of the IPv4 address of
+    /// the client in uppercase hex and its shorter prefixes, e.g.
+    /// `C0A8010A`, `C0A8010`, ..., `C` for `192.168.1.10`. If none of them
+    /// exists, `default` is served.
+    ///
+    /// pxelinux asks for `default` only after its UUID, MAC and IP-hex
+    /// configs, so other files are served as requested and the lookup
+    /// order of pxelinux is kept, while clients that ask for `default`
+    /// directly still get their own config.
+    pub fn with_client_ip_fallback(self) -> Self {
+        DirHandler {
+            client_ip_fallback: true,
+            ..self
+        }
+    }
+
+    /// Serve gzip-compressed files when the requested file does not exist.
+    ///
+    /// A read request of `image.bin` is served with the decompressed
+    /// content of `image.bin.gz`, if `image.bin` does not exist. The size
+    /// of the decompressed content is unknown, so `tsize` is not
+    /// acknowledged for these files.
+    #[cfg(feature = "gzip")]
+    pub fn decompress_gz(self) -> Self {
+        DirHandler {
+            decompress_gz: true,
+            ..self
+        }
+    }
+
+    /// Serve only files with the given extensions to read requests.
+    ///
+    /// Extensions are matched case-insensitively and without the leading
+    /// `.`, e.g. `&["0", "cfg", "img"]`. Other files are rejected with
+    /// [`packet::Error::PermissionDenied`].
+    pub fn with_extensions(self, extensions: &[&str]) -> Self {
+        let extensions =
+            extensions.iter().map(|ext| ext.to_lowercase()).collect();
+
+        DirHandler {
+            extensions: Some(extensions),
+            ..self
+        }
+    }
+
+    /// Map requested paths of read requests to other files.
+    ///
+    /// The callback gets the requested path, relative to the served
+    /// directory and without any `/` or `./` prefix. If it returns a path,
+    /// that file is served instead, otherwise the requested path is used.
+    /// Returned path is not restricted to the served directory.
+    ///
+    /// ```ignore
+    /// let handler = DirHandler::new("/srv/tftp", DirHandlerMode::ReadOnly)?
+    ///     .with_remap(|path| {
+    ///         (path == Path::new("pxelinux.0"))
+    ///             .then(|| PathBuf::from("/usr/lib/syslinux/pxelinux.0"))
+    ///     });
+    /// ```
+    pub fn with_remap<F>(self, remap: F) -> Self
+    where
+        F: Fn(&Path) -> Option<PathBuf> + Send + Sync + 'static,
+    {
+        DirHandler {
+            remap: Some(Box::new(remap)),
+            ..self
+        }
+    }
+
+    /// Resolve symlinks of `path` and make sure that it is still in the
+    /// served directory `dir`.
+    fn resolve_symlinks(
+        &self,
+        dir: &Path,
+        path: PathBuf,
+    ) -> Result<PathBuf, packet::Error> {
+        if self.follow_symlinks {
+            return Ok(path);
+        }
+
+        let resolved = match fs::canonicalize(&path) {
+            Ok(resolved) => resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // A dangling symlink would be followed on creation.
+                if fs::symlink_metadata(&path).is_ok() {
+                    return Err(packet::Error::PermissionDenied);
+                }
+
+                // File does not exist (yet), so we resolve its directory.
+                match (path.parent(), path.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        fs::canonicalize(parent)?.join(name)
+                    }
+                    _ => return Err(packet::Error::FileNotFound),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        if resolved.starts_with(dir) {
+            Ok(resolved)
+        } else {
+            Err(packet::Error::PermissionDenied)
+        }
+    }
+
+    /// First file named after `client` that exists next to `path`, if
+    /// `path` is in a `pxelinux.cfg` directory.
+    fn client_ip_lookup(&self, client: &SocketAddr, path: PathBuf) -> PathBuf {
+        if path.file_name() != Some("default".as_ref()) {
+            return path;
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if dir.file_name() == Some("pxelinux.cfg".as_ref()) => {
+                dir
+            }
+            _ => return path,
+        };
+
+        let found = client_ip_names(client)
+            .into_iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file());
+
+        found.unwrap_or(path)
+    }
+
+    /// Requested path with `\` separators replaced, if `dos_paths` is set.
+    fn requested_path(&self, path: &Path) -> PathBuf {
+        match self.dos_paths {
+            true => dos_path(path),
+            false => path.to_path_buf(),
+        }
+    }
+
+    fn is_allowed_extension(&self, path: &Path) -> bool {
+        let extensions = match &self.extensions {
+            Some(extensions) => extensions,
+            None => return true,
+        };
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => extensions.contains(&ext.to_lowercase()),
+            None => false,
+        }
+    }
 }
 
 impl crate::server::Handler for DirHandler {
-    type Reader = Unblock<File>;
-    type Writer = Unblock<File>;
+    type Reader = DirReader;
+    type Writer = DirWriter;
 
     async fn read_req_open(
         &mut self,
-        _client: &SocketAddr,
+        client: &SocketAddr,
         path: &Path,
+        _req: &packet::RwReq,
     ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
         if !self.serve_rrq {
             return Err(packet::Error::IllegalOperation);
         }
 
-        let path = secure_path(&self.dir, path)?;
+        let path = secure_path(&self.dir, &self.requested_path(path))?;
+
+        if !self.is_allowed_extension(&path) {
+            return Err(packet::Error::PermissionDenied);
+        }
+
+        let path = match self.client_ip_fallback {
+            true => self.client_ip_lookup(client, path),
+            false => path,
+        };
+
+        let remapped = self.remap.as_ref().and_then(|remap| {
+            remap(path.strip_prefix(&self.dir).unwrap_or(&path))
+        });
+
+        // Resolved paths have no symlinks, so a symlink that shows up
+        // before the file is opened is not followed.
+        let nofollow = remapped.is_none() && !self.follow_symlinks;
+
+        let path = match remapped {
+            Some(path) => path,
+            None => self.resolve_symlinks(&self.dir, path)?,
+        };
+
+        let (path, gzip) = match gz_path(&path) {
+            Some(gz) if self.decompress_gz && !path.is_file() => (gz, true),
+            _ => (path, false),
+        };
 
         // Send only regular files
-        if !path.is_file() {
-            return Err(packet::Error::FileNotFound);
+        match path.metadata() {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => {
+                trace!("TFTP not a regular file: {}", path.display());
+                return Err(packet::Error::IllegalOperation);
+            }
+            Err(e) => return Err(e.into()),
         }
 
         let path_clone = path.clone();
-        let (file, len) = unblock(move || open_file_ro(path_clone)).await?;
-        let reader = Unblock::new(file);
+        let (file, len) =
+            unblock(move || open_file_ro(path_clone, nofollow)).await?;
+        let file = Unblock::new(file);
+
+        let (reader, len) = match gzip {
+            #[cfg(feature = "gzip")]
+            true => {
+                let decoder = GzipDecoder::new(BufReader::new(file));
+                (DirReader(ReaderKind::Gzip(decoder)), None)
+            }
+            _ => (DirReader(ReaderKind::File(file)), len),
+        };
 
         trace!("TFTP sending file: {}", path.display());
 
         Ok((reader, len))
     }
 
+    async fn read_req_seek(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> io::Result<bool> {
+        match &mut reader.0 {
+            ReaderKind::File(file) => {
+                file.seek(SeekFrom::Start(pos)).await?;
+                Ok(true)
+            }
+            #[cfg(feature = "gzip")]
+            ReaderKind::Gzip(_) => Ok(false),
+        }
+    }
+
     async fn write_req_open(
         &mut self,
         _client: &SocketAddr,
         path: &Path,
+        _req: &packet::RwReq,
         size: Option<u64>,
     ) -> Result<Self::Writer, packet::Error> {
         if !self.serve_wrq {
             return Err(packet::Error::IllegalOperation);
         }
 
-        let path = secure_path(&self.dir, path)?;
+        match (size, self.max_upload_size) {
+            (Some(size), Some(max)) if size > max => {
+                return Err(packet::Error::DiskFull);
+            }
+            (None, _) if self.require_tsize => {
+                let msg = "Transfer size is required".to_string();
+                return Err(packet::Error::Msg(msg));
+            }
+            _ => {}
+        }
 
-        let path_clone = path.clone();
-        let file = unblock(move || open_file_wo(path_clone, size)).await?;
-        let writer = Unblock::new(file);
+        let path = secure_path(&self.write_dir, &self.requested_path(path))?;
+        let path = self.resolve_symlinks(&self.write_dir, path)?;
+
+        let upload = match self.atomic_writes {
+            true => Some((temp_path(&path)?, path.clone())),
+            false => None,
+        };
+
+        let open_path = match &upload {
+            Some((temp, _)) => temp.clone(),
+            None => path.clone(),
+        };
+        let size = size.map(|size| (size, self.preallocate));
+        let nofollow = upload.is_some() || !self.follow_symlinks;
+        let file =
+            unblock(move || open_file_wo(open_path, size, nofollow)).await?;
 
         trace!("TFTP receiving file: {}", path.display());
 
-        Ok(writer)
+        Ok(DirWriter {
+            file: Unblock::new(file),
+            upload,
+        })
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        if self.sync_writes {
+            writer.file.with_mut(|file| file.sync_all()).await?;
+        }
+
+        if let Some((temp, path)) = writer.upload.clone() {
+            let path =
+                unblock(move || fs::rename(temp, &path).map(|_| path)).await?;
+            trace!("TFTP upload done: {}", path.display());
+            writer.upload = None;
+        }
+
+        Ok(())
     }
 }
 
+/// Canonical path of a served directory.
+fn served_dir(dir: &Path) -> Result<PathBuf> {
+    let dir = fs::canonicalize(dir)?;
+
+    if !dir.is_dir() {
+        return Err(Error::NotDir(dir));
+    }
+
+    trace!("TFTP directory: {}", dir.display());
+
+    Ok(dir)
+}
+
 fn secure_path(
     restricted_dir: &Path,
     path: &Path,
@@ -135,18 +571,218 @@ fn secure_path(
     Ok(restricted_dir.join(path))
 }
 
-fn open_file_ro(path: PathBuf) -> io::Result<(File, Option<u64>)> {
-    let file = File::open(path)?;
+/// Path with `\` separators replaced with `/`.
+fn dos_path(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().replace('\\', "/"))
+}
+
+/// Hidden path next to `path` for its upload, which is unique within the
+/// process.
+fn temp_path(path: &Path) -> Result<PathBuf, packet::Error> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let name = path.file_name().ok_or(packet::Error::FileNotFound)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut temp = std::ffi::OsString::from(".");
+    temp.push(name);
+    temp.push(format!(".{}.{}.tmp", process::id(), id));
+    Ok(path.with_file_name(temp))
+}
+
+/// Path of the gzip-compressed version of `path`.
+fn gz_path(path: &Path) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_owned();
+    name.push(".gz");
+    Some(path.with_file_name(name))
+}
+
+/// Names that pxelinux looks up for the IP address of `client`, from the
+/// most to the least specific. IPv6 clients have none.
+fn client_ip_names(client: &SocketAddr) -> Vec<String> {
+    let ip = match client.ip().to_canonical() {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Vec::new(),
+    };
+
+    let hex = format!("{:08X}", u32::from(ip));
+    (1..=hex.len()).rev().map(|len| hex[..len].to_string()).collect()
+}
+
+/// Open file for reading.
+///
+/// Length is taken from the opened file, so it matches the content even if
+/// the path is replaced in the meantime.
+fn open_file_ro(
+    path: PathBuf,
+    nofollow: bool,
+) -> io::Result<(File, Option<u64>)> {
+    let mut opts = OpenOptions::new();
+    opts.read(true);
+    set_nofollow(&mut opts, nofollow);
+
+    let file = opts.open(path)?;
     let len = file.metadata().ok().map(|m| m.len());
     Ok((file, len))
 }
 
-fn open_file_wo(path: PathBuf, size: Option<u64>) -> io::Result<File> {
-    let file = File::create(path)?;
+fn open_file_wo(
+    path: PathBuf,
+    size: Option<(u64, PreallocateMode)>,
+    nofollow: bool,
+) -> io::Result<File> {
+    let mut opts = OpenOptions::new();
+    opts.write(true).create(true).truncate(true);
+    set_nofollow(&mut opts, nofollow);
 
-    if let Some(size) = size {
-        file.set_len(size)?;
+    let file = opts.open(path)?;
+
+    match size {
+        Some((size, PreallocateMode::Sparse)) => file.set_len(size)?,
+        Some((size, PreallocateMode::Full)) => allocate(&file, size)?,
+        Some((_, PreallocateMode::None)) | None => {}
     }
 
     Ok(file)
 }
+
+/// Allocate `size` bytes of disk space for `file`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let len = libc::off_t::try_from(size)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    // SAFETY: The fd is valid while `file` is alive.
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+        0 => Ok(()),
+        rc => Err(io::Error::from_raw_os_error(rc)),
+    }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd"
+)))]
+fn allocate(file: &File, size: u64) -> io::Result<()> {
+    file.set_len(size)
+}
+
+#[cfg(unix)]
+fn set_nofollow(opts: &mut OpenOptions, nofollow: bool) {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    if nofollow {
+        opts.custom_flags(libc::O_NOFOLLOW);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_nofollow(_opts: &mut OpenOptions, _nofollow: bool) {}
+
+/// Reader of [`DirHandler`] read requests.
+pub struct DirReader(ReaderKind);
+
+enum ReaderKind {
+    File(Unblock<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzipDecoder<BufReader<Unblock<File>>>),
+}
+
+impl AsyncRead for DirReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.0 {
+            ReaderKind::File(file) => Pin::new(file).poll_read(cx, buf),
+            #[cfg(feature = "gzip")]
+            ReaderKind::Gzip(decoder) => Pin::new(decoder).poll_read(cx, buf),
+        }
+    }
+}
+
+/// Writer of [`DirHandler`] write requests.
+pub struct DirWriter {
+    file: Unblock<File>,
+    // Temporary file and destination of an atomic write, which is renamed
+    // when the transfer completes.
+    upload: Option<(PathBuf, PathBuf)>,
+}
+
+impl AsyncWrite for DirWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.file).poll_close(cx)
+    }
+}
+
+impl Drop for DirWriter {
+    fn drop(&mut self) {
+        // Upload did not complete, e.g. its transfer failed before it
+        // started or it was cancelled.
+        if let Some((temp, _)) = &self.upload {
+            let _ = fs::remove_file(temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_ip_names_chain() {
+        let client = "192.0.2.91:69".parse().unwrap();
+        assert_eq!(
+            client_ip_names(&client),
+            [
+                "C000025B", "C000025", "C00002", "C0000", "C000", "C00", "C0",
+                "C"
+            ]
+        );
+
+        let client = "[::ffff:10.0.0.1]:69".parse().unwrap();
+        assert_eq!(client_ip_names(&client)[0], "0A000001");
+
+        let client = "[2001:db8::1]:69".parse().unwrap();
+        assert!(client_ip_names(&client).is_empty());
+    }
+
+    #[test]
+    fn dos_path_separators() {
+        assert_eq!(dos_path(Path::new("a\\b\\c")), Path::new("a/b/c"));
+        assert_eq!(dos_path(Path::new("\\boot.cfg")), Path::new("/boot.cfg"));
+        assert_eq!(dos_path(Path::new("a/b")), Path::new("a/b"));
+
+        let dir = Path::new("/srv/tftp");
+        assert_eq!(
+            secure_path(dir, &dos_path(Path::new("a\\b\\c"))).unwrap(),
+            Path::new("/srv/tftp/a/b/c")
+        );
+        assert!(matches!(
+            secure_path(dir, &dos_path(Path::new("a\\..\\b"))),
+            Err(packet::Error::PermissionDenied)
+        ));
+    }
+}
@@ -0,0 +1,155 @@
+use bytes::Bytes;
+use futures_lite::io::Cursor;
+use futures_lite::AsyncWrite;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::packet;
+
+/// Handler that serves files from memory.
+///
+/// Files are looked up by the requested filename, without any leading `/`.
+/// Clones of the handler share the same files, so you can keep one to
+/// inspect uploads while the server runs.
+#[derive(Clone, Default)]
+pub struct MemHandler {
+    files: Arc<Mutex<HashMap<String, Bytes>>>,
+    serve_wrq: bool,
+}
+
+impl MemHandler {
+    /// Create new handler without any files that serves only read
+    /// requests.
+    pub fn new() -> Self {
+        MemHandler::default()
+    }
+
+    /// Store uploads of write requests.
+    ///
+    /// A file is added, or replaced, when its transfer completes. Failed
+    /// transfers leave the files untouched.
+    pub fn allow_writes(self) -> Self {
+        MemHandler {
+            serve_wrq: true,
+            ..self
+        }
+    }
+
+    /// Add a file, returns the previous content of `name` if any.
+    pub fn insert(
+        &self,
+        name: impl Into<String>,
+        data: impl Into<Bytes>,
+    ) -> Option<Bytes> {
+        self.files.lock().unwrap().insert(name.into(), data.into())
+    }
+
+    /// Get the content of a file.
+    pub fn get(&self, name: &str) -> Option<Bytes> {
+        self.files.lock().unwrap().get(name).cloned()
+    }
+
+    /// Remove a file, returns its content if it existed.
+    pub fn remove(&self, name: &str) -> Option<Bytes> {
+        self.files.lock().unwrap().remove(name)
+    }
+}
+
+impl crate::server::Handler for MemHandler {
+    type Reader = Cursor<Bytes>;
+    type Writer = MemWriter;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        let data =
+            self.get(&file_name(path)).ok_or(packet::Error::FileNotFound)?;
+        let len = data.len() as u64;
+
+        Ok((Cursor::new(data), Some(len)))
+    }
+
+    async fn read_req_seek(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> io::Result<bool> {
+        reader.set_position(pos);
+        Ok(true)
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        if !self.serve_wrq {
+            return Err(packet::Error::IllegalOperation);
+        }
+
+        Ok(MemWriter {
+            name: file_name(path),
+            data: Vec::new(),
+        })
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        // Upload is added only when its transfer completes
+        let data = std::mem::take(&mut writer.data);
+        self.insert(writer.name.clone(), data);
+        Ok(())
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    let path = path.strip_prefix("/").unwrap_or(path);
+    path.to_string_lossy().into_owned()
+}
+
+/// Writer of [`MemHandler`] uploads.
+pub struct MemWriter {
+    name: String,
+    data: Vec<u8>,
+}
+
+impl AsyncWrite for MemWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.data.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
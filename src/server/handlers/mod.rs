@@ -1,5 +1,13 @@
 //! Handlers for common use-cases.
 
+mod boxed;
+mod chain;
 mod dir;
+mod mem;
+mod read_fn;
 
+pub use self::boxed::*;
+pub use self::chain::*;
 pub use self::dir::*;
+pub use self::mem::*;
+pub use self::read_fn::*;
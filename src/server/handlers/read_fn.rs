@@ -0,0 +1,54 @@
+use futures_lite::io::Sink;
+use futures_lite::AsyncRead;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::packet;
+use crate::server::Handler;
+
+/// Handler that serves read requests with a closure, see
+/// [`TftpServerBuilder::serve_read_fn`].
+///
+/// The closure gets the address of the client and the requested path, and
+/// returns the reader of the file and its size, if it is known. Write
+/// requests are rejected with [`packet::Error::IllegalOperation`].
+///
+/// [`TftpServerBuilder::serve_read_fn`]: crate::server::TftpServerBuilder::serve_read_fn
+pub struct ReadFnHandler<F>(F);
+
+impl<F> ReadFnHandler<F> {
+    /// Create new handler that serves read requests with `read_fn`.
+    pub fn new(read_fn: F) -> Self {
+        ReadFnHandler(read_fn)
+    }
+}
+
+impl<F, Fut, R> Handler for ReadFnHandler<F>
+where
+    F: Fn(SocketAddr, PathBuf) -> Fut + Send,
+    Fut: Future<Output = Result<(R, Option<u64>), packet::Error>> + Send,
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    type Reader = R;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        (self.0)(*client, path.to_path_buf()).await
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Err(packet::Error::IllegalOperation)
+    }
+}
@@ -2,9 +2,11 @@
 
 mod builder;
 mod handler;
+mod rate_limit;
 mod read_req;
 #[allow(clippy::module_inception)]
 mod server;
+mod span;
 mod write_req;
 
 pub mod handlers;
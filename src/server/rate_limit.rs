@@ -0,0 +1,82 @@
+use async_io::Timer;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Token bucket of the send rate, of a transfer or shared by all the
+/// transfers of a server.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    // Tokens that can be saved up while the server is idle.
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // Goes negative when senders reserve more than what is available, the
+    // debt is paid by sleeping.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter that allows bursts of 100ms.
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        let burst = bytes_per_sec / 10.0;
+
+        RateLimiter {
+            bytes_per_sec,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` can be sent.
+    ///
+    /// Tokens are reserved right away, so concurrent senders are served in
+    /// the order they asked and none of them can starve.
+    pub(crate) async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            self.refill(&mut bucket);
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+        };
+
+        Timer::after(wait).await;
+    }
+
+    /// Take `bytes` only if they can be sent right away.
+    ///
+    /// Unlike [`acquire`](Self::acquire) the bucket never goes into debt,
+    /// so callers can drop what exceeds the rate instead of waiting.
+    pub(crate) fn try_acquire(&self, bytes: usize) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        self.refill(&mut bucket);
+
+        if bucket.tokens < bytes as f64 {
+            return false;
+        }
+
+        bucket.tokens -= bytes as f64;
+        true
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill);
+
+        bucket.tokens = (bucket.tokens
+            + elapsed.as_secs_f64() * self.bytes_per_sec)
+            .min(self.burst);
+        bucket.last_refill = now;
+    }
+}
@@ -1,42 +1,386 @@
+use async_channel::{Receiver, Sender};
 use async_executor::Executor;
 use async_io::Async;
-use async_lock::Mutex;
+use async_lock::{
+    Mutex, RwLock, RwLockReadGuardArc, Semaphore, SemaphoreGuardArc,
+};
+use bytes::{Bytes, BytesMut};
+use futures_lite::future;
 use log::trace;
-use std::collections::HashSet;
+use std::cmp;
+use std::collections::HashMap;
 use std::future::Future;
+use std::io;
 use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
+use super::rate_limit::RateLimiter;
 use super::read_req::*;
+use super::span;
 use super::write_req::*;
-use super::Handler;
+use super::{Handler, TransferError, TransferStats};
 use crate::error::*;
-use crate::packet::{Packet, RwReq};
+use crate::packet::{
+    self, Mode, Opts, Packet, PacketType, RwReq, PACKET_DATA_HEADER_LEN,
+};
+use crate::utils::{
+    bind_udp, is_socket_closed, is_transient_error, route_mtu, SocketOpts,
+};
 
 /// TFTP server.
 pub struct TftpServer<H>
 where
     H: Handler,
 {
-    pub(crate) socket: Async<UdpSocket>,
+    pub(crate) socket: Arc<Async<UdpSocket>>,
     pub(crate) handler: Arc<Mutex<H>>,
-    pub(crate) reqs_in_progress: Arc<Mutex<HashSet<SocketAddr>>>,
-    pub(crate) ex: Executor<'static>,
+    pub(crate) reqs_in_progress: ReqsInProgress,
+    pub(crate) ex: Arc<Executor<'static>>,
+    pub(crate) task_slots: Option<Arc<Semaphore>>,
+    // Requests that wait for a task slot.
+    pub(crate) pending_slots: Option<Arc<Semaphore>>,
+    // Every transfer holds a read lock until it is done.
+    pub(crate) transfers: Arc<RwLock<()>>,
+    pub(crate) shutdown_tx: Sender<Shutdown>,
+    pub(crate) shutdown_rx: Receiver<Shutdown>,
+    // Never used for sending, transfers are cancelled when it is closed.
+    pub(crate) cancel_tx: Sender<()>,
+    pub(crate) cancel_rx: Receiver<()>,
+    // Listening socket, when the server stops.
+    pub(crate) socket_tx: Sender<UdpSocket>,
+    pub(crate) socket_rx: Receiver<UdpSocket>,
     pub(crate) config: ServerConfig,
     pub(crate) local_ip: IpAddr,
+    // Replies to unexpected packets, which can come from spoofed addresses.
+    pub(crate) unexpected_replies: RateLimiter,
+}
+
+/// Handle that stops a [`TftpServer`].
+///
+/// It can be cloned and sent to other threads. Methods of the handle
+/// can be called before [`TftpServer::serve`] is called, in which case
+/// `serve` returns immediately.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    tx: Sender<Shutdown>,
+}
+
+/// Handle that gives back the listening socket of a [`TftpServer`] after
+/// it stops.
+///
+/// It is returned by [`TftpServer::socket_handle`].
+#[derive(Debug)]
+pub struct SocketHandle {
+    rx: Receiver<UdpSocket>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Shutdown {
+    Graceful,
+    Now,
+}
+
+impl ShutdownHandle {
+    /// Stop accepting new requests. [`TftpServer::serve`] returns `Ok(())`
+    /// after all the transfers in progress are done.
+    ///
+    /// Requests that arrive in the meantime are refused with an ERROR
+    /// packet, unless [`drop_requests_on_shutdown`] is set.
+    ///
+    /// [`drop_requests_on_shutdown`]: super::TftpServerBuilder::drop_requests_on_shutdown
+    pub fn shutdown(&self) {
+        let _ = self.tx.try_send(Shutdown::Graceful);
+    }
+
+    /// Stop accepting new requests and cancel all the transfers in
+    /// progress. [`TftpServer::serve`] returns `Ok(())`.
+    pub fn shutdown_now(&self) {
+        let _ = self.tx.try_send(Shutdown::Now);
+    }
+}
+
+impl SocketHandle {
+    /// Wait until [`TftpServer::serve`] or [`TftpServer::serve_on`]
+    /// returns and take the listening socket.
+    ///
+    /// The socket is left in non-blocking mode. It is a duplicate of the
+    /// listening socket if transfers of the server still hold it, e.g.
+    /// cancelled transfers of an executor that is not run anymore. Returns
+    /// an error if the server is dropped without serving.
+    pub async fn into_std_socket(self) -> Result<UdpSocket> {
+        self.rx.recv().await.map_err(|_| {
+            io::Error::new(io::ErrorKind::NotConnected, "server dropped").into()
+        })
+    }
+}
+
+enum Event {
+    Recv(io::Result<(usize, SocketAddr)>),
+    Handled,
+    Shutdown(Shutdown),
+}
+
+pub(crate) type ReqsInProgress = Arc<Mutex<HashMap<SocketAddr, ReqInProgress>>>;
+
+/// Channels of a request in progress.
+pub(crate) struct ReqInProgress {
+    // Notified when the client sends its request again.
+    req_resent: Sender<()>,
+    // Datagrams of the client, in single port mode.
+    datagrams: Option<Sender<Bytes>>,
+    // Closed to cancel the request, when the client restarts it. It also
+    // tells the request apart from the one that replaces it.
+    cancel: Arc<Sender<()>>,
+}
+
+/// Resources that are held by a transfer until it is done.
+struct TransferGuard {
+    task_slot: TaskSlot,
+    _transfer: RwLockReadGuardArc<()>,
+    // Channel of `ReqInProgress::cancel`.
+    cancel: (Arc<Sender<()>>, Receiver<()>),
+}
+
+/// Slot of a transfer within the limit of `max_tasks`.
+enum TaskSlot {
+    Unlimited,
+    Acquired(SemaphoreGuardArc),
+    // Waits for a slot, while holding a slot of `max_pending_tasks`.
+    Pending(Arc<Semaphore>, SemaphoreGuardArc),
+}
+
+impl TaskSlot {
+    async fn acquire(self) -> Option<SemaphoreGuardArc> {
+        match self {
+            TaskSlot::Unlimited => None,
+            TaskSlot::Acquired(slot) => Some(slot),
+            TaskSlot::Pending(task_slots, _pending_slot) => {
+                Some(task_slots.acquire_arc().await)
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct ServerConfig {
     pub(crate) timeout: Duration,
     pub(crate) block_size_limit: Option<u16>,
-    pub(crate) max_send_retries: u32,
+    // Limit of `max_block_size_buffer`, which the handler can not raise.
+    pub(crate) block_size_buffer_limit: Option<u16>,
+    pub(crate) block_size_min: u16,
+    pub(crate) path_mtu: Option<u16>,
+    pub(crate) auto_mtu_clamp: bool,
+    pub(crate) window_size_limit: Option<u16>,
+    pub(crate) default_window_size: Option<u16>,
+    pub(crate) read_ahead: usize,
+    pub(crate) read_max_retries: u32,
+    pub(crate) write_max_retries: u32,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_total_retransmits: Option<u64>,
+    pub(crate) on_retransmit: Option<RetransmitFn>,
+    pub(crate) timeout_jitter: Option<Duration>,
+    pub(crate) rate_limiter: Option<Arc<RateLimiter>>,
+    pub(crate) per_client_rate_limit: Option<u64>,
     pub(crate) ignore_client_timeout: bool,
     pub(crate) ignore_client_block_size: bool,
+    pub(crate) ignore_client_window_size: bool,
+    pub(crate) no_oack: bool,
+    pub(crate) tolerant_transfer_size: bool,
+    pub(crate) tolerant_ack: bool,
+    pub(crate) strict_options: bool,
+    pub(crate) unexpected_packet_policy: UnexpectedPacketPolicy,
+    pub(crate) max_error_message_len: usize,
+    pub(crate) max_filename_len: usize,
+    pub(crate) masked_error: Option<packet::Error>,
+    pub(crate) allow_client: Option<AllowClientFn>,
+    pub(crate) drop_denied_clients: bool,
+    pub(crate) drop_requests_on_shutdown: bool,
+    pub(crate) reply_unknown_tid: bool,
+    pub(crate) single_port: bool,
+    pub(crate) restart_on_new_request: bool,
+    pub(crate) socket_opts: SocketOpts,
+    pub(crate) transfer_port_range: Option<RangeInclusive<u16>>,
+    pub(crate) events: Events,
+}
+
+impl ServerConfig {
+    /// Encode an ERROR packet within the message limit.
+    pub(crate) fn error_packet(&self, error: &packet::Error) -> Bytes {
+        let mut buf = BytesMut::new();
+        Packet::encode_error(error, self.max_error_message_len, &mut buf);
+        buf.freeze()
+    }
+
+    /// Error of a request that the handler rejected with `error`.
+    pub(crate) fn handler_error(
+        &self,
+        peer: &SocketAddr,
+        error: packet::Error,
+    ) -> Error {
+        match &self.masked_error {
+            Some(masked) => {
+                trace!(
+                    "Handler error masked (peer: {}, error: {:?})",
+                    peer,
+                    error
+                );
+                Error::Packet(masked.clone())
+            }
+            None => Error::Packet(error),
+        }
+    }
+
+    /// Fail if the block size of OACK is below the minimum or, in strict
+    /// options mode, if the options of OACK are not the ones that client
+    /// requested.
+    pub(crate) fn check_oack_opts(
+        &self,
+        req: &RwReq,
+        oack_opts: Option<&Opts>,
+    ) -> Result<()> {
+        let block_size = oack_opts.and_then(|opts| opts.block_size);
+
+        if block_size.is_some_and(|bsize| bsize < self.block_size_min) {
+            return Err(Error::Packet(packet::Error::OptionNegotiationFailed));
+        }
+
+        if !self.strict_options {
+            return Ok(());
+        }
+
+        let oack_opts = oack_opts.cloned().unwrap_or_default();
+        let requested = &req.opts;
+
+        let honored = (requested.block_size.is_none()
+            || requested.block_size == oack_opts.block_size)
+            && (requested.window_size.is_none()
+                || requested.window_size == oack_opts.window_size)
+            && (requested.timeout.is_none()
+                || requested.timeout == oack_opts.timeout);
+
+        if honored {
+            Ok(())
+        } else {
+            Err(Error::Packet(packet::Error::OptionNegotiationFailed))
+        }
+    }
+
+    /// Replace the block size limit with `limit` of the handler, within
+    /// the range of RFC 2348 and the block buffer.
+    pub(crate) fn limit_block_size(&mut self, limit: u16) {
+        let limit = limit.clamp(MIN_BLOCK_SIZE as u16, MAX_BLOCK_SIZE as u16);
+
+        self.block_size_limit = match self.block_size_buffer_limit {
+            Some(max) => Some(cmp::min(limit, max)),
+            None => Some(limit),
+        };
+    }
+
+    /// Block size limit for `peer`, that also keeps DATA packets within
+    /// the path MTU.
+    pub(crate) fn block_size_limit_for(
+        &self,
+        peer: &SocketAddr,
+    ) -> Option<u16> {
+        let route_mtu = match self.auto_mtu_clamp {
+            true => route_mtu(*peer),
+            false => None,
+        };
+
+        let mtu = match (self.path_mtu.map(u32::from), route_mtu) {
+            (Some(mtu), Some(route_mtu)) => Some(cmp::min(mtu, route_mtu)),
+            (mtu, None) | (None, mtu) => mtu,
+        };
+
+        let mtu_limit = mtu.map(|mtu| {
+            let ip_header_len = match peer.ip().to_canonical() {
+                IpAddr::V4(_) => IPV4_HEADER_LEN,
+                IpAddr::V6(_) => IPV6_HEADER_LEN,
+            };
+
+            let max = (mtu as usize)
+                .saturating_sub(ip_header_len + UDP_HEADER_LEN)
+                .saturating_sub(PACKET_DATA_HEADER_LEN);
+
+            max.clamp(MIN_BLOCK_SIZE, usize::from(u16::MAX)) as u16
+        });
+
+        match (self.block_size_limit, mtu_limit) {
+            (Some(limit), Some(mtu_limit)) => Some(cmp::min(limit, mtu_limit)),
+            (limit, None) | (None, limit) => limit,
+        }
+    }
+}
+
+pub(crate) type AllowClientFn = Arc<dyn Fn(&SocketAddr) -> bool + Send + Sync>;
+pub(crate) type RetransmitFn = Arc<dyn Fn(&SocketAddr, u16, u32) + Send + Sync>;
+
+/// Policy for packets that are received on the listening socket but they
+/// are neither RRQ nor WRQ (e.g. a stray DATA or ACK).
+#[derive(Debug, Clone, Default)]
+pub enum UnexpectedPacketPolicy {
+    /// Silently ignore the packet.
+    #[default]
+    Ignore,
+    /// Reply with an ERROR packet.
+    ///
+    /// Use [`packet::Error::UnknownTransferId`] to reply as RFC1350
+    /// specifies, e.g. to a late retransmission of a transfer that is
+    /// already done, so the peer stops. ERROR packets are never answered,
+    /// so two peers with the same policy can not end up in an endless loop.
+    Error(packet::Error),
+}
+
+/// Event of a [`TftpServer`].
+///
+/// See [`TftpServerBuilder::events_channel`].
+///
+/// [`TftpServerBuilder::events_channel`]: super::TftpServerBuilder::events_channel
+#[derive(Debug, Clone)]
+pub enum ServerEvent {
+    /// A read or write request was received.
+    RequestReceived(SocketAddr, RwReq),
+    /// Handler accepted a request and its transfer started.
+    TransferStarted(SocketAddr),
+    /// A transfer completed successfully.
+    TransferCompleted(SocketAddr, TransferStats),
+    /// A request or its transfer failed on the server side.
+    TransferFailed(SocketAddr, TransferError),
+    /// Client aborted a transfer with an ERROR packet.
+    ClientAborted(SocketAddr, packet::Error),
+    /// Client aborted a read request after it got the size of the file,
+    /// see [`Handler::size_probe`].
+    SizeProbe(SocketAddr),
+}
+
+/// Sender of [`ServerEvent`]s that never blocks the server.
+#[derive(Clone, Default)]
+pub(crate) struct Events(pub(crate) Option<Sender<ServerEvent>>);
+
+impl Events {
+    /// Send an event. It is dropped if the channel is full or closed.
+    pub(crate) fn send(&self, event: ServerEvent) {
+        if let Some(tx) = &self.0 {
+            let _ = tx.try_send(event);
+        }
+    }
 }
 
 pub(crate) const DEFAULT_BLOCK_SIZE: usize = 512;
+// Smallest block size of RFC 2348.
+pub(crate) const MIN_BLOCK_SIZE: usize = 8;
+// Largest block size of RFC 2348.
+pub(crate) const MAX_BLOCK_SIZE: usize = 65464;
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+// Datagrams that are queued for a transfer in single port mode.
+const SINGLE_PORT_BACKLOG: usize = 64;
+// Bytes per second of replies to unexpected packets.
+pub(crate) const UNEXPECTED_REPLY_RATE: u64 = 4096;
 
 impl<H: 'static> TftpServer<H>
 where
@@ -47,146 +391,991 @@ where
         Ok(self.socket.get_ref().local_addr()?)
     }
 
+    /// Consume the server without serving and return its listening socket.
+    ///
+    /// This gives back a socket that was set with [`std_socket`], e.g. one
+    /// of systemd socket activation. The socket is left in non-blocking
+    /// mode. To reclaim the socket after serving, use [`socket_handle`].
+    ///
+    /// [`std_socket`]: super::TftpServerBuilder::std_socket
+    /// [`socket_handle`]: Self::socket_handle
+    pub fn into_std_socket(self) -> Result<UdpSocket> {
+        Ok(take_socket(self.socket)?)
+    }
+
+    /// Returns a handle that gives back the listening socket after the
+    /// server stops.
+    ///
+    /// Only one of the handles of a server gets the socket.
+    pub fn socket_handle(&self) -> SocketHandle {
+        SocketHandle {
+            rx: self.socket_rx.clone(),
+        }
+    }
+
+    /// Returns a handle that stops the server.
+    pub fn handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tx: self.shutdown_tx.clone(),
+        }
+    }
+
     /// Consume and start the server.
+    ///
+    /// Returns `Ok(())` when the server is stopped with a [`ShutdownHandle`]
+    /// or if the listening socket gets closed (e.g. its file descriptor was
+    /// closed by another part of the application). Errors that leave the
+    /// socket usable, e.g. `ECONNREFUSED` of an earlier reply, are logged
+    /// and the server keeps serving. Any other socket error is returned as
+    /// `Err`. Errors of binding the socket are returned by [`build`]
+    /// instead. The socket can be taken back with [`socket_handle`].
+    ///
+    /// Transfers run on an executor that is owned by the server and driven
+    /// by this future. Use [`serve_on`] to run them on another executor.
+    ///
+    /// **Note:** On Linux, `shutdown(2)` of a UDP socket does not produce
+    /// an error on the receiving side, so it can not be used to stop the server.
+    ///
+    /// [`build`]: crate::server::TftpServerBuilder::build
+    /// [`serve_on`]: Self::serve_on
+    /// [`socket_handle`]: Self::socket_handle
     pub async fn serve(self) -> Result<()> {
-        self.ex
-            .run(async {
-                let mut buf = [0u8; 4096];
+        let ex = Arc::clone(&self.ex);
+        ex.run(self.serve_loop()).await
+    }
+
+    /// Consume and start the server, spawning transfers on `ex`.
+    ///
+    /// This is the same as [`serve`], but `ex` is not driven by the
+    /// returned future, so it must be run by the caller (e.g. on a pool of
+    /// threads). Transfers in progress are cancelled when the server stops
+    /// with [`ShutdownHandle::shutdown_now`] or when the returned future is
+    /// dropped.
+    ///
+    /// [`serve`]: Self::serve
+    pub async fn serve_on(mut self, ex: Arc<Executor<'static>>) -> Result<()> {
+        self.ex = ex;
+        self.serve_loop().await
+    }
+
+    async fn serve_loop(self) -> Result<()> {
+        let res = self.accept_loop().await;
 
-                loop {
-                    let (len, peer) = self.socket.recv_from(&mut buf).await?;
+        if let Ok(socket) = take_socket(self.socket) {
+            let _ = self.socket_tx.try_send(socket);
+        }
+
+        res
+    }
+
+    async fn accept_loop(&self) -> Result<()> {
+        let mut buf = self.recv_buffer();
+
+        // Shutdown is polled first, otherwise a flood of requests that are
+        // always ready would starve it.
+        loop {
+            let event = future::or(
+                async { Event::Shutdown(self.recv_shutdown().await) },
+                async { Event::Recv(self.socket.recv_from(&mut buf).await) },
+            )
+            .await;
+
+            let (len, peer) = match event {
+                Event::Recv(Ok(x)) => x,
+                Event::Recv(Err(e)) if is_socket_closed(&e) => {
+                    trace!("Listening socket closed: {}", &e);
+                    return Ok(());
+                }
+                Event::Recv(Err(e)) if is_transient_error(&e) => {
+                    trace!("Listening socket error: {}", &e);
+                    continue;
+                }
+                Event::Recv(Err(e)) => return Err(e.into()),
+                Event::Shutdown(shutdown) => {
+                    self.stop(shutdown).await;
+                    return Ok(());
+                }
+                Event::Handled => unreachable!(),
+            };
+
+            // Waiting for a task slot blocks the accept loop, but it must
+            // not block the shutdown.
+            let event = future::or(
+                async { Event::Shutdown(self.recv_shutdown().await) },
+                async {
                     self.handle_req_packet(peer, &buf[..len]).await;
+                    Event::Handled
+                },
+            )
+            .await;
+
+            if let Event::Shutdown(shutdown) = event {
+                self.stop(shutdown).await;
+                return Ok(());
+            }
+        }
+    }
+
+    async fn stop(&self, shutdown: Shutdown) {
+        match shutdown {
+            Shutdown::Graceful => {
+                trace!("Graceful shutdown");
+                self.drain().await;
+            }
+            Shutdown::Now => {
+                trace!("Shutdown");
+                self.cancel_tx.close();
+            }
+        }
+    }
+
+    /// Buffer of the listening socket, which also receives DATA packets of
+    /// any block size in single port mode.
+    fn recv_buffer(&self) -> Vec<u8> {
+        match self.config.single_port {
+            true => vec![0u8; usize::from(u16::MAX)],
+            false => vec![0u8; 4096],
+        }
+    }
+
+    /// Spawn a request task that is cancelled when the server stops.
+    fn spawn_req(&self, req_fut: impl Future<Output = ()> + Send + 'static) {
+        let cancel_rx = self.cancel_rx.clone();
+
+        self.ex
+            .spawn(future::or(req_fut, async move {
+                let _ = cancel_rx.recv().await;
+            }))
+            .detach();
+    }
+
+    async fn recv_shutdown(&self) -> Shutdown {
+        // Server holds a sender, so the channel is never closed.
+        self.shutdown_rx.recv().await.unwrap_or(Shutdown::Now)
+    }
+
+    /// Wait until all transfers are done, or until `shutdown_now`
+    /// is called.
+    async fn drain(&self) {
+        future::or(
+            async {
+                self.transfers.write().await;
+            },
+            future::or(
+                async {
+                    while let Shutdown::Graceful = self.recv_shutdown().await {}
+                },
+                self.recv_while_draining(),
+            ),
+        )
+        .await
+    }
+
+    /// Keep receiving on the listening socket while new requests are not
+    /// accepted, to refuse requests and to pass datagrams to the transfers
+    /// in single port mode.
+    async fn recv_while_draining(&self) {
+        if !self.config.single_port && self.config.drop_requests_on_shutdown {
+            return future::pending().await;
+        }
+
+        let mut buf = self.recv_buffer();
+
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((len, peer)) => {
+                    self.handle_draining_packet(peer, &buf[..len]).await;
+                }
+                Err(e) if is_socket_closed(&e) => {
+                    return future::pending().await
                 }
-            })
-            .await
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Handle a datagram of the listening socket during graceful shutdown.
+    async fn handle_draining_packet(&self, peer: SocketAddr, data: &[u8]) {
+        if self.forward_datagram(peer, data).await || !is_request(data) {
+            return;
+        }
+
+        trace!("Request during shutdown (peer: {})", &peer);
+
+        if self.config.drop_requests_on_shutdown {
+            return;
+        }
+
+        let error = packet::Error::Msg("Server is shutting down".to_string());
+        let data = self.config.error_packet(&error);
+        // We do not care if `send_to` resulted to an IO error.
+        let _ = self.socket.send_to(&data[..], peer).await;
+    }
+
+    /// Pass a datagram to the transfer of `peer`, in single port mode.
+    ///
+    /// Returns `false` if there is no such transfer, or if the datagram is
+    /// a request.
+    async fn forward_datagram(&self, peer: SocketAddr, data: &[u8]) -> bool {
+        if !self.config.single_port || is_request(data) {
+            return false;
+        }
+
+        match self.reqs_in_progress.lock().await.get(&peer) {
+            Some(ReqInProgress {
+                datagrams: Some(datagrams),
+                ..
+            }) => {
+                // Datagrams are dropped if the transfer falls behind, as
+                // they would be by the OS for a socket of its own.
+                let _ = datagrams.try_send(Bytes::copy_from_slice(data));
+                true
+            }
+            _ => false,
+        }
     }
 
     async fn handle_req_packet(&self, peer: SocketAddr, data: &[u8]) {
+        if self.forward_datagram(peer, data).await {
+            return;
+        }
+
         let packet = match Packet::decode(data) {
-            Ok(p @ Packet::Rrq(_)) => p,
-            Ok(p @ Packet::Wrq(_)) => p,
-            // Ignore packets that are not requests
-            Ok(_) => return,
+            Ok(Packet::Rrq(req)) => {
+                let event = ServerEvent::RequestReceived(peer, req.clone());
+                self.config.events.send(event);
+                Packet::Rrq(req)
+            }
+            Ok(Packet::Wrq(req)) => {
+                let event = ServerEvent::RequestReceived(peer, req.clone());
+                self.config.events.send(event);
+                Packet::Wrq(req)
+            }
+            // Never reply to errors
+            Ok(Packet::Error(_)) => return,
+            Ok(_) => {
+                self.handle_unexpected_packet(peer).await;
+                return;
+            }
             // Ignore invalid packets
             Err(_) => return,
         };
 
-        if !self.reqs_in_progress.lock().await.insert(peer) {
-            // Ignore pending requests
-            return;
+        if let Packet::Rrq(req) | Packet::Wrq(req) = &packet {
+            if req.filename.len() > self.config.max_filename_len {
+                self.handle_long_filename(peer).await;
+                return;
+            }
+        }
+
+        if let Some(allow_client) = &self.config.allow_client {
+            if !allow_client(&peer) {
+                self.handle_denied_client(peer).await;
+                return;
+            }
+        }
+
+        // Requests in progress get notified when client sends its request
+        // again, which means that our first reply was lost.
+        if let Some(req) = self.reqs_in_progress.lock().await.get(&peer) {
+            if !self.config.restart_on_new_request {
+                trace!("Request resent (peer: {})", &peer);
+                let _ = req.req_resent.try_send(());
+                return;
+            }
+
+            // Cancelled before a task slot is taken, so its slot is freed.
+            trace!("Request restarted (peer: {})", &peer);
+            req.cancel.close();
         }
 
+        let task_slot = match self.task_slot().await {
+            Some(task_slot) => task_slot,
+            None => {
+                self.handle_busy(peer).await;
+                return;
+            }
+        };
+
+        let (cancel_tx, cancel_rx) = async_channel::bounded(1);
+        let cancel_tx = Arc::new(cancel_tx);
+
+        let guard = TransferGuard {
+            task_slot,
+            _transfer: self.transfers.read_arc().await,
+            cancel: (Arc::clone(&cancel_tx), cancel_rx),
+        };
+
+        // Only the accept loop inserts requests, so the peer is either
+        // not in progress or its request is cancelled and replaced.
+        let (req_resent_tx, req_resent_rx) = async_channel::bounded(1);
+        let (datagrams_tx, datagrams_rx) = match self.config.single_port {
+            true => {
+                let (tx, rx) = async_channel::bounded(SINGLE_PORT_BACKLOG);
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+
+        self.reqs_in_progress.lock().await.insert(
+            peer,
+            ReqInProgress {
+                req_resent: req_resent_tx,
+                datagrams: datagrams_tx,
+                cancel: cancel_tx,
+            },
+        );
+
+        let shared_socket =
+            datagrams_rx.map(|rx| (Arc::clone(&self.socket), rx));
+
         match packet {
-            Packet::Rrq(req) => self.handle_rrq(peer, req),
-            Packet::Wrq(req) => self.handle_wrq(peer, req),
+            Packet::Rrq(req) => {
+                self.handle_rrq(peer, req, guard, shared_socket)
+            }
+            Packet::Wrq(req) => {
+                self.handle_wrq(peer, req, guard, req_resent_rx, shared_socket)
+            }
             _ => unreachable!(),
         }
     }
 
-    fn handle_rrq(&self, peer: SocketAddr, req: RwReq) {
+    async fn handle_unexpected_packet(&self, peer: SocketAddr) {
+        let error = match &self.config.unexpected_packet_policy {
+            UnexpectedPacketPolicy::Ignore => return,
+            UnexpectedPacketPolicy::Error(e) => e.clone(),
+        };
+
+        trace!("Unexpected packet received (peer: {})", &peer);
+
+        let data = self.config.error_packet(&error);
+
+        // Stray packets are cheap to send with a forged source address,
+        // so do not let the server be used to flood someone with errors.
+        if !self.unexpected_replies.try_acquire(data.len()) {
+            trace!("Unexpected packet not answered (peer: {})", &peer);
+            return;
+        }
+
+        // We do not care if `send_to` resulted to an IO error.
+        let _ = self.socket.send_to(&data[..], peer).await;
+    }
+
+    /// Returns a task slot for a new request, or `None` if the request
+    /// must be rejected because too many requests are pending.
+    async fn task_slot(&self) -> Option<TaskSlot> {
+        let task_slots = match &self.task_slots {
+            Some(task_slots) => task_slots,
+            None => return Some(TaskSlot::Unlimited),
+        };
+
+        if let Some(slot) = task_slots.try_acquire_arc() {
+            return Some(TaskSlot::Acquired(slot));
+        }
+
+        match &self.pending_slots {
+            // Transfers of single port mode get their datagrams from the
+            // accept loop, so it must not block.
+            None if self.config.single_port => None,
+            // Wait for a free task slot. This blocks the accept loop while
+            // the executor is saturated.
+            None => Some(TaskSlot::Acquired(task_slots.acquire_arc().await)),
+            // Request waits for a free task slot in its own task.
+            Some(pending_slots) => {
+                let pending_slot = pending_slots.try_acquire_arc()?;
+                Some(TaskSlot::Pending(Arc::clone(task_slots), pending_slot))
+            }
+        }
+    }
+
+    async fn handle_busy(&self, peer: SocketAddr) {
+        trace!("Too many pending requests (peer: {})", &peer);
+
+        let error = packet::Error::Msg("Server is busy".to_string());
+        let data = self.config.error_packet(&error);
+        // We do not care if `send_to` resulted to an IO error.
+        let _ = self.socket.send_to(&data[..], peer).await;
+    }
+
+    async fn handle_long_filename(&self, peer: SocketAddr) {
+        trace!("Filename too long (peer: {})", &peer);
+
+        let data = self.config.error_packet(&packet::Error::IllegalOperation);
+        // We do not care if `send_to` resulted to an IO error.
+        let _ = self.socket.send_to(&data[..], peer).await;
+    }
+
+    async fn handle_denied_client(&self, peer: SocketAddr) {
+        trace!("Client denied (peer: {})", &peer);
+
+        if self.config.drop_denied_clients {
+            return;
+        }
+
+        let data = self.config.error_packet(&packet::Error::PermissionDenied);
+        // We do not care if `send_to` resulted to an IO error.
+        let _ = self.socket.send_to(&data[..], peer).await;
+    }
+
+    fn error_sender(&self) -> ErrorSender {
+        ErrorSender {
+            listen_socket: Arc::clone(&self.socket),
+            local_ip: self.local_ip,
+            config: self.config.clone(),
+        }
+    }
+
+    fn handle_rrq(
+        &self,
+        peer: SocketAddr,
+        req: RwReq,
+        guard: TransferGuard,
+        shared_socket: Option<SharedSocket>,
+    ) {
         trace!("RRQ recieved (peer: {}, req: {:?})", &peer, &req);
 
         let handler = Arc::clone(&self.handler);
-        let config = self.config.clone();
+        let mut config = self.config.clone();
         let local_ip = self.local_ip;
+        let error_sender = self.error_sender();
+        let events = self.config.events.clone();
+        let filename = req.filename.clone();
 
         // Prepare request future
         let req_fut = async move {
-            let (mut reader, size) = handler
-                .lock()
-                .await
-                .read_req_open(&peer, req.filename.as_ref())
-                .await
-                .map_err(Error::Packet)?;
+            // Mail mode is obsolete (RFC 1350)
+            if req.mode == Mode::Mail {
+                return Err(Error::Packet(packet::Error::IllegalOperation));
+            }
+
+            let (mut reader, size) = {
+                let mut handler = handler.lock().await;
+
+                handler
+                    .should_serve(&peer, req.filename.as_ref(), &req)
+                    .await
+                    .map_err(|e| config.handler_error(&peer, e))?;
+
+                let opened = handler
+                    .read_req_open(&peer, req.filename.as_ref(), &req)
+                    .await
+                    .map_err(|e| config.handler_error(&peer, e))?;
 
-            let mut read_req = ReadRequest::init(
-                &mut reader,
-                size,
+                if let Some(limit) =
+                    handler.max_block_size(req.filename.as_ref())
+                {
+                    config.limit_block_size(limit);
+                }
+
+                opened
+            };
+
+            let setup = async {
+                let socket = TransferSocket::new(
+                    local_ip,
+                    peer,
+                    &config,
+                    shared_socket,
+                )?;
+                let mut read_req = ReadRequest::init(
+                    &mut reader,
+                    size,
+                    peer,
+                    &req,
+                    config,
+                    socket,
+                )
+                .await?;
+
+                let seeked = match read_req.seek_position() {
+                    Some(pos) => {
+                        let seeked = handler
+                            .lock()
+                            .await
+                            .read_req_seek(
+                                &peer,
+                                req.filename.as_ref(),
+                                read_req.reader(),
+                                pos,
+                            )
+                            .await?;
+                        seeked.then_some(pos)
+                    }
+                    None => None,
+                };
+                read_req.resume(seeked).await?;
+
+                Ok(read_req)
+            };
+
+            let mut read_req = match setup.await {
+                Ok(read_req) => read_req,
+                Err(e) => {
+                    setup_failed(
+                        &handler,
+                        &events,
+                        &error_sender,
+                        peer,
+                        req.filename.as_ref(),
+                        e,
+                    )
+                    .await;
+                    return Ok(());
+                }
+            };
+
+            events.send(ServerEvent::TransferStarted(peer));
+
+            let result = read_req.handle().await;
+
+            if read_req.is_size_probe() {
+                trace!("RRQ size probe (peer: {})", &peer);
+                events.send(ServerEvent::SizeProbe(peer));
+                handler
+                    .lock()
+                    .await
+                    .size_probe(&peer, req.filename.as_ref(), &req)
+                    .await;
+                return Ok(());
+            }
+
+            transfer_done(
+                &handler,
+                &events,
                 peer,
-                &req,
-                config,
-                local_ip,
+                req.filename.as_ref(),
+                result,
             )
-            .await?;
-
-            read_req.handle().await;
+            .await;
 
             Ok(())
         };
 
         let reqs_in_progress = Arc::clone(&self.reqs_in_progress);
+        let events = self.config.events.clone();
 
         // Run request future in a new task
-        self.ex
-            .spawn(run_req(req_fut, peer, reqs_in_progress, local_ip))
-            .detach();
+        let req_fut = run_req(
+            req_fut,
+            peer,
+            reqs_in_progress,
+            self.error_sender(),
+            events,
+            guard,
+        );
+        self.spawn_req(span::instrument(req_fut, true, peer, &filename));
     }
 
-    fn handle_wrq(&self, peer: SocketAddr, req: RwReq) {
+    fn handle_wrq(
+        &self,
+        peer: SocketAddr,
+        req: RwReq,
+        guard: TransferGuard,
+        req_resent: Receiver<()>,
+        shared_socket: Option<SharedSocket>,
+    ) {
         trace!("WRQ recieved (peer: {}, req: {:?})", &peer, &req);
 
         let handler = Arc::clone(&self.handler);
-        let config = self.config.clone();
+        let mut config = self.config.clone();
         let local_ip = self.local_ip;
+        let error_sender = self.error_sender();
+        let events = self.config.events.clone();
+        let filename = req.filename.clone();
 
         // Prepare request future
         let req_fut = async move {
-            let mut writer = handler
-                .lock()
-                .await
-                .write_req_open(
-                    &peer,
-                    req.filename.as_ref(),
-                    req.opts.transfer_size,
+            // Mail mode is obsolete (RFC 1350)
+            if req.mode == Mode::Mail {
+                return Err(Error::Packet(packet::Error::IllegalOperation));
+            }
+
+            let mut writer = {
+                let mut handler = handler.lock().await;
+
+                handler
+                    .should_serve(&peer, req.filename.as_ref(), &req)
+                    .await
+                    .map_err(|e| config.handler_error(&peer, e))?;
+
+                let writer = handler
+                    .write_req_open(
+                        &peer,
+                        req.filename.as_ref(),
+                        &req,
+                        req.opts.transfer_size,
+                    )
+                    .await
+                    .map_err(|e| config.handler_error(&peer, e))?;
+
+                if let Some(limit) =
+                    handler.max_block_size(req.filename.as_ref())
+                {
+                    config.limit_block_size(limit);
+                }
+
+                writer
+            };
+
+            let setup = async {
+                let socket = TransferSocket::new(
+                    local_ip,
+                    peer,
+                    &config,
+                    shared_socket,
+                )?;
+                WriteRequest::init(
+                    &mut writer,
+                    peer,
+                    &req,
+                    config,
+                    socket,
+                    req_resent,
                 )
                 .await
-                .map_err(Error::Packet)?;
+            };
+
+            let mut write_req = match setup.await {
+                Ok(write_req) => write_req,
+                Err(e) => {
+                    setup_failed(
+                        &handler,
+                        &events,
+                        &error_sender,
+                        peer,
+                        req.filename.as_ref(),
+                        e,
+                    )
+                    .await;
+                    return Ok(());
+                }
+            };
+
+            events.send(ServerEvent::TransferStarted(peer));
+
+            let mut result = write_req.handle().await;
+
+            if result.is_ok() {
+                let sync = handler
+                    .lock()
+                    .await
+                    .write_req_sync(&peer, req.filename.as_ref(), &mut writer)
+                    .await;
 
-            let mut write_req =
-                WriteRequest::init(&mut writer, peer, &req, config, local_ip)
-                    .await?;
+                if let Err(e) = sync {
+                    result = Err(e.into());
+                }
+            }
 
-            write_req.handle().await;
+            transfer_done(
+                &handler,
+                &events,
+                peer,
+                req.filename.as_ref(),
+                result,
+            )
+            .await;
 
             Ok(())
         };
 
         let reqs_in_progress = Arc::clone(&self.reqs_in_progress);
+        let events = self.config.events.clone();
 
         // Run request future in a new task
-        self.ex
-            .spawn(run_req(req_fut, peer, reqs_in_progress, local_ip))
-            .detach();
+        let req_fut = run_req(
+            req_fut,
+            peer,
+            reqs_in_progress,
+            self.error_sender(),
+            events,
+            guard,
+        );
+        self.spawn_req(span::instrument(req_fut, false, peer, &filename));
+    }
+}
+
+async fn transfer_done<H: Handler>(
+    handler: &Mutex<H>,
+    events: &Events,
+    peer: SocketAddr,
+    path: &Path,
+    result: Result<TransferStats>,
+) {
+    let mut handler = handler.lock().await;
+
+    match &result {
+        Ok(stats) => {
+            events.send(ServerEvent::TransferCompleted(peer, stats.clone()))
+        }
+        Err(Error::ClientAborted {
+            code,
+            ..
+        }) => {
+            events.send(ServerEvent::ClientAborted(peer, code.clone()));
+            handler.client_aborted(&peer, code).await;
+        }
+        Err(_) => {}
+    }
+
+    let result = result.map_err(TransferError::from);
+
+    if let Err(e) = &result {
+        if !matches!(e, TransferError::ClientAborted(_)) {
+            events.send(ServerEvent::TransferFailed(peer, e.clone()));
+        }
     }
+
+    handler.transfer_done(&peer, path, result).await;
 }
 
-async fn send_error(
+/// Fail a transfer after the handler opened its reader or writer, so the
+/// handler gets the error in `transfer_done` as for any other failure.
+async fn setup_failed<H: Handler>(
+    handler: &Mutex<H>,
+    events: &Events,
+    error_sender: &ErrorSender,
+    peer: SocketAddr,
+    path: &Path,
     error: Error,
+) {
+    trace!("Request failed (peer: {}, error: {}", &peer, &error);
+
+    if let Err(e) = error_sender.send(packet::Error::from(&error), peer).await {
+        trace!("Failed to send error to peer {}: {}", &peer, &e);
+    }
+
+    transfer_done(handler, events, peer, path, Err(error)).await;
+}
+
+/// Bind the socket of a transfer on `local_ip` and connect it to `peer`.
+///
+/// The port is picked from the transfer port range, if one is set.
+/// Otherwise the OS assigns an ephemeral port. Since the socket is
+/// connected, the OS drops datagrams that do not come from `peer`. It is
+/// not connected with `reply_unknown_tid`, which needs to receive them.
+pub(crate) fn bind_transfer_socket(
+    local_ip: IpAddr,
+    peer: SocketAddr,
+    config: &ServerConfig,
+) -> Result<Async<UdpSocket>> {
+    let socket = bind_transfer_port(local_ip, config)?;
+
+    if !config.reply_unknown_tid {
+        socket.get_ref().connect(peer)?;
+    }
+
+    Ok(socket)
+}
+
+/// Takes the listening socket back from the transfers that may still hold
+/// it, or duplicates it.
+fn take_socket(socket: Arc<Async<UdpSocket>>) -> io::Result<UdpSocket> {
+    match Arc::try_unwrap(socket) {
+        Ok(socket) => socket.into_inner(),
+        Err(socket) => socket.get_ref().try_clone(),
+    }
+}
+
+/// Whether `data` is an RRQ or a WRQ, without parsing the rest of it.
+fn is_request(data: &[u8]) -> bool {
+    let opcode = match data {
+        [a, b, ..] => u16::from_be_bytes([*a, *b]),
+        _ => return false,
+    };
+
+    matches!(
+        PacketType::from_u16(opcode),
+        Some(PacketType::Rrq | PacketType::Wrq)
+    )
+}
+
+/// Listening socket and the datagrams that the accept loop forwards to a
+/// transfer, in single port mode.
+pub(crate) type SharedSocket = (Arc<Async<UdpSocket>>, Receiver<Bytes>);
+
+/// Socket of a transfer.
+pub(crate) enum TransferSocket {
+    /// Socket that is bound for the transfer.
+    Bound(Async<UdpSocket>),
+    /// Listening socket of the server, in single port mode. Datagrams of
+    /// the client are received from the accept loop.
+    Shared(Arc<Async<UdpSocket>>, Receiver<Bytes>, SocketAddr),
+}
+
+impl TransferSocket {
+    pub(crate) fn new(
+        local_ip: IpAddr,
+        peer: SocketAddr,
+        config: &ServerConfig,
+        shared_socket: Option<SharedSocket>,
+    ) -> Result<Self> {
+        match shared_socket {
+            Some((socket, datagrams)) => {
+                Ok(TransferSocket::Shared(socket, datagrams, peer))
+            }
+            None => Ok(TransferSocket::Bound(bind_transfer_socket(
+                local_ip, peer, config,
+            )?)),
+        }
+    }
+
+    /// UDP socket that is used for sending.
+    pub(crate) fn udp(&self) -> &Async<UdpSocket> {
+        match self {
+            TransferSocket::Bound(socket) => socket,
+            TransferSocket::Shared(socket, ..) => socket,
+        }
+    }
+
+    pub(crate) async fn send_to(
+        &self,
+        buf: &[u8],
+        peer: SocketAddr,
+    ) -> io::Result<usize> {
+        self.udp().send_to(buf, peer).await
+    }
+
+    pub(crate) async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            TransferSocket::Bound(socket) => socket.recv_from(buf).await,
+            TransferSocket::Shared(_, datagrams, peer) => {
+                // Accept loop keeps the sender until the transfer is done.
+                let data = datagrams
+                    .recv()
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+                let len = cmp::min(data.len(), buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok((len, *peer))
+            }
+        }
+    }
+}
+
+/// Reply with ERROR `UnknownTransferId` (RFC1350) to a datagram that
+/// reached a transfer socket from another address than its client.
+pub(crate) async fn send_unknown_tid(
+    socket: &Async<UdpSocket>,
+    data: &[u8],
     peer: SocketAddr,
+) {
+    trace!("Packet from unknown transfer ID (peer: {})", &peer);
+
+    // Never reply to errors
+    if let Ok(Packet::Error(_)) = Packet::decode(data) {
+        return;
+    }
+
+    let data = Packet::Error(packet::Error::UnknownTransferId).to_bytes();
+    // We do not care if `send_to` resulted to an IO error.
+    let _ = socket.send_to(&data[..], peer).await;
+}
+
+fn bind_transfer_port(
     local_ip: IpAddr,
-) -> Result<()> {
-    let addr: SocketAddr = SocketAddr::new(local_ip, 0);
-    let socket = Async::<UdpSocket>::bind(addr).map_err(Error::Bind)?;
+    config: &ServerConfig,
+) -> Result<Async<UdpSocket>> {
+    let ports = match &config.transfer_port_range {
+        Some(range) => range.clone(),
+        None => {
+            let addr = SocketAddr::new(local_ip, 0);
+            return bind_udp(addr, &config.socket_opts).map_err(Error::Bind);
+        }
+    };
+
+    let mut last_err = None;
 
-    let data = Packet::Error(error.into()).to_bytes();
-    socket.send_to(&data[..], peer).await?;
+    for port in ports {
+        let addr = SocketAddr::new(local_ip, port);
 
-    Ok(())
+        match bind_udp(addr, &config.socket_opts) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                last_err = Some(e)
+            }
+            Err(e) => return Err(Error::Bind(e)),
+        }
+    }
+
+    Err(Error::Bind(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::AddrInUse, "Transfer port range is empty")
+    })))
+}
+
+/// Sends the ERROR packet of a failed request.
+struct ErrorSender {
+    listen_socket: Arc<Async<UdpSocket>>,
+    local_ip: IpAddr,
+    config: ServerConfig,
+}
+
+impl ErrorSender {
+    async fn send(&self, error: packet::Error, peer: SocketAddr) -> Result<()> {
+        let data = self.config.error_packet(&error);
+
+        // Clients in single port mode expect replies from the listening
+        // socket only.
+        if self.config.single_port {
+            self.listen_socket.send_to(&data[..], peer).await?;
+            return Ok(());
+        }
+
+        match bind_transfer_socket(self.local_ip, peer, &self.config) {
+            Ok(socket) => socket.send_to(&data[..], peer).await?,
+            // All transfer ports are taken, reply from the listening
+            // socket instead of leaving the client waiting.
+            Err(Error::Bind(_)) => {
+                self.listen_socket.send_to(&data[..], peer).await?
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(())
+    }
 }
 
 async fn run_req(
     req_fut: impl Future<Output = Result<()>>,
     peer: SocketAddr,
-    reqs_in_progress: Arc<Mutex<HashSet<SocketAddr>>>,
-    local_ip: IpAddr,
+    reqs_in_progress: ReqsInProgress,
+    error_sender: ErrorSender,
+    events: Events,
+    // Resources are released when the request is done.
+    guard: TransferGuard,
 ) {
-    if let Err(e) = req_fut.await {
-        trace!("Request failed (peer: {}, error: {}", &peer, &e);
+    let (cancel_tx, cancel_rx) = guard.cancel;
+
+    let run = async {
+        let _task_slot = guard.task_slot.acquire().await;
+
+        if let Err(e) = req_fut.await {
+            trace!("Request failed (peer: {}, error: {}", &peer, &e);
+
+            let error = packet::Error::from(&e);
+            events.send(ServerEvent::TransferFailed(peer, e.into()));
 
-        if let Err(e) = send_error(e, peer, local_ip).await {
-            trace!("Failed to send error to peer {}: {}", &peer, &e);
+            if let Err(e) = error_sender.send(error, peer).await {
+                trace!("Failed to send error to peer {}: {}", &peer, &e);
+            }
         }
-    }
+    };
+
+    future::or(run, async {
+        let _ = cancel_rx.recv().await;
+    })
+    .await;
 
-    reqs_in_progress.lock().await.remove(&peer);
+    let mut reqs_in_progress = reqs_in_progress.lock().await;
+
+    // A restarted request is already replaced by the new one.
+    if reqs_in_progress
+        .get(&peer)
+        .is_some_and(|req| Arc::ptr_eq(&req.cancel, &cancel_tx))
+    {
+        reqs_in_progress.remove(&peer);
+    }
 }
@@ -0,0 +1,65 @@
+//! Spans of transfer tasks, with the `tracing` feature.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+/// Run the task of a read (`is_rrq`) or write request in its span.
+///
+/// Span has the peer and the filename. Block size and window size are
+/// recorded when they are negotiated, with [`record_opts`].
+#[cfg(feature = "tracing")]
+pub(crate) fn instrument<F>(
+    fut: F,
+    is_rrq: bool,
+    peer: SocketAddr,
+    filename: &str,
+) -> impl Future<Output = F::Output>
+where
+    F: Future,
+{
+    use tracing::field::Empty;
+    use tracing::Instrument;
+
+    let span = match is_rrq {
+        true => tracing::info_span!(
+            "RRQ",
+            %peer,
+            filename,
+            block_size = Empty,
+            window_size = Empty
+        ),
+        false => tracing::info_span!(
+            "WRQ",
+            %peer,
+            filename,
+            block_size = Empty,
+            window_size = Empty
+        ),
+    };
+
+    fut.instrument(span)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn instrument<F>(
+    fut: F,
+    _is_rrq: bool,
+    _peer: SocketAddr,
+    _filename: &str,
+) -> F
+where
+    F: Future,
+{
+    fut
+}
+
+/// Add the negotiated options to the span of the current transfer.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_opts(block_size: usize, window_size: usize) {
+    let span = tracing::Span::current();
+    span.record("block_size", block_size);
+    span.record("window_size", window_size);
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn record_opts(_block_size: usize, _window_size: usize) {}
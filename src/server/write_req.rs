@@ -1,24 +1,32 @@
-use async_io::Async;
+use async_channel::Receiver;
 use bytes::{Buf, Bytes, BytesMut};
-use futures_lite::{AsyncWrite, AsyncWriteExt};
+use futures_lite::{future, AsyncWrite, AsyncWriteExt};
 use log::trace;
 use std::cmp;
 use std::io;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use crate::error::{Error, Result};
-use crate::packet::{Opts, Packet, RwReq, PACKET_DATA_HEADER_LEN};
-use crate::server::{ServerConfig, DEFAULT_BLOCK_SIZE};
-use crate::utils::io_timeout;
+use crate::packet::{self, Mode, Opts, Packet, RwReq, PACKET_DATA_HEADER_LEN};
+use crate::server::span;
+use crate::server::{
+    send_unknown_tid, RetransmitFn, ServerConfig, TransferSocket,
+    TransferStats, DEFAULT_BLOCK_SIZE,
+};
+use crate::utils::{block_id_add, ignore_refused, io_timeout, Jitter};
 
 pub(crate) struct WriteRequest<'w, W>
 where
     W: AsyncWrite + Send,
 {
     peer: SocketAddr,
-    socket: Async<UdpSocket>,
+    socket: TransferSocket,
     writer: &'w mut W,
+    mode: Mode,
+    // Last byte of previous block was a netascii `\r`.
+    netascii_cr: bool,
+    netascii_buffer: Vec<u8>,
     // BytesMut reclaims memory only if it is continuous.
     // Because we always need to keep the previous ACK, we can not use
     // `buffer` as its storage since it breaks the continuity.
@@ -26,9 +34,31 @@ where
     buffer: BytesMut,
     ack: BytesMut,
     block_size: usize,
+    window_size: usize,
+    // Blocks that were received since the last ACK was sent.
+    window_received: usize,
+    // ACK of the last block in sequence was sent because of an out of order
+    // block, it is sent only once until the next block in sequence.
+    gap_acked: bool,
+    rollover: u16,
+    // Size that client advertised with `tsize`.
+    transfer_size: Option<u64>,
     timeout: Duration,
     max_retries: u32,
+    max_error_message_len: usize,
     oack_opts: Option<Opts>,
+    // Options of the request, reported if the client aborts.
+    requested: Opts,
+    stats: TransferStats,
+    // Notified when client sends its request again.
+    req_resent: Receiver<()>,
+    idle_timeout: Option<Duration>,
+    max_total_retransmits: Option<u64>,
+    on_retransmit: Option<RetransmitFn>,
+    jitter: Option<Jitter>,
+    reply_unknown_tid: bool,
+    // Last time that a new block was received.
+    last_progress: Instant,
 }
 
 impl<'w, W> WriteRequest<'w, W>
@@ -40,9 +70,11 @@ where
         peer: SocketAddr,
         req: &RwReq,
         config: ServerConfig,
-        local_ip: IpAddr,
+        socket: TransferSocket,
+        req_resent: Receiver<()>,
     ) -> Result<WriteRequest<'w, W>> {
-        let oack_opts = build_oack_opts(&config, req);
+        let oack_opts = build_oack_opts(&config, &peer, req);
+        config.check_oack_opts(req, oack_opts.as_ref())?;
 
         let block_size = oack_opts
             .as_ref()
@@ -50,37 +82,95 @@ where
             .map(usize::from)
             .unwrap_or(DEFAULT_BLOCK_SIZE);
 
+        let window_size = oack_opts
+            .as_ref()
+            .and_then(|o| o.window_size)
+            .map(usize::from)
+            .unwrap_or(1);
+
         let timeout = oack_opts
             .as_ref()
             .and_then(|o| o.timeout)
             .map(|t| Duration::from_secs(u64::from(t)))
             .unwrap_or(config.timeout);
 
-        let addr = SocketAddr::new(local_ip, 0);
-        let socket = Async::<UdpSocket>::bind(addr).map_err(Error::Bind)?;
+        span::record_opts(block_size, window_size);
 
         Ok(WriteRequest {
             peer,
             socket,
             writer,
+            mode: req.mode,
+            netascii_cr: false,
+            netascii_buffer: Vec::new(),
             buffer: BytesMut::new(),
             ack: BytesMut::new(),
             block_size,
+            window_size,
+            window_received: 0,
+            gap_acked: false,
+            rollover: oack_opts.as_ref().and_then(|o| o.rollover).unwrap_or(0),
+            transfer_size: req.opts.transfer_size.filter(|_| !config.no_oack),
             timeout,
-            max_retries: config.max_send_retries,
+            max_retries: config.write_max_retries,
+            max_error_message_len: config.max_error_message_len,
             oack_opts,
+            requested: req.opts.clone(),
+            stats: TransferStats {
+                block_size: block_size as u16,
+                window_size: window_size as u16,
+                timeout,
+                ..TransferStats::default()
+            },
+            idle_timeout: config.idle_timeout,
+            max_total_retransmits: config.max_total_retransmits,
+            on_retransmit: config.on_retransmit.clone(),
+            jitter: config.timeout_jitter.map(Jitter::new),
+            reply_unknown_tid: config.reply_unknown_tid,
+            last_progress: Instant::now(),
+            req_resent,
         })
     }
 
-    pub(crate) async fn handle(&mut self) {
-        if let Err(e) = self.try_handle().await {
-            trace!("WRQ request failed (peer: {}, error: {}", self.peer, &e);
+    pub(crate) async fn handle(&mut self) -> Result<TransferStats> {
+        let start = Instant::now();
+
+        match self.try_handle().await {
+            Ok(()) => {
+                self.stats.duration = start.elapsed();
+                Ok(self.stats.clone())
+            }
+            // Never reply to errors
+            Err(
+                e @ Error::ClientAborted {
+                    ..
+                },
+            ) => {
+                trace!(
+                    "WRQ request aborted (peer: {}, error: {})",
+                    self.peer,
+                    &e
+                );
+                Err(e)
+            }
+            Err(e) => {
+                trace!(
+                    "WRQ request failed (peer: {}, error: {}",
+                    self.peer,
+                    &e
+                );
 
-            Packet::Error(e.into()).encode(&mut self.buffer);
-            let buf = self.buffer.split().freeze();
-            // Errors are never retransmitted.
-            // We do not care if `send_to` resulted to an IO error.
-            let _ = self.socket.send_to(&buf[..], self.peer).await;
+                Packet::encode_error(
+                    &packet::Error::from(&e),
+                    self.max_error_message_len,
+                    &mut self.buffer,
+                );
+                let buf = self.buffer.split().freeze();
+                // Errors are never retransmitted.
+                // We do not care if `send_to` resulted to an IO error.
+                let _ = self.socket.send_to(&buf[..], self.peer).await;
+                Err(e)
+            }
         }
     }
 
@@ -93,39 +183,134 @@ where
             None => Packet::Ack(0).encode(&mut self.ack),
         }
 
-        self.socket.send_to(&self.ack, self.peer).await?;
+        self.send_ack().await?;
 
         loop {
             // Recv data
-            block_id = block_id.wrapping_add(1);
+            block_id = block_id_add(block_id, 1, self.rollover);
             let data = self.recv_data(block_id).await?;
+            self.stats.bytes += data.len() as u64;
+            self.stats.blocks += 1;
 
             // Write data to file
-            self.writer.write_all(&data[..]).await?;
+            match self.mode {
+                Mode::Netascii => {
+                    decode_netascii(
+                        &data,
+                        &mut self.netascii_cr,
+                        &mut self.netascii_buffer,
+                    );
+                    self.writer.write_all(&self.netascii_buffer).await?;
+                }
+                _ => self.writer.write_all(&data[..]).await?,
+            }
 
             if data.len() < self.block_size {
                 break;
             }
         }
 
+        // A `\r` at the end of the transfer is not followed by anything.
+        if self.netascii_cr {
+            self.writer.write_all(b"\r").await?;
+        }
+
+        // Buffered writers may still hold the last blocks.
+        self.writer.flush().await?;
+
         Ok(())
     }
 
+    /// Receive block `block_id`.
+    ///
+    /// Blocks are acknowledged once per window (RFC7440) and with the last
+    /// block. On a timeout, or on a block that is out of order, the last
+    /// block in sequence is acknowledged again so the client sends the
+    /// window again after it.
     async fn recv_data(&mut self, block_id: u16) -> Result<Bytes> {
-        for _ in 0..=self.max_retries {
-            match self.recv_data_block(block_id).await {
-                Ok(data) => {
-                    // Data received, send ACK
+        let mut retries = 0;
+        // Retransmissions of the ACK that asks for `block_id`.
+        let mut attempt = 0;
+
+        while retries <= self.max_retries {
+            let timeout = self.recv_timeout()?;
+            let req_resent = self.req_resent.clone();
+            let ack_gap = self.window_size > 1 && !self.gap_acked;
+            let recv = self.recv_data_block(block_id, ack_gap, timeout);
+
+            let res = future::or(recv, async move {
+                match req_resent.recv().await {
+                    // Client did not receive our first reply, so we handle
+                    // it as a timeout.
+                    Ok(()) => Err(io::ErrorKind::TimedOut.into()),
+                    Err(_) => future::pending().await,
+                }
+            })
+            .await;
+
+            match res {
+                Ok(RecvBlock::Aborted(code)) => {
+                    return Err(Error::ClientAborted {
+                        peer: self.peer,
+                        code,
+                        requested: Box::new(self.requested.clone()),
+                    })
+                }
+                Ok(RecvBlock::Oversized) => {
+                    trace!(
+                        "WRQ (peer: {}, block_id: {}) - Block too large",
+                        self.peer,
+                        block_id
+                    );
+                    return Err(Error::Packet(packet::Error::IllegalOperation));
+                }
+                Ok(RecvBlock::Data(data)) => {
+                    // Do not acknowledge data beyond the advertised size
+                    if let Some(transfer_size) = self.transfer_size {
+                        if self.stats.bytes + data.len() as u64 > transfer_size
+                        {
+                            return Err(Error::Packet(packet::Error::DiskFull));
+                        }
+                    }
+
+                    self.last_progress = Instant::now();
+                    self.gap_acked = false;
+                    self.window_received += 1;
+
                     self.ack.clear();
                     Packet::Ack(block_id).encode(&mut self.ack);
 
-                    self.socket.send_to(&self.ack, self.peer).await?;
+                    // Data received, send ACK at the end of the window
+                    if self.window_received == self.window_size
+                        || data.len() < self.block_size
+                    {
+                        self.send_ack().await?;
+                    }
+
                     return Ok(data);
                 }
+                Ok(RecvBlock::OutOfOrder) => {
+                    trace!(
+                        "WRQ (peer: {}, block_id: {}) - Block out of order",
+                        self.peer,
+                        block_id
+                    );
+                    self.gap_acked = true;
+                    attempt += 1;
+                    self.count_retransmits(1, block_id, attempt)?;
+                    self.send_ack().await?;
+                }
                 Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    // Do not resend an ACK that we will not wait for
+                    if retries == self.max_retries {
+                        break;
+                    }
+
                     // On timeout reply with the previous ACK packet
-                    self.socket.send_to(&self.ack, self.peer).await?;
-                    continue;
+                    attempt += 1;
+                    self.count_retransmits(1, block_id, attempt)?;
+                    self.send_ack().await?;
+                    retries += 1;
                 }
                 Err(e) => return Err(e.into()),
             }
@@ -134,53 +319,205 @@ where
         Err(Error::MaxSendRetriesReached(self.peer, block_id))
     }
 
-    async fn recv_data_block(&mut self, block_id: u16) -> io::Result<Bytes> {
+    /// Send the ACK of the last block in sequence, or the first OACK/ACK if
+    /// no block is received yet.
+    async fn send_ack(&mut self) -> Result<()> {
+        self.window_received = 0;
+        ignore_refused(self.socket.send_to(&self.ack, self.peer).await)?;
+        Ok(())
+    }
+
+    /// Count `n` retransmitted packets of `attempt` for `block_id`, fails
+    /// with `Error::MaxRetransmitsReached` when they are too many.
+    fn count_retransmits(
+        &mut self,
+        n: u64,
+        block_id: u16,
+        attempt: u32,
+    ) -> Result<()> {
+        self.stats.retransmits += n;
+
+        if let Some(on_retransmit) = &self.on_retransmit {
+            on_retransmit(&self.peer, block_id, attempt);
+        }
+
+        match self.max_total_retransmits {
+            Some(max) if self.stats.retransmits > max => {
+                Err(Error::MaxRetransmitsReached(self.peer))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Timeout of the next receive, with its jitter.
+    ///
+    /// It is shortened so the transfer is not idle for more than
+    /// `idle_timeout`, which fails with `Error::IdleTimeout` when it is
+    /// reached.
+    fn recv_timeout(&mut self) -> Result<Duration> {
+        let jitter = self.jitter.as_mut().map(Jitter::next_delay);
+        let timeout = self.timeout + jitter.unwrap_or_default();
+
+        let Some(idle_timeout) = self.idle_timeout else {
+            return Ok(timeout);
+        };
+
+        match idle_timeout.checked_sub(self.last_progress.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => {
+                Ok(cmp::min(timeout, remaining))
+            }
+            _ => Err(Error::IdleTimeout(self.peer)),
+        }
+    }
+
+    /// Receive block `block_id`.
+    ///
+    /// Returns `OutOfOrder` if `ack_gap` is set and another block is
+    /// received.
+    async fn recv_data_block(
+        &mut self,
+        block_id: u16,
+        ack_gap: bool,
+        timeout: Duration,
+    ) -> io::Result<RecvBlock> {
         let socket = &mut self.socket;
         let peer = self.peer;
+        let reply_unknown_tid = self.reply_unknown_tid;
+        let max_len = PACKET_DATA_HEADER_LEN + self.block_size;
 
-        self.buffer.resize(PACKET_DATA_HEADER_LEN + self.block_size, 0);
+        // One more byte than a full block, so a larger datagram is not
+        // silently truncated to a valid block.
+        self.buffer.resize(max_len + 1, 0);
         let mut buf = self.buffer.split();
 
-        io_timeout(self.timeout, async move {
+        io_timeout(timeout, async move {
             loop {
-                let (len, recved_peer) = socket.recv_from(&mut buf[..]).await?;
+                let (len, recved_peer) =
+                    match ignore_refused(socket.recv_from(&mut buf[..]).await)?
+                    {
+                        Some(x) => x,
+                        None => continue,
+                    };
 
+                // Socket is connected to the client we are serving, unless
+                // we reply to the packets of other clients.
                 if recved_peer != peer {
+                    if reply_unknown_tid {
+                        send_unknown_tid(
+                            socket.udp(),
+                            &buf[..len],
+                            recved_peer,
+                        )
+                        .await;
+                    }
                     continue;
                 }
 
-                if let Ok(Packet::Data(recved_block_id, _)) =
-                    Packet::decode(&buf[..len])
-                {
-                    if recved_block_id == block_id {
+                match Packet::decode(&buf[..len]) {
+                    Ok(Packet::Data(recved_block_id, _))
+                        if recved_block_id == block_id =>
+                    {
+                        if len > max_len {
+                            return Ok(RecvBlock::Oversized);
+                        }
+
                         buf.truncate(len);
                         buf.advance(PACKET_DATA_HEADER_LEN);
                         break;
                     }
+                    Ok(Packet::Data(..)) if ack_gap => {
+                        return Ok(RecvBlock::OutOfOrder)
+                    }
+                    Ok(Packet::Error(e)) => return Ok(RecvBlock::Aborted(e)),
+                    _ => {}
                 }
             }
 
-            Ok(buf.freeze())
+            Ok(RecvBlock::Data(buf.freeze()))
         })
         .await
     }
 }
 
-fn build_oack_opts(config: &ServerConfig, req: &RwReq) -> Option<Opts> {
+/// Datagram that `recv_data_block` received.
+enum RecvBlock {
+    /// Payload of the expected block.
+    Data(Bytes),
+    /// Another block, while a gap in the window is not acknowledged yet.
+    OutOfOrder,
+    /// Expected block with more data than the block size.
+    Oversized,
+    /// ERROR packet of the client.
+    Aborted(packet::Error),
+}
+
+/// Translate netascii (RFC 1350) back to raw bytes: `\r\n` is written as
+/// `\n` and `\r\0` as `\r`.
+///
+/// `\r` can be the last byte of a block, so `cr` keeps this state between
+/// the calls.
+fn decode_netascii(data: &[u8], cr: &mut bool, out: &mut Vec<u8>) {
+    out.clear();
+
+    for &byte in data {
+        if *cr {
+            *cr = false;
+
+            match byte {
+                b'\n' => out.push(b'\n'),
+                b'\0' => out.push(b'\r'),
+                // Invalid sequence, keep it as it is.
+                b'\r' => {
+                    out.push(b'\r');
+                    *cr = true;
+                }
+                _ => {
+                    out.push(b'\r');
+                    out.push(byte);
+                }
+            }
+        } else if byte == b'\r' {
+            *cr = true;
+        } else {
+            out.push(byte);
+        }
+    }
+}
+
+fn build_oack_opts(
+    config: &ServerConfig,
+    peer: &SocketAddr,
+    req: &RwReq,
+) -> Option<Opts> {
+    if config.no_oack {
+        return None;
+    }
+
     let mut opts = Opts::default();
 
     if !config.ignore_client_block_size {
-        opts.block_size = match (req.opts.block_size, config.block_size_limit) {
-            (Some(bsize), Some(limit)) => Some(cmp::min(bsize, limit)),
-            (Some(bsize), None) => Some(bsize),
-            _ => None,
-        };
+        opts.block_size =
+            match (req.opts.block_size, config.block_size_limit_for(peer)) {
+                (Some(bsize), Some(limit)) => Some(cmp::min(bsize, limit)),
+                (Some(bsize), None) => Some(bsize),
+                _ => None,
+            };
     }
 
     if !config.ignore_client_timeout {
         opts.timeout = req.opts.timeout;
     }
 
+    if !config.ignore_client_window_size {
+        opts.window_size =
+            match (req.opts.window_size, config.window_size_limit) {
+                (Some(wsize), Some(limit)) => Some(cmp::min(wsize, limit)),
+                (Some(wsize), None) => Some(wsize),
+                _ => None,
+            };
+    }
+
+    opts.rollover = req.opts.rollover;
     opts.transfer_size = req.opts.transfer_size;
 
     if opts == Opts::default() {
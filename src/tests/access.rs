@@ -0,0 +1,62 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn builder_allowing(
+    handler: BytesHandler,
+    allowed: &RawClient,
+) -> TftpServerBuilder<BytesHandler> {
+    let allowed = allowed.socket.local_addr().unwrap();
+    TftpServerBuilder::with_handler(handler)
+        .allow_client(move |peer| *peer == allowed)
+}
+
+#[test]
+fn allowed_client() {
+    let client = RawClient::new();
+    let builder = builder_allowing(BytesHandler::new("hello"), &client);
+    let addr = spawn_server(builder);
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks.concat(), b"hello");
+}
+
+#[test]
+fn denied_client() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+    let builder = builder_allowing(handler, &RawClient::new());
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, peer) = client.recv();
+    assert_eq!(peer, addr);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::PermissionDenied))
+    ));
+    assert_eq!(opens.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn denied_client_dropped() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+    let builder =
+        builder_allowing(handler, &RawClient::new()).drop_denied_clients();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.set_timeout(Duration::from_millis(200));
+    client.send_rrq("test", Opts::default(), addr);
+
+    assert!(client.try_recv().is_none());
+    assert_eq!(opens.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::packet::{Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn padded_ack(block_id: u16, len: usize) -> Vec<u8> {
+    let mut data = Packet::Ack(block_id).to_bytes().to_vec();
+    data.resize(len, 0);
+    data
+}
+
+#[test]
+fn padded_ack_tolerant() {
+    let content = vec![0xaa; 3 * 2048 + 100];
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content))
+        .timeout(Duration::from_millis(300))
+        .tolerant_ack();
+
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(2048),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, peer) = client.recv();
+    assert_eq!(expect_oack(&data).block_size, Some(2048));
+    client.send_raw(&padded_ack(0, 2048 + 4), peer);
+
+    // Collect ids of the DATA packets we receive after each padded ACK.
+    let mut block_ids = Vec::new();
+    for _ in 0..3 {
+        let (data, _) = client.recv();
+        let block_id = match Packet::decode(&data) {
+            Ok(Packet::Data(block_id, _)) => block_id,
+            p => panic!("expected DATA, got: {:?}", p),
+        };
+        block_ids.push(block_id);
+        client.send_raw(&padded_ack(block_id, 2048 + 4), peer);
+    }
+
+    assert_eq!(block_ids, [1, 2, 3]);
+}
+
+#[test]
+fn padded_ack_strict() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(300));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(2048),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send_raw(&padded_ack(0, 2048 + 4), peer);
+
+    // Padded ACK is ignored, so the server retransmits OACK.
+    let (data, _) = client.recv();
+    expect_oack(&data);
+}
@@ -0,0 +1,407 @@
+use futures_lite::io::Cursor;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::{MemHandler, MemWriter};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+fn blksize_opts(block_size: u16) -> Opts {
+    Opts {
+        block_size: Some(block_size),
+        ..Opts::default()
+    }
+}
+
+#[test]
+fn rrq_large_block_size() {
+    let content: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(4096)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(4096));
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(blocks[0].len(), 4096);
+    assert_eq!(blocks.concat(), content);
+}
+
+#[test]
+fn wrq_large_block_size() {
+    let content: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let blocks: Vec<&[u8]> = content.chunks(4096).collect();
+    let oack = wrq_transfer(
+        &client,
+        rw_req("test", blksize_opts(4096)),
+        addr,
+        &blocks,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(4096));
+
+    wait_for_len(&data, content.len());
+    assert_eq!(*data.lock().unwrap(), content);
+}
+
+fn negotiated_block_size(builder: TftpServerBuilder<BytesHandler>) -> u16 {
+    requested_block_size(builder, 4096)
+}
+
+fn requested_block_size(
+    builder: TftpServerBuilder<BytesHandler>,
+    block_size: u16,
+) -> u16 {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", blksize_opts(block_size), addr);
+
+    let (data, _) = client.recv();
+    expect_oack(&data).block_size.unwrap()
+}
+
+#[test]
+fn max_block_size_buffer() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .max_block_size_buffer(1028);
+    assert_eq!(negotiated_block_size(builder), 1024);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .max_block_size_buffer(1028)
+        .block_size_limit(512);
+    assert_eq!(negotiated_block_size(builder), 512);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .max_block_size_buffer(8192);
+    assert_eq!(negotiated_block_size(builder), 4096);
+}
+
+#[test]
+fn block_size_min_default() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    assert_eq!(requested_block_size(builder, 8), 8);
+}
+
+fn expect_option_negotiation_failed(data: &[u8]) {
+    assert!(matches!(
+        Packet::decode(data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+}
+
+#[test]
+fn block_size_min() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(512);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    client.send_rrq("test", blksize_opts(8), addr);
+    expect_option_negotiation_failed(&client.recv().0);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(512);
+    assert_eq!(requested_block_size(builder, 512), 512);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(512);
+    assert_eq!(requested_block_size(builder, 1024), 1024);
+
+    // Values below the RFC minimum are ignored
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(1);
+    assert_eq!(requested_block_size(builder, 8), 8);
+
+    // Limits below the minimum are not raised to it
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(512)
+        .block_size_limit(256);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    client.send_rrq("test", blksize_opts(1024), addr);
+    expect_option_negotiation_failed(&client.recv().0);
+}
+
+#[test]
+fn block_size_limit_out_of_range() {
+    // Limits below the RFC minimum are raised to it
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_limit(4);
+    assert_eq!(negotiated_block_size(builder), 8);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_limit(u16::MAX);
+    assert_eq!(requested_block_size(builder, 65464), 65464);
+}
+
+#[test]
+fn wrq_block_size_min() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler).block_size_min(16);
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("test", blksize_opts(8))), addr);
+    expect_option_negotiation_failed(&client.recv().0);
+
+    let client = RawClient::new();
+    let oack = wrq_transfer(
+        &client,
+        rw_req("test", blksize_opts(16)),
+        addr,
+        &[b"0123456789abcdef", b"end"],
+    );
+    assert_eq!(oack.unwrap().block_size, Some(16));
+
+    wait_for_len(&data, 19);
+    assert_eq!(&data.lock().unwrap()[..], b"0123456789abcdefend");
+}
+
+#[test]
+fn rrq_path_mtu() {
+    let content: Vec<u8> = (0..3000).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .path_mtu(1500);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // DATA packets of 1472 bytes fill the UDP payload of a 1500 MTU
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(8192)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(1468));
+    assert_eq!(blocks[0].len(), 1468);
+    assert_eq!(blocks.concat(), content);
+}
+
+#[test]
+fn rrq_path_mtu_ipv6() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .path_mtu(1500);
+    let addr = spawn_server_at(builder, "[::1]:0".parse().unwrap());
+    let client = RawClient::bind("[::1]:0");
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(8192)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(1448));
+}
+
+#[test]
+fn wrq_path_mtu() {
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new("")).path_mtu(1500);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let oack = wrq_transfer(
+        &client,
+        rw_req("test", blksize_opts(8192)),
+        addr,
+        &[b"x"],
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1468));
+}
+
+#[test]
+fn path_mtu_with_block_size_limit() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .path_mtu(1500)
+        .block_size_limit(1024);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(8192)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(1024));
+
+    // Requests below the limits are not changed
+    let client = RawClient::new();
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(512)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(512));
+}
+
+/// Handler that limits the block size of `*.cfg` files to 512, of `*.bin`
+/// files to 1428 and of `*.tiny` files to 4, which is out of range.
+struct CappedHandler(MemHandler);
+
+impl Handler for CappedHandler {
+    type Reader = Cursor<bytes::Bytes>;
+    type Writer = MemWriter;
+
+    fn max_block_size(&self, path: &Path) -> Option<u16> {
+        match path.extension()?.to_str()? {
+            "cfg" => Some(512),
+            "bin" => Some(1428),
+            "tiny" => Some(4),
+            _ => None,
+        }
+    }
+
+    async fn read_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        self.0.read_req_open(client, path, req).await
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        self.0.write_req_open(client, path, req, size).await
+    }
+}
+
+#[test]
+fn handler_max_block_size() {
+    let files = MemHandler::new().allow_writes();
+    files.insert("boot.cfg", "cfg");
+    files.insert("image.bin", vec![0x55; 2000]);
+    files.insert("boot.img", vec![0x55; 2000]);
+    files.insert("boot.tiny", "tiny");
+
+    let handler = CappedHandler(files.clone());
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    // Every transfer uses a new client, so it is not mistaken for a
+    // resent request of the previous one.
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("boot.cfg", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(512));
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("image.bin", blksize_opts(8192)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1428));
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("boot.img", blksize_opts(8192)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(8192));
+
+    let oack = wrq_transfer(
+        &RawClient::new(),
+        rw_req("new.cfg", blksize_opts(1428)),
+        addr,
+        &[b"cfg"],
+    );
+    assert_eq!(oack.unwrap().block_size, Some(512));
+
+    // Limits of the handler are kept in the range of RFC 2348
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("boot.tiny", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(8));
+
+    // and replace the global one, in both directions.
+    let handler = CappedHandler(files.clone());
+    let builder =
+        TftpServerBuilder::with_handler(handler).block_size_limit(1024);
+    let addr = spawn_server(builder);
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("image.bin", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1428));
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("boot.cfg", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(512));
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("boot.img", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1024));
+
+    let handler = CappedHandler(files.clone());
+    let builder =
+        TftpServerBuilder::with_handler(handler).block_size_limit(512);
+    let addr = spawn_server(builder);
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("image.bin", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1428));
+
+    // Block buffer and path MTU are still hard bounds.
+
+    let handler = CappedHandler(files.clone());
+    let builder =
+        TftpServerBuilder::with_handler(handler).max_block_size_buffer(1028);
+    let addr = spawn_server(builder);
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("image.bin", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(1024));
+
+    let handler = CappedHandler(files);
+    let builder = TftpServerBuilder::with_handler(handler).path_mtu(1000);
+    let addr = spawn_server(builder);
+
+    let (oack, _) = rrq_transfer(
+        &RawClient::new(),
+        rw_req("image.bin", blksize_opts(1428)),
+        addr,
+    );
+    assert_eq!(oack.unwrap().block_size, Some(968));
+}
+
+#[test]
+fn auto_mtu_clamp_loopback() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .auto_mtu_clamp();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // MTU of loopback is 65536, so the largest block size fits
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(65464)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(65464));
+
+    // Lower static MTU is preferred
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .auto_mtu_clamp()
+        .path_mtu(1500);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", blksize_opts(8192)), addr);
+    assert_eq!(oack.unwrap().block_size, Some(1468));
+}
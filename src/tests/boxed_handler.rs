@@ -0,0 +1,61 @@
+use std::net::SocketAddr;
+
+use crate::packet::{Opts, Packet};
+use crate::server::handlers::{BoxedHandler, MemHandler};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+fn rrq_first_block(addr: SocketAddr, filename: &str) -> Vec<u8> {
+    let client = RawClient::new();
+    client.send_rrq(filename, Opts::default(), addr);
+
+    let (data, peer) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Data(1, block)) => {
+            let block = block.to_vec();
+            client.send(&Packet::Ack(1), peer);
+            block
+        }
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+#[test]
+fn handlers_of_different_types() {
+    let mem = MemHandler::new();
+    mem.insert("file", "mem");
+
+    let mut handlers: Vec<BoxedHandler> = vec![
+        mem.boxed(),
+        BytesHandler::new("bytes").boxed(),
+        BoxedHandler::new(BytesHandler::new("new")),
+    ];
+
+    // Boxing a boxed handler keeps it as it is
+    let last = handlers.pop().unwrap();
+    handlers.push(last.boxed());
+
+    let addrs: Vec<_> = handlers
+        .into_iter()
+        .map(|handler| spawn_server(TftpServerBuilder::with_handler(handler)))
+        .collect();
+
+    assert_eq!(rrq_first_block(addrs[0], "file"), b"mem");
+    assert_eq!(rrq_first_block(addrs[1], "file"), b"bytes");
+    assert_eq!(rrq_first_block(addrs[2], "file"), b"new");
+}
+
+#[test]
+fn boxed_transfer_callbacks() {
+    let bytes = BytesHandler::new("");
+    let data = bytes.data();
+    let results = bytes.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(bytes.boxed()));
+    let client = RawClient::new();
+
+    wrq_transfer(&client, rw_req("up", Opts::default()), addr, &[b"up"]);
+
+    assert!(wait_for_result(&results).is_ok());
+    assert_eq!(*data.lock().unwrap(), b"up");
+}
@@ -0,0 +1,231 @@
+use futures_lite::io::{sink, Empty, Sink};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::tempdir;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::{
+    ChainHandler, DirHandler, DirHandlerMode, MemHandler,
+};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+fn rrq(addr: SocketAddr, filename: &str) -> Result<Vec<u8>, packet::Error> {
+    let client = RawClient::new();
+    client.send_rrq(filename, Opts::default(), addr);
+
+    let (data, peer) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Data(1, block)) => {
+            let block = block.to_vec();
+            client.send(&Packet::Ack(1), peer);
+            Ok(block)
+        }
+        Ok(Packet::Error(e)) => Err(e),
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+/// Handler that accepts only write requests and counts their syncs.
+struct SyncHandler(Arc<AtomicUsize>);
+
+impl Handler for SyncHandler {
+    type Reader = Empty;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Err(packet::Error::FileNotFound)
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn dir_falls_back_to_mem() {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("both.txt"), "dir").unwrap();
+    fs::write(dir.path().join("dir.txt"), "dir").unwrap();
+
+    let mem = MemHandler::new();
+    mem.insert("both.txt", "mem");
+    mem.insert("mem.txt", "mem");
+
+    let handler = ChainHandler::new()
+        .with_handler(
+            DirHandler::new(dir.path(), DirHandlerMode::ReadOnly).unwrap(),
+        )
+        .with_handler(mem);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    assert_eq!(rrq(addr, "dir.txt").unwrap(), b"dir");
+    assert_eq!(rrq(addr, "both.txt").unwrap(), b"dir");
+    assert_eq!(rrq(addr, "mem.txt").unwrap(), b"mem");
+    assert!(matches!(rrq(addr, "none.txt"), Err(packet::Error::FileNotFound)));
+}
+
+#[test]
+fn other_errors_are_not_chained() {
+    let dir = tempdir().unwrap();
+    let mem = MemHandler::new();
+    mem.insert("mem.txt", "mem");
+
+    let handler = ChainHandler::new()
+        .with_handler(
+            DirHandler::new(dir.path(), DirHandlerMode::ReadOnly)
+                .unwrap()
+                .with_extensions(&["cfg"]),
+        )
+        .with_handler(mem);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    assert!(matches!(
+        rrq(addr, "mem.txt"),
+        Err(packet::Error::PermissionDenied)
+    ));
+}
+
+#[test]
+fn empty_chain() {
+    let addr =
+        spawn_server(TftpServerBuilder::with_handler(ChainHandler::new()));
+    assert!(matches!(rrq(addr, "any"), Err(packet::Error::FileNotFound)));
+}
+
+#[test]
+fn transfer_done_goes_to_serving_handler() {
+    let mem = MemHandler::new();
+    mem.insert("mem.txt", "mem");
+    let bytes = BytesHandler::new("bytes");
+    let results = bytes.results();
+
+    let handler = ChainHandler::new().with_handler(mem).with_handler(bytes);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    assert_eq!(rrq(addr, "mem.txt").unwrap(), b"mem");
+    thread::sleep(Duration::from_millis(100));
+    assert!(results.lock().unwrap().is_empty());
+
+    assert_eq!(rrq(addr, "other.txt").unwrap(), b"bytes");
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.bytes, 5);
+}
+
+#[test]
+fn wrq_goes_to_serving_handler() {
+    let mem = MemHandler::new().allow_writes();
+    let handler = ChainHandler::new().with_handler(mem.clone());
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    wrq_transfer(&client, rw_req("up.txt", Opts::default()), addr, &[b"up"]);
+
+    for _ in 0..100 {
+        if mem.get("up.txt").is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(mem.get("up.txt").unwrap(), "up");
+}
+
+#[test]
+fn wrq_sync_goes_to_serving_handler() {
+    let syncs = Arc::new(AtomicUsize::new(0));
+    let handler = ChainHandler::new()
+        .with_handler(MemHandler::new())
+        .with_handler(SyncHandler(Arc::clone(&syncs)));
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    // Read-only handlers reject writes with IllegalOperation, which stops
+    // the chain.
+    client.send(&Packet::Wrq(rw_req("up.txt", Opts::default())), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+
+    let handler = ChainHandler::new()
+        .with_handler(SyncHandler(Arc::clone(&syncs)))
+        .with_handler(MemHandler::new());
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    wrq_transfer(&client, rw_req("up.txt", Opts::default()), addr, &[b"up"]);
+
+    for _ in 0..100 {
+        if syncs.load(Ordering::SeqCst) > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(syncs.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn transfer_done_after_failed_init() {
+    let mem = MemHandler::new().allow_writes();
+    let bytes = BytesHandler::new("bytes");
+    let results = bytes.results();
+
+    let handler =
+        ChainHandler::new().with_handler(mem.clone()).with_handler(bytes);
+    let builder = TftpServerBuilder::with_handler(handler)
+        .ignore_client_timeout()
+        .strict_options();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // Upload is opened by the first handler, but its options are rejected
+    let opts = Opts {
+        timeout: Some(5),
+        ..Opts::default()
+    };
+    client.send(&Packet::Wrq(rw_req("up.txt", opts)), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+
+    // Next transfer of the same client goes to the handler that serves it
+    client.send_rrq("other.txt", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, b"bytes"))));
+    client.send(&Packet::Ack(1), peer);
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.bytes, 5);
+    assert!(mem.get("up.txt").is_none());
+}
@@ -0,0 +1,261 @@
+use futures_lite::future::block_on;
+use futures_lite::AsyncReadExt;
+use std::net::UdpSocket;
+use std::time::Duration;
+use tempfile::tempdir;
+
+use crate::client::TftpClient;
+use crate::error::Error;
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn get(client: &TftpClient, content: &[u8], opts: Opts) -> Vec<u8> {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content));
+    let addr = spawn_server(builder);
+
+    block_on(async {
+        let mut reader = client.get(addr, "test", opts).await.unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    })
+}
+
+#[test]
+fn client_get() {
+    let client = TftpClient::new();
+
+    assert_eq!(get(&client, b"", Opts::default()), b"");
+    assert_eq!(get(&client, b"hello", Opts::default()), b"hello");
+
+    // Multiple of default block size
+    let content: Vec<u8> = (0..1024).map(|x| x as u8).collect();
+    assert_eq!(get(&client, &content, Opts::default()), content);
+}
+
+#[test]
+fn client_get_opts() {
+    let client = TftpClient::new();
+    let content: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        window_size: Some(4),
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    assert_eq!(get(&client, &content, opts), content);
+
+    let opts = Opts {
+        block_size: Some(8),
+        ..Opts::default()
+    };
+    assert_eq!(get(&client, &content[..64], opts), &content[..64]);
+}
+
+#[test]
+fn client_get_file_not_found() {
+    let dir = tempdir().unwrap();
+    let builder = TftpServerBuilder::with_dir_ro(dir.path()).unwrap();
+    let addr = spawn_server(builder);
+
+    let client = TftpClient::new();
+    let res = block_on(client.get(addr, "missing", Opts::default()));
+
+    assert!(matches!(res, Err(Error::Packet(packet::Error::FileNotFound))));
+}
+
+#[test]
+fn client_get_retransmit() {
+    let server = RawClient::new();
+    let addr = server.socket.local_addr().unwrap();
+
+    let handle = std::thread::spawn(move || {
+        let client = TftpClient::new().timeout(Duration::from_millis(100));
+
+        block_on(async {
+            let mut reader = client.get(addr, "test", Opts::default()).await?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await?;
+            Ok::<_, Error>(data)
+        })
+    });
+
+    // Drop the first request, reply to the retransmitted one.
+    let (req, peer) = server.recv();
+    let (retransmitted, _) = server.recv();
+    assert_eq!(req, retransmitted);
+
+    // Reply from a new port, as servers do.
+    let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    transfer.send_to(&Packet::Data(1, b"hello").to_bytes(), peer).unwrap();
+
+    let mut buf = [0u8; 16];
+    let len = transfer.recv(&mut buf).unwrap();
+    assert!(matches!(Packet::decode(&buf[..len]), Ok(Packet::Ack(1))));
+
+    assert_eq!(handle.join().unwrap().unwrap(), b"hello");
+}
+
+#[test]
+fn client_get_lost_ack() {
+    let server = RawClient::new();
+    let addr = server.socket.local_addr().unwrap();
+
+    // Much longer than the retransmission timeout of the server.
+    let handle = std::thread::spawn(move || {
+        let client = TftpClient::new().timeout(Duration::from_secs(10));
+
+        block_on(async {
+            let mut reader = client.get(addr, "test", Opts::default()).await?;
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).await?;
+            Ok::<_, Error>(data)
+        })
+    });
+
+    let (_, peer) = server.recv();
+    let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    transfer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0u8; 16];
+
+    let block = [0x55; 512];
+    transfer.send_to(&Packet::Data(1, &block).to_bytes(), peer).unwrap();
+    let len = transfer.recv(&mut buf).unwrap();
+    assert!(matches!(Packet::decode(&buf[..len]), Ok(Packet::Ack(1))));
+
+    // Act as if the ACK was lost and retransmit the block, the client
+    // must acknowledge it again without waiting for its own timeout.
+    for _ in 0..3 {
+        transfer.send_to(&Packet::Data(1, &block).to_bytes(), peer).unwrap();
+        let len = transfer.recv(&mut buf).unwrap();
+        assert!(matches!(Packet::decode(&buf[..len]), Ok(Packet::Ack(1))));
+    }
+
+    transfer.send_to(&Packet::Data(2, b"end").to_bytes(), peer).unwrap();
+    let len = transfer.recv(&mut buf).unwrap();
+    assert!(matches!(Packet::decode(&buf[..len]), Ok(Packet::Ack(2))));
+
+    let data = handle.join().unwrap().unwrap();
+    assert_eq!(&data[..512], &block[..]);
+    assert_eq!(&data[512..], b"end");
+}
+
+#[test]
+fn client_get_max_send_retries() {
+    let server = RawClient::new();
+    let addr = server.socket.local_addr().unwrap();
+
+    let client = TftpClient::new()
+        .timeout(Duration::from_millis(10))
+        .max_send_retries(2);
+    let res = block_on(client.get(addr, "test", Opts::default()));
+    assert!(matches!(res, Err(Error::MaxSendRetriesReached(_, 1))));
+
+    // Request and two retransmissions
+    for _ in 0..3 {
+        server.recv();
+    }
+    server.set_timeout(Duration::from_millis(100));
+    assert!(server.try_recv().is_none());
+}
+
+fn put(client: &TftpClient, content: &[u8], opts: Opts) -> Vec<u8> {
+    let handler = BytesHandler::new("old content");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    block_on(client.put(addr, "test", content, opts)).unwrap();
+
+    wait_for_len(&data, content.len());
+    let data = data.lock().unwrap().clone();
+    data
+}
+
+#[test]
+fn client_put() {
+    let client = TftpClient::new();
+
+    assert_eq!(put(&client, b"", Opts::default()), b"");
+    assert_eq!(put(&client, b"hello", Opts::default()), b"hello");
+
+    // Multiple of default block size
+    let content: Vec<u8> = (0..1024).map(|x| x as u8).collect();
+    assert_eq!(put(&client, &content, Opts::default()), content);
+}
+
+#[test]
+fn client_put_opts() {
+    let client = TftpClient::new();
+    let content: Vec<u8> = (0..10000).map(|x| x as u8).collect();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        transfer_size: Some(content.len() as u64),
+        ..Opts::default()
+    };
+    assert_eq!(put(&client, &content, opts), content);
+
+    let opts = Opts {
+        block_size: Some(8),
+        ..Opts::default()
+    };
+    assert_eq!(put(&client, &content[..64], opts), &content[..64]);
+}
+
+#[test]
+fn client_put_access_violation() {
+    let dir = tempdir().unwrap();
+    let builder = TftpServerBuilder::with_dir_ro(dir.path()).unwrap();
+    let addr = spawn_server(builder);
+
+    let client = TftpClient::new();
+    let res =
+        block_on(client.put(addr, "test", &b"hello"[..], Opts::default()));
+
+    assert!(matches!(res, Err(Error::Packet(_))));
+}
+
+#[test]
+fn client_put_duplicate_ack() {
+    let server = RawClient::new();
+    let addr = server.socket.local_addr().unwrap();
+    let content: Vec<u8> = (0..600).map(|x| x as u8).collect();
+    let expected = content.clone();
+
+    let handle = std::thread::spawn(move || {
+        let client = TftpClient::new();
+        block_on(client.put(addr, "test", &content[..], Opts::default()))
+    });
+
+    let (req, peer) = server.recv();
+    assert!(matches!(Packet::decode(&req), Ok(Packet::Wrq(_))));
+
+    let transfer = RawClient::new();
+    transfer.send(&Packet::Ack(0), peer);
+
+    let (data, _) = transfer.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == &expected[..512]
+    ));
+
+    // Duplicate ACK must not trigger a retransmission.
+    transfer.send(&Packet::Ack(0), peer);
+    transfer.set_timeout(Duration::from_millis(200));
+    assert!(transfer.try_recv().is_none());
+    transfer.set_timeout(Duration::from_secs(5));
+
+    transfer.send(&Packet::Ack(1), peer);
+
+    let (data, _) = transfer.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(2, block)) if block == &expected[512..]
+    ));
+
+    transfer.send(&Packet::Ack(2), peer);
+    handle.join().unwrap().unwrap();
+}
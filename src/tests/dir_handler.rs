@@ -0,0 +1,542 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tempfile::{tempdir, TempDir};
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::{DirHandler, DirHandlerMode};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn test_dir() -> TempDir {
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("notes.txt"), "notes").unwrap();
+    fs::write(dir.path().join("boot.cfg"), "cfg").unwrap();
+    fs::write(dir.path().join("disk.IMG"), "img").unwrap();
+    fs::create_dir(dir.path().join("real")).unwrap();
+    fs::write(dir.path().join("real/pxelinux.bin"), "pxe").unwrap();
+    dir
+}
+
+fn rrq(handler: DirHandler, filename: &str) -> Result<Vec<u8>, packet::Error> {
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send_rrq(filename, Opts::default(), addr);
+
+    let (data, peer) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Data(1, block)) => {
+            let block = block.to_vec();
+            client.send(&Packet::Ack(1), peer);
+            Ok(block)
+        }
+        Ok(Packet::Error(e)) => Err(e),
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+fn handler(dir: &TempDir) -> DirHandler {
+    DirHandler::new(dir.path(), DirHandlerMode::ReadOnly).unwrap()
+}
+
+#[test]
+fn extensions_allowlist() {
+    let dir = test_dir();
+    let exts = &["0", "cfg", "img"];
+
+    assert!(matches!(
+        rrq(handler(&dir).with_extensions(exts), "notes.txt"),
+        Err(packet::Error::PermissionDenied)
+    ));
+    assert_eq!(
+        rrq(handler(&dir).with_extensions(exts), "boot.cfg").unwrap(),
+        b"cfg"
+    );
+    assert_eq!(
+        rrq(handler(&dir).with_extensions(&["CFG"]), "boot.cfg").unwrap(),
+        b"cfg"
+    );
+    // Case-insensitive
+    assert_eq!(
+        rrq(handler(&dir).with_extensions(exts), "disk.IMG").unwrap(),
+        b"img"
+    );
+    assert!(matches!(
+        rrq(handler(&dir).with_extensions(exts), "real"),
+        Err(packet::Error::PermissionDenied)
+    ));
+
+    // Without allowlist everything is served
+    assert_eq!(rrq(handler(&dir), "notes.txt").unwrap(), b"notes");
+}
+
+#[test]
+fn remap() {
+    let dir = test_dir();
+    let real = dir.path().join("real/pxelinux.bin");
+
+    let remap = move |path: &Path| -> Option<PathBuf> {
+        (path == Path::new("pxelinux.0")).then(|| real.clone())
+    };
+
+    let with_remap = || handler(&dir).with_remap(remap.clone());
+
+    assert_eq!(rrq(with_remap(), "pxelinux.0").unwrap(), b"pxe");
+    assert_eq!(rrq(with_remap(), "/pxelinux.0").unwrap(), b"pxe");
+    assert_eq!(rrq(with_remap(), "boot.cfg").unwrap(), b"cfg");
+    assert!(matches!(
+        rrq(with_remap(), "missing"),
+        Err(packet::Error::FileNotFound)
+    ));
+}
+
+#[test]
+fn tsize_matches_content() {
+    let dir = test_dir();
+    let content: Vec<u8> = (0..1500).map(|x| x as u8).collect();
+    fs::write(dir.path().join("large.bin"), &content).unwrap();
+
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler(&dir)));
+    let client = RawClient::new();
+
+    let opts = Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("large.bin", opts), addr);
+
+    let data = blocks.concat();
+    assert_eq!(oack.unwrap().transfer_size, Some(data.len() as u64));
+    assert_eq!(data, content);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let outside = tempdir().unwrap();
+    fs::write(outside.path().join("secret"), "secret").unwrap();
+
+    let dir = test_dir();
+    symlink(outside.path().join("secret"), dir.path().join("escape")).unwrap();
+    symlink(dir.path().join("boot.cfg"), dir.path().join("inside")).unwrap();
+
+    assert!(matches!(
+        rrq(handler(&dir), "escape"),
+        Err(packet::Error::PermissionDenied)
+    ));
+    assert_eq!(rrq(handler(&dir), "inside").unwrap(), b"cfg");
+    assert_eq!(
+        rrq(handler(&dir).follow_symlinks(true), "escape").unwrap(),
+        b"secret"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn wrq_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let outside = tempdir().unwrap();
+    let dir = test_dir();
+    symlink(outside.path(), dir.path().join("outside")).unwrap();
+    symlink(outside.path().join("new"), dir.path().join("dangling")).unwrap();
+
+    let handler =
+        DirHandler::new(dir.path(), DirHandlerMode::WriteOnly).unwrap();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    for filename in ["outside/file", "dangling"] {
+        let client = RawClient::new();
+        client.send(&Packet::Wrq(rw_req(filename, Opts::default())), addr);
+
+        let (data, _) = client.recv();
+        assert!(matches!(
+            Packet::decode(&data),
+            Ok(Packet::Error(packet::Error::PermissionDenied))
+        ));
+    }
+
+    assert_eq!(fs::read_dir(outside.path()).unwrap().count(), 0);
+
+    // New files in the served directory are fine
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("new", Opts::default()), addr, &[b"new"]);
+}
+
+fn wrq_error(addr: SocketAddr, filename: &str, opts: Opts) -> packet::Error {
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req(filename, opts)), addr);
+
+    let (data, _) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Error(e)) => e,
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+fn tsize(size: u64) -> Opts {
+    Opts {
+        transfer_size: Some(size),
+        ..Opts::default()
+    }
+}
+
+#[test]
+fn max_upload_size() {
+    let dir = test_dir();
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::WriteOnly)
+        .unwrap()
+        .max_upload_size(4);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    let e = wrq_error(addr, "large", tsize(5));
+    assert!(matches!(e, packet::Error::DiskFull));
+    assert!(!dir.path().join("large").exists());
+
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("small", tsize(4)), addr, &[b"four"]);
+
+    // Uploads without tsize are not limited
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("any", Opts::default()), addr, &[b"large"]);
+}
+
+#[test]
+fn require_tsize_on_write() {
+    let dir = test_dir();
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::WriteOnly)
+        .unwrap()
+        .require_tsize_on_write();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    let e = wrq_error(addr, "new", Opts::default());
+    assert!(matches!(e, packet::Error::Msg(msg) if msg.contains("size")));
+    assert!(!dir.path().join("new").exists());
+
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("new", tsize(3)), addr, &[b"new"]);
+}
+
+#[test]
+fn client_ip_fallback() {
+    let dir = test_dir();
+    let cfg = dir.path().join("pxelinux.cfg");
+    fs::create_dir(&cfg).unwrap();
+    fs::write(cfg.join("default"), "default").unwrap();
+    fs::write(cfg.join("7F"), "7F").unwrap();
+    fs::write(cfg.join("7F000001"), "7F000001").unwrap();
+
+    let fallback = || handler(&dir).with_client_ip_fallback();
+
+    // Most specific name of 127.0.0.1 is served first
+    assert_eq!(rrq(fallback(), "pxelinux.cfg/default").unwrap(), b"7F000001");
+
+    // Other files are served as requested
+    fs::write(cfg.join("01-aa-bb-cc-dd-ee-ff"), "mac").unwrap();
+    assert_eq!(
+        rrq(fallback(), "pxelinux.cfg/01-aa-bb-cc-dd-ee-ff").unwrap(),
+        b"mac"
+    );
+    assert_eq!(rrq(fallback(), "pxelinux.cfg/7F").unwrap(), b"7F");
+    assert!(matches!(
+        rrq(fallback(), "pxelinux.cfg/other"),
+        Err(packet::Error::FileNotFound)
+    ));
+
+    fs::remove_file(cfg.join("7F000001")).unwrap();
+    assert_eq!(rrq(fallback(), "pxelinux.cfg/default").unwrap(), b"7F");
+
+    fs::remove_file(cfg.join("7F")).unwrap();
+    assert_eq!(rrq(fallback(), "pxelinux.cfg/default").unwrap(), b"default");
+    assert!(matches!(
+        rrq(fallback(), "pxelinux.cfg/other"),
+        Err(packet::Error::FileNotFound)
+    ));
+
+    // Only `default` of `pxelinux.cfg` is looked up
+    fs::write(dir.path().join("7F000001"), "top").unwrap();
+    fs::write(dir.path().join("default"), "top default").unwrap();
+    assert_eq!(rrq(fallback(), "default").unwrap(), b"top default");
+
+    // Disabled by default
+    fs::write(cfg.join("7F000001"), "7F000001").unwrap();
+    assert_eq!(rrq(handler(&dir), "pxelinux.cfg/default").unwrap(), b"default");
+}
+
+#[cfg(feature = "gzip")]
+#[test]
+fn decompress_gz() {
+    use async_compression::futures::bufread::GzipEncoder;
+    use futures_lite::future::block_on;
+    use futures_lite::AsyncReadExt;
+
+    let content: Vec<u8> = (0..2000u32).map(|i| (i % 251) as u8).collect();
+    let mut compressed = Vec::new();
+    block_on(GzipEncoder::new(&content[..]).read_to_end(&mut compressed))
+        .unwrap();
+
+    let dir = test_dir();
+    fs::write(dir.path().join("image.bin.gz"), &compressed).unwrap();
+
+    let addr = spawn_server(TftpServerBuilder::with_handler(
+        handler(&dir).decompress_gz(),
+    ));
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("image.bin", opts), addr);
+
+    // Size of the decompressed content is unknown
+    let oack = oack.unwrap();
+    assert_eq!(oack.block_size, Some(1024));
+    assert_eq!(oack.transfer_size, None);
+    assert_eq!(blocks.concat(), content);
+
+    // Existing files are served as they are
+    fs::write(dir.path().join("notes.txt.gz"), &compressed).unwrap();
+    let gz = handler(&dir).decompress_gz();
+    assert_eq!(rrq(gz, "notes.txt").unwrap(), b"notes");
+
+    // Disabled by default
+    assert!(matches!(
+        rrq(handler(&dir), "image.bin"),
+        Err(packet::Error::FileNotFound)
+    ));
+}
+
+#[test]
+fn rrq_errors() {
+    let dir = test_dir();
+
+    assert!(matches!(
+        rrq(handler(&dir), "missing.txt"),
+        Err(packet::Error::FileNotFound)
+    ));
+    assert!(matches!(
+        rrq(handler(&dir), "real"),
+        Err(packet::Error::IllegalOperation)
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn rrq_permission_denied() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = test_dir();
+    let path = dir.path().join("notes.txt");
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+
+    // Privileged users can read the file anyway
+    if fs::File::open(&path).is_ok() {
+        return;
+    }
+
+    assert!(matches!(
+        rrq(handler(&dir), "notes.txt"),
+        Err(packet::Error::PermissionDenied)
+    ));
+}
+
+/// Names of the files in `dir` that start with `.`.
+fn hidden_files(dir: &TempDir) -> Vec<String> {
+    fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with('.'))
+        .collect()
+}
+
+fn wait_for_hidden_files(dir: &TempDir, count: usize) {
+    for _ in 0..100 {
+        if hidden_files(dir).len() == count {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("hidden files: {:?}", hidden_files(dir));
+}
+
+#[test]
+fn atomic_writes() {
+    let dir = test_dir();
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::ReadWrite)
+        .unwrap()
+        .atomic_writes();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let notes = dir.path().join("notes.txt");
+
+    // Client aborts in the middle of the upload
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("notes.txt", Opts::default())), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(0))));
+
+    client.send(&Packet::Data(1, &[b'x'; 512]), peer);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(1))));
+
+    // Upload goes to a temporary file
+    assert_eq!(hidden_files(&dir).len(), 1);
+    assert_eq!(fs::read(&notes).unwrap(), b"notes");
+
+    client.send(&Packet::Error(packet::Error::Msg("bye".to_string())), peer);
+    wait_for_hidden_files(&dir, 0);
+    assert_eq!(fs::read(&notes).unwrap(), b"notes");
+
+    // Completed upload replaces the file
+    let client = RawClient::new();
+    let req = rw_req("notes.txt", Opts::default());
+    wrq_transfer(&client, req, addr, &[b"new notes"]);
+    wait_for_hidden_files(&dir, 0);
+    assert_eq!(fs::read(&notes).unwrap(), b"new notes");
+
+    // New files show up only when they are complete
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("new.txt", Opts::default())), addr);
+    let (_, peer) = client.recv();
+    client.send(&Packet::Data(1, &[b'x'; 512]), peer);
+    client.recv();
+    assert!(!dir.path().join("new.txt").exists());
+
+    client.send(&Packet::Data(2, b"end"), peer);
+    client.recv();
+    wait_for_hidden_files(&dir, 0);
+    assert_eq!(fs::read(dir.path().join("new.txt")).unwrap().len(), 515);
+}
+
+#[test]
+fn atomic_writes_failed_init() {
+    let dir = test_dir();
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::ReadWrite)
+        .unwrap()
+        .atomic_writes();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .ignore_client_timeout()
+        .strict_options();
+    let addr = spawn_server(builder);
+
+    // Upload is opened, but its options are rejected
+    let client = RawClient::new();
+    let opts = Opts {
+        timeout: Some(5),
+        ..Opts::default()
+    };
+    client.send(&Packet::Wrq(rw_req("failed.txt", opts)), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+    wait_for_hidden_files(&dir, 0);
+
+    // Next upload of the same client goes to its own file
+    let req = rw_req("notes.txt", Opts::default());
+    wrq_transfer(&client, req, addr, &[b"new notes"]);
+    wait_for_hidden_files(&dir, 0);
+    assert_eq!(fs::read(dir.path().join("notes.txt")).unwrap(), b"new notes");
+    assert!(!dir.path().join("failed.txt").exists());
+}
+
+#[test]
+fn split_dirs() {
+    let read_dir = test_dir();
+    let write_dir = tempdir().unwrap();
+    fs::write(write_dir.path().join("upload.bin"), "upload").unwrap();
+
+    let split =
+        || DirHandler::split(read_dir.path(), write_dir.path()).unwrap();
+
+    // Reads are served only from the read directory
+    assert_eq!(rrq(split(), "notes.txt").unwrap(), b"notes");
+    assert!(matches!(
+        rrq(split(), "upload.bin"),
+        Err(packet::Error::FileNotFound)
+    ));
+
+    // Writes go only to the write directory
+    let addr = spawn_server(TftpServerBuilder::with_handler(split()));
+    let client = RawClient::new();
+    wrq_transfer(
+        &client,
+        rw_req("notes.txt", Opts::default()),
+        addr,
+        &[b"new"],
+    );
+    let notes = write_dir.path().join("notes.txt");
+    for _ in 0..100 {
+        if fs::read(&notes).is_ok_and(|data| data == b"new") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(fs::read(&notes).unwrap(), b"new");
+    assert_eq!(fs::read(read_dir.path().join("notes.txt")).unwrap(), b"notes");
+
+    // Neither directory can be reached from the other
+    let escape = format!(
+        "../{}/upload.bin",
+        write_dir.path().file_name().unwrap().to_str().unwrap()
+    );
+    assert!(matches!(
+        rrq(split(), &escape),
+        Err(packet::Error::PermissionDenied)
+    ));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::symlink;
+
+        symlink(read_dir.path(), write_dir.path().join("images")).unwrap();
+        assert!(matches!(
+            wrq_error(addr, "images/new", Opts::default()),
+            packet::Error::PermissionDenied
+        ));
+        assert!(!read_dir.path().join("new").exists());
+    }
+}
+
+#[test]
+fn dos_paths() {
+    let dir = test_dir();
+    fs::create_dir_all(dir.path().join("a/b")).unwrap();
+    fs::write(dir.path().join("a/b/c"), "abc").unwrap();
+
+    let dos = || handler(&dir).dos_paths();
+    assert_eq!(rrq(dos(), "a\\b\\c").unwrap(), b"abc");
+    assert_eq!(rrq(dos(), "\\real\\pxelinux.bin").unwrap(), b"pxe");
+    assert!(matches!(
+        rrq(dos(), "a\\..\\notes.txt"),
+        Err(packet::Error::PermissionDenied)
+    ));
+
+    // Without the flag it is a single file name
+    assert!(matches!(
+        rrq(handler(&dir), "a\\b\\c"),
+        Err(packet::Error::FileNotFound)
+    ));
+
+    // Writes are normalized too
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::WriteOnly)
+        .unwrap()
+        .dos_paths();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+    let req = rw_req("a\\b\\upload", Opts::default());
+    wrq_transfer(&client, req, addr, &[b"up"]);
+    assert!(dir.path().join("a/b/upload").exists());
+    assert!(matches!(
+        wrq_error(addr, "a\\..\\..\\escape", Opts::default()),
+        packet::Error::PermissionDenied
+    ));
+}
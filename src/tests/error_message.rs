@@ -0,0 +1,138 @@
+use futures_lite::io::{sink, Cursor, Sink};
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::packet::{self, Mode, Opts, Packet};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Refuses every request with a custom message.
+struct MsgHandler(String);
+
+impl Handler for MsgHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Err(packet::Error::Msg(self.0.clone()))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+}
+
+/// Returns the raw ERROR packet and its message.
+fn rrq_error(builder: TftpServerBuilder<MsgHandler>) -> (Vec<u8>, String) {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, _) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Error(packet::Error::Msg(msg))) => (data, msg),
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+#[test]
+fn long_message_truncated() {
+    let handler = MsgHandler("x".repeat(10 * 1024));
+    let (data, msg) = rrq_error(TftpServerBuilder::with_handler(handler));
+
+    // Opcode, error code, message and its NUL
+    assert_eq!(data.len(), 4 + 255 + 1);
+    assert_eq!(msg, "x".repeat(255));
+}
+
+#[test]
+fn max_error_message_len() {
+    let handler = MsgHandler("Database at 10.0.0.5 is down".to_string());
+    let builder =
+        TftpServerBuilder::with_handler(handler).max_error_message_len(11);
+    let (_, msg) = rrq_error(builder);
+    assert_eq!(msg, "Database at");
+}
+
+#[test]
+fn control_characters_replaced() {
+    let handler = MsgHandler("line 1\nline 2\0hidden".to_string());
+    let (_, msg) = rrq_error(TftpServerBuilder::with_handler(handler));
+    assert_eq!(msg, "line 1 line 2 hidden");
+}
+
+/// Refuses every request with the same error.
+struct ErrorHandler(packet::Error);
+
+impl Handler for ErrorHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Err(self.0.clone())
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Err(self.0.clone())
+    }
+}
+
+fn req_error(addr: SocketAddr, packet: Packet) -> packet::Error {
+    let client = RawClient::new();
+    client.send(&packet, addr);
+
+    let (data, _) = client.recv();
+    match Packet::decode(&data) {
+        Ok(Packet::Error(e)) => e,
+        p => panic!("unexpected packet: {:?}", p),
+    }
+}
+
+#[test]
+fn mask_errors_as() {
+    let rrq = || Packet::Rrq(rw_req("test", Opts::default()));
+    let wrq = || Packet::Wrq(rw_req("test", Opts::default()));
+
+    let handler = ErrorHandler(packet::Error::PermissionDenied);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    assert!(matches!(req_error(addr, rrq()), packet::Error::PermissionDenied));
+
+    let handler = ErrorHandler(packet::Error::PermissionDenied);
+    let builder = TftpServerBuilder::with_handler(handler)
+        .mask_errors_as(packet::Error::FileNotFound);
+    let addr = spawn_server(builder);
+    assert!(matches!(req_error(addr, rrq()), packet::Error::FileNotFound));
+    assert!(matches!(req_error(addr, wrq()), packet::Error::FileNotFound));
+
+    // Errors of the server are not masked
+    let mut req = rw_req("test", Opts::default());
+    req.mode = Mode::Mail;
+    assert!(matches!(
+        req_error(addr, Packet::Rrq(req)),
+        packet::Error::IllegalOperation
+    ));
+}
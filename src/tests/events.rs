@@ -0,0 +1,132 @@
+use async_channel::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{ServerEvent, TftpServerBuilder, TransferError};
+
+use super::utils::*;
+
+fn next_event(rx: &Receiver<ServerEvent>) -> ServerEvent {
+    for _ in 0..500 {
+        if let Ok(event) = rx.try_recv() {
+            return event;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no event received");
+}
+
+#[test]
+fn events_of_transfer() {
+    let (tx, rx) = async_channel::unbounded();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .events_channel(tx);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    let client_addr = client.socket.local_addr().unwrap();
+
+    assert!(matches!(
+        next_event(&rx),
+        ServerEvent::RequestReceived(peer, req)
+            if peer == client_addr && req.filename() == "test"
+    ));
+    assert!(matches!(
+        next_event(&rx),
+        ServerEvent::TransferStarted(peer) if peer == client_addr
+    ));
+    assert!(matches!(
+        next_event(&rx),
+        ServerEvent::TransferCompleted(peer, stats)
+            if peer == client_addr && stats.bytes == 5
+    ));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn events_of_aborted_transfer() {
+    let (tx, rx) = async_channel::unbounded();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .events_channel(tx);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (_, peer) = client.recv();
+    client.send(&Packet::Error(packet::Error::DiskFull), peer);
+
+    assert!(matches!(next_event(&rx), ServerEvent::RequestReceived(..)));
+    assert!(matches!(next_event(&rx), ServerEvent::TransferStarted(_)));
+    assert!(matches!(
+        next_event(&rx),
+        ServerEvent::ClientAborted(_, packet::Error::DiskFull)
+    ));
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn events_of_failed_transfer() {
+    let (tx, rx) = async_channel::unbounded();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(50))
+        .max_send_retries(1)
+        .events_channel(tx);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    assert!(matches!(next_event(&rx), ServerEvent::RequestReceived(..)));
+    assert!(matches!(next_event(&rx), ServerEvent::TransferStarted(_)));
+    assert!(matches!(
+        next_event(&rx),
+        ServerEvent::TransferFailed(_, TransferError::Timeout)
+    ));
+}
+
+#[test]
+fn events_of_failed_init() {
+    let (tx, rx) = async_channel::unbounded();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .block_size_min(1024)
+        .events_channel(tx);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(512),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+    client.recv();
+
+    assert!(matches!(next_event(&rx), ServerEvent::RequestReceived(..)));
+    assert!(matches!(next_event(&rx), ServerEvent::TransferFailed(..)));
+
+    // Failure is reported only once
+    thread::sleep(Duration::from_millis(50));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn events_full_channel() {
+    let (tx, rx) = async_channel::bounded(1);
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .events_channel(tx);
+    let addr = spawn_server(builder);
+
+    // Server is not blocked by a full channel
+    for _ in 0..2 {
+        let client = RawClient::new();
+        let (_, blocks) =
+            rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+        assert_eq!(blocks, vec![b"hello".to_vec()]);
+    }
+
+    assert!(matches!(next_event(&rx), ServerEvent::RequestReceived(..)));
+    assert!(rx.try_recv().is_err());
+}
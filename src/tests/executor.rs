@@ -0,0 +1,212 @@
+use async_channel::Sender;
+use async_executor::Executor;
+use async_io::Timer;
+use futures_lite::future::{self, block_on};
+use futures_lite::io::{sink, Cursor, Sink};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Handler that keeps the names of the threads that open requests.
+struct ThreadsHandler {
+    threads: Arc<Mutex<Vec<Option<String>>>>,
+}
+
+impl Handler for ThreadsHandler {
+    type Reader = Cursor<&'static [u8]>;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        let name = thread::current().name().map(str::to_owned);
+        self.threads.lock().unwrap().push(name);
+        Ok((Cursor::new(&b"hello"[..]), None))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+}
+
+/// Run `ex` on a pool of threads named `worker`, until the returned
+/// sender is dropped.
+fn spawn_workers(ex: &Arc<Executor<'static>>) -> Sender<()> {
+    let (stop_tx, stop_rx) = async_channel::bounded::<()>(1);
+
+    for _ in 0..4 {
+        let ex = Arc::clone(ex);
+        let stop_rx = stop_rx.clone();
+
+        thread::Builder::new()
+            .name("worker".to_string())
+            .spawn(move || {
+                block_on(ex.run(stop_rx.recv())).ok();
+            })
+            .unwrap();
+    }
+
+    stop_tx
+}
+
+#[test]
+fn serve_on_external_executor() {
+    let ex = Arc::new(Executor::new());
+    let workers = spawn_workers(&ex);
+
+    let threads = Arc::new(Mutex::new(Vec::new()));
+    let handler = ThreadsHandler {
+        threads: Arc::clone(&threads),
+    };
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(handler)
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build(),
+    )
+    .unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    let handle = tftpd.handle();
+
+    let server_ex = Arc::clone(&ex);
+    let server = thread::spawn(move || block_on(tftpd.serve_on(server_ex)));
+
+    let clients: Vec<_> = (0..8)
+        .map(|_| {
+            thread::spawn(move || {
+                let client = RawClient::new();
+                let req = rw_req("test", Opts::default());
+                rrq_transfer(&client, req, addr).1
+            })
+        })
+        .collect();
+
+    for client in clients {
+        assert_eq!(client.join().unwrap(), [b"hello"]);
+    }
+
+    handle.shutdown();
+    server.join().unwrap().unwrap();
+    drop(workers);
+
+    // Transfers were not spawned on the thread that serves
+    let threads = threads.lock().unwrap();
+    assert_eq!(threads.len(), 8);
+    assert!(threads.iter().all(|name| name.as_deref() == Some("worker")));
+}
+
+#[test]
+fn serve_on_shutdown_now_cancels_transfers() {
+    let ex = Arc::new(Executor::new());
+    let workers = spawn_workers(&ex);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(50))
+        .bind("127.0.0.1:0".parse().unwrap());
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    let handle = tftpd.handle();
+
+    let server_ex = Arc::clone(&ex);
+    let server = thread::spawn(move || block_on(tftpd.serve_on(server_ex)));
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    handle.shutdown_now();
+    server.join().unwrap().unwrap();
+
+    // Drop retransmissions that were sent before the transfer was
+    // cancelled.
+    thread::sleep(Duration::from_millis(100));
+    client.set_timeout(Duration::from_millis(10));
+    while client.try_recv().is_some() {}
+
+    // The executor is still running, but the transfer is not.
+    client.set_timeout(Duration::from_millis(300));
+    assert!(client.try_recv().is_none());
+    drop(workers);
+}
+
+#[test]
+fn serve_on_executor_that_stopped() {
+    let ex = Arc::new(Executor::new());
+    let workers = spawn_workers(&ex);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .bind("127.0.0.1:0".parse().unwrap());
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    let handle = tftpd.handle();
+
+    let server_ex = Arc::clone(&ex);
+    let server = thread::spawn(move || block_on(tftpd.serve_on(server_ex)));
+
+    // Nothing runs the executor anymore, so requests are accepted but
+    // their transfers never start.
+    drop(workers);
+    thread::sleep(Duration::from_millis(100));
+
+    let client = RawClient::new();
+    client.set_timeout(Duration::from_millis(300));
+    client.send_rrq("test", Opts::default(), addr);
+    assert!(client.try_recv().is_none());
+
+    handle.shutdown_now();
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn serve_on_dropped_mid_serve() {
+    let ex = Arc::new(Executor::new());
+    let workers = spawn_workers(&ex);
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(50))
+        .bind("127.0.0.1:0".parse().unwrap());
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+
+    let server_ex = Arc::clone(&ex);
+    let server = thread::spawn(move || {
+        block_on(future::or(tftpd.serve_on(server_ex), async {
+            Timer::after(Duration::from_millis(200)).await;
+            Ok(())
+        }))
+    });
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    // Server is dropped while the transfer is in progress
+    server.join().unwrap().unwrap();
+
+    thread::sleep(Duration::from_millis(100));
+    client.set_timeout(Duration::from_millis(10));
+    while client.try_recv().is_some() {}
+
+    // The transfer was cancelled and the executor is still running
+    client.set_timeout(Duration::from_millis(300));
+    assert!(client.try_recv().is_none());
+    assert!(block_on(ex.spawn(async { true })));
+    drop(workers);
+}
@@ -32,6 +32,7 @@ impl Handler for RandomHandler {
         &mut self,
         _client: &SocketAddr,
         _path: &Path,
+        _req: &packet::RwReq,
     ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
         let md5_tx = self.md5_tx.take().expect("md5_tx already consumed");
         Ok((RandomFile::new(self.file_size, md5_tx), None))
@@ -41,6 +42,7 @@ impl Handler for RandomHandler {
         &mut self,
         _client: &SocketAddr,
         _path: &Path,
+        _req: &packet::RwReq,
         _size: Option<u64>,
     ) -> Result<Self::Writer, packet::Error> {
         Err(packet::Error::IllegalOperation)
@@ -0,0 +1,59 @@
+use std::net::SocketAddr;
+
+use crate::packet::Opts;
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+#[test]
+fn rrq_ipv6() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let addr = spawn_server_at(builder, "[::1]:0".parse().unwrap());
+    assert!(addr.is_ipv6());
+
+    let client = RawClient::bind("[::1]:0");
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+}
+
+#[test]
+fn wrq_ipv6() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler);
+    let addr = spawn_server_at(builder, "[::1]:0".parse().unwrap());
+
+    let client = RawClient::bind("[::1]:0");
+    wrq_transfer(&client, rw_req("test", Opts::default()), addr, &[b"hello"]);
+
+    wait_for_len(&data, 5);
+    assert_eq!(&*data.lock().unwrap(), b"hello");
+}
+
+#[test]
+fn dual_stack() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .dual_stack();
+    let addr = spawn_server_at(builder, "[::]:0".parse().unwrap());
+    let addr: SocketAddr = ([127, 0, 0, 1], addr.port()).into();
+
+    // IPv4 client
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+}
+
+#[test]
+fn rrq_ipv6_socket_opts() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .socket_ttl(16)
+        .socket_dscp(46);
+    let addr = spawn_server_at(builder, "[::1]:0".parse().unwrap());
+
+    let client = RawClient::bind("[::1]:0");
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+}
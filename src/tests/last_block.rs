@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use crate::packet::{Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn opts(block_size: u16, window_size: Option<u16>) -> Opts {
+    Opts {
+        block_size: Some(block_size),
+        window_size,
+        ..Opts::default()
+    }
+}
+
+/// Read a file of `blocks` full blocks and check that it ends with an
+/// empty block that is acknowledged.
+fn rrq_exact_multiple(blocks: usize, window_size: Option<u16>) {
+    let content: Vec<u8> = (0..blocks * 8).map(|x| x as u8).collect();
+    let handler = BytesHandler::new(&content[..]);
+    let results = handler.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let (_, received) =
+        rrq_transfer(&client, rw_req("test", opts(8, window_size)), addr);
+
+    assert_eq!(received.len(), blocks + 1);
+    assert!(received[..blocks].iter().all(|block| block.len() == 8));
+    assert!(received[blocks].is_empty());
+    assert_eq!(received.concat(), content);
+
+    // Server is done once the empty block is acknowledged
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.blocks, blocks as u64 + 1);
+    assert_eq!(stats.bytes, content.len() as u64);
+    assert_eq!(stats.retransmits, 0);
+
+    client.set_timeout(Duration::from_millis(200));
+    assert!(client.try_recv().is_none());
+}
+
+#[test]
+fn rrq_one_block() {
+    rrq_exact_multiple(1, None);
+}
+
+#[test]
+fn rrq_two_blocks() {
+    rrq_exact_multiple(2, None);
+}
+
+#[test]
+fn rrq_exact_window() {
+    // Empty block is the first block of a new window
+    rrq_exact_multiple(2, Some(2));
+    rrq_exact_multiple(4, Some(4));
+    rrq_exact_multiple(8, Some(4));
+}
+
+#[test]
+fn rrq_partial_window() {
+    // Empty block ends a window early
+    rrq_exact_multiple(2, Some(4));
+    rrq_exact_multiple(3, Some(2));
+}
+
+#[test]
+fn rrq_default_block_size() {
+    let content = vec![0x55; 1024];
+    let addr = spawn_server(TftpServerBuilder::with_handler(
+        BytesHandler::new(&content[..]),
+    ));
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    for block_id in 1..=3 {
+        let (data, peer) = client.recv();
+        match Packet::decode(&data) {
+            Ok(Packet::Data(id, block)) => {
+                assert_eq!(id, block_id);
+                let len = if block_id == 3 {
+                    0
+                } else {
+                    512
+                };
+                assert_eq!(block.len(), len);
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+        client.send(&Packet::Ack(block_id), peer);
+    }
+}
+
+fn wrq_exact_multiple(blocks: usize, window_size: Option<u16>) {
+    let content: Vec<u8> = (0..blocks * 8).map(|x| x as u8).collect();
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let results = handler.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let mut sent: Vec<&[u8]> = content.chunks(8).collect();
+    sent.push(b"");
+    wrq_transfer(&client, rw_req("test", opts(8, window_size)), addr, &sent);
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.blocks, blocks as u64 + 1);
+    assert_eq!(*data.lock().unwrap(), content);
+}
+
+#[test]
+fn wrq_two_blocks() {
+    wrq_exact_multiple(2, None);
+}
+
+#[test]
+fn wrq_exact_window() {
+    wrq_exact_multiple(2, Some(2));
+    wrq_exact_multiple(4, Some(2));
+}
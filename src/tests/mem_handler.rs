@@ -0,0 +1,99 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::MemHandler;
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn tsize_opts() -> Opts {
+    Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    }
+}
+
+/// Wait until `name` is stored in `handler`.
+fn wait_for_file(handler: &MemHandler, name: &str) -> Vec<u8> {
+    for _ in 0..100 {
+        if let Some(data) = handler.get(name) {
+            return data.to_vec();
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("{} was not stored", name);
+}
+
+#[test]
+fn rrq_from_memory() {
+    let content: Vec<u8> = (0..1500).map(|x| x as u8).collect();
+    let handler = MemHandler::new();
+    handler.insert("boot/image.bin", content.clone());
+
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("/boot/image.bin", tsize_opts()), addr);
+    assert_eq!(oack.unwrap().transfer_size, Some(1500));
+    assert_eq!(blocks.concat(), content);
+
+    // Use another client, the previous transfer may not be done yet
+    let client = RawClient::new();
+    client.send_rrq("missing", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::FileNotFound))
+    ));
+}
+
+#[test]
+fn wrq_round_trip() {
+    let handler = MemHandler::new().allow_writes();
+    let files = handler.clone();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let blocks: [&[u8]; 2] = [&[0x55; 512], b"end"];
+    wrq_transfer(&client, rw_req("upload", Opts::default()), addr, &blocks);
+    assert_eq!(wait_for_file(&files, "upload"), blocks.concat());
+
+    let client = RawClient::new();
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("upload", tsize_opts()), addr);
+    assert_eq!(oack.unwrap().transfer_size, Some(515));
+    assert_eq!(blocks.concat(), files.get("upload").unwrap());
+}
+
+#[test]
+fn wrq_failed_upload_not_stored() {
+    let handler = MemHandler::new().allow_writes();
+    let files = handler.clone();
+    files.insert("upload", "old");
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("upload", Opts::default())), addr);
+    let (_, peer) = client.recv();
+    client.send(&Packet::Data(1, &[0x55; 512]), peer);
+    client.recv();
+    client.send(&Packet::Error(packet::Error::DiskFull), peer);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(files.get("upload").unwrap(), "old");
+}
+
+#[test]
+fn wrq_read_only() {
+    let addr = spawn_server(TftpServerBuilder::with_handler(MemHandler::new()));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("upload", Opts::default())), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+}
@@ -1,7 +1,45 @@
 #![cfg(test)]
 
+mod access;
+mod ack;
+mod blksize;
+mod boxed_handler;
+mod chain_handler;
+mod client;
+mod dir_handler;
+mod error_message;
+mod events;
+mod executor;
 mod external_client;
 mod handlers;
+mod ipv6;
+mod last_block;
+mod mem_handler;
+mod mode;
+mod netascii;
+mod oack;
+mod offset;
 mod packet;
+mod port_range;
 mod random_file;
+mod rate_limit;
+mod read_ahead;
+mod read_fn;
+mod reader_errors;
+mod request;
+mod restart;
+mod retries;
+mod rollover;
 mod rrq;
+mod serve;
+mod should_serve;
+mod shutdown;
+mod single_port;
+mod socket_opts;
+mod tasks;
+mod tracing;
+mod transfer_done;
+mod unexpected;
+mod utils;
+mod window;
+mod wrq;
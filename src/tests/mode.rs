@@ -0,0 +1,85 @@
+use futures_lite::io::{Cursor, Sink};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::packet::{self, Mode, Opts, Packet, RwReq};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Handler that serves only octet transfers.
+struct OctetHandler;
+
+impl Handler for OctetHandler {
+    type Reader = Cursor<&'static [u8]>;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        req: &RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        match req.mode() {
+            Mode::Octet => Ok((Cursor::new(&b"hello"[..]), None)),
+            _ => Err(packet::Error::IllegalOperation),
+        }
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Err(packet::Error::IllegalOperation)
+    }
+}
+
+#[test]
+fn handler_rejects_mode() {
+    let addr = spawn_server(TftpServerBuilder::with_handler(OctetHandler));
+    let client = RawClient::new();
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, [b"hello"]);
+
+    let client = RawClient::new();
+    let mut req = rw_req("test", Opts::default());
+    req.mode = Mode::Netascii;
+    client.send(&Packet::Rrq(req), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+}
+
+#[test]
+fn mail_mode_rejected() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    for packet in [Packet::Rrq, Packet::Wrq] {
+        let client = RawClient::new();
+        let mut req = rw_req("test", Opts::default());
+        req.mode = Mode::Mail;
+        client.send(&packet(req), addr);
+
+        let (reply, _) = client.recv();
+        assert!(matches!(
+            Packet::decode(&reply),
+            Ok(Packet::Error(packet::Error::IllegalOperation))
+        ));
+    }
+
+    // Handler never sees the requests
+    assert_eq!(opens.load(Ordering::SeqCst), 0);
+    assert_eq!(&*data.lock().unwrap(), b"hello");
+}
@@ -0,0 +1,116 @@
+use crate::packet::{Mode, Opts, RwReq};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn netascii_rrq(content: &[u8], block_size: u16) -> Vec<Vec<u8>> {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let mut req = rw_req(
+        "test",
+        Opts {
+            block_size: Some(block_size),
+            ..Opts::default()
+        },
+    );
+    req.mode = Mode::Netascii;
+
+    let (oack, blocks) = rrq_transfer(&client, req, addr);
+    assert_eq!(oack.unwrap().block_size, Some(block_size));
+
+    blocks
+}
+
+#[test]
+fn rrq_netascii_encode() {
+    let blocks = netascii_rrq(b"a\nb\r", 8);
+    assert_eq!(blocks, [&b"a\r\nb\r\0"[..]]);
+}
+
+#[test]
+fn rrq_netascii_block_boundary() {
+    // `\n` is the last byte of the first block, so its injected `\r`
+    // ends the first block and `\n` starts the second one.
+    let blocks = netascii_rrq(b"abcdefg\nh\r", 8);
+    assert_eq!(blocks, [&b"abcdefg\r"[..], &b"\nh\r\0"[..]]);
+
+    let blocks = netascii_rrq(b"abcdef\r\n", 8);
+    assert_eq!(blocks, [&b"abcdef\r\0"[..], &b"\r\n"[..]]);
+
+    // Exact multiple of block size must be followed by an empty block.
+    let blocks = netascii_rrq(b"abcdefg\n", 8);
+    assert_eq!(blocks, [&b"abcdefg\r"[..], &b"\n"[..]]);
+
+    let blocks = netascii_rrq(b"abc\ndef", 8);
+    assert_eq!(blocks, [&b"abc\r\ndef"[..], &b""[..]]);
+}
+
+#[test]
+fn rrq_netascii_no_tsize() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("a\nb"));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let mut req = rw_req(
+        "test",
+        Opts {
+            transfer_size: Some(0),
+            ..Opts::default()
+        },
+    );
+    req.mode = Mode::Netascii;
+
+    let (oack, blocks) = rrq_transfer(&client, req, addr);
+    assert!(oack.is_none());
+    assert_eq!(blocks, [&b"a\r\nb"[..]]);
+}
+
+fn netascii_req(block_size: u16) -> RwReq {
+    let mut req = rw_req(
+        "test",
+        Opts {
+            block_size: Some(block_size),
+            ..Opts::default()
+        },
+    );
+    req.mode = Mode::Netascii;
+    req
+}
+
+#[test]
+fn wrq_netascii_decode() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    // `\r` of both sequences is the last byte of a block.
+    let blocks: &[&[u8]] = &[b"abcdefg\r", b"\nabcdef\r", b"\0x\r\n"];
+    wrq_transfer(&client, netascii_req(8), addr, blocks);
+    wait_for_len(&data, 17);
+
+    assert_eq!(&data.lock().unwrap()[..], b"abcdefg\nabcdef\rx\n");
+}
+
+#[test]
+fn netascii_round_trip() {
+    let content = b"line 1\r\nline 2\r\nline 3\r\0end\r\n";
+
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    // Upload `content` in netascii,
+    let blocks: Vec<&[u8]> = content.chunks(8).collect();
+    wrq_transfer(&client, netascii_req(8), addr, &blocks);
+    wait_for_len(&data, 24);
+    assert_eq!(&data.lock().unwrap()[..], b"line 1\nline 2\nline 3\rend\n");
+
+    // then download it in netascii.
+    let client = RawClient::new();
+    let (_, blocks) = rrq_transfer(&client, netascii_req(8), addr);
+    assert_eq!(blocks.concat(), content);
+}
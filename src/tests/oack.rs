@@ -0,0 +1,289 @@
+use std::time::{Duration, Instant};
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn rrq_tsize_oack(
+    builder: TftpServerBuilder<BytesHandler>,
+    tsize: u64,
+) -> Opts {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        transfer_size: Some(tsize),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, _) = client.recv();
+    expect_oack(&data)
+}
+
+#[test]
+fn rrq_tsize_zero() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let opts = rrq_tsize_oack(builder, 0);
+    assert_eq!(opts.transfer_size, Some(5));
+}
+
+#[test]
+fn rrq_tsize_nonzero_strict() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        transfer_size: Some(1234),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    // No other option was negotiated, so we get the first block directly.
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == b"hello"
+    ));
+}
+
+#[test]
+fn rrq_tsize_nonzero_tolerant() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .tolerant_transfer_size();
+    let opts = rrq_tsize_oack(builder, 1234);
+    assert_eq!(opts.transfer_size, Some(5));
+}
+
+#[test]
+fn rrq_oack_retransmit() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(100));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts.clone(), addr);
+
+    // Drop the first OACK
+    let (first, _) = client.recv();
+    let (second, peer) = client.recv();
+    assert_eq!(expect_oack(&first), opts);
+    assert_eq!(first, second);
+
+    client.send(&Packet::Ack(0), peer);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == b"hello"
+    ));
+}
+
+#[test]
+fn rrq_oack_max_send_retries() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(50))
+        .max_send_retries(2);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    // OACK and two retransmissions
+    for _ in 0..3 {
+        let (data, _) = client.recv();
+        expect_oack(&data);
+    }
+
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Error(_))));
+}
+
+fn expect_option_negotiation_failed(data: &[u8]) {
+    assert!(matches!(
+        Packet::decode(data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+}
+
+#[test]
+fn strict_options_window_size() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .window_size_limit(8)
+        .strict_options();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        window_size: Some(64),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, _) = client.recv();
+    expect_option_negotiation_failed(&data);
+
+    // Options within the limits are accepted
+    let opts = Opts {
+        window_size: Some(8),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts.clone(), addr);
+
+    let (data, _) = client.recv();
+    assert_eq!(expect_oack(&data), opts);
+}
+
+#[test]
+fn strict_options_wrq_block_size() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(""))
+        .block_size_limit(1024)
+        .strict_options();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1428),
+        ..Opts::default()
+    };
+    client.send(&Packet::Wrq(rw_req("test", opts)), addr);
+
+    let (data, _) = client.recv();
+    expect_option_negotiation_failed(&data);
+}
+
+#[test]
+fn strict_options_ignored_timeout() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .ignore_client_timeout()
+        .strict_options();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        timeout: Some(5),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, _) = client.recv();
+    expect_option_negotiation_failed(&data);
+}
+
+#[test]
+fn non_strict_options_clamped() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .window_size_limit(8);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        window_size: Some(64),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, _) = client.recv();
+    assert_eq!(expect_oack(&data).window_size, Some(8));
+}
+
+#[test]
+fn unknown_opts_not_acknowledged() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_raw(b"\x00\x01test\0octet\0blksize2\x00999\0", addr);
+
+    // Only unknown options were requested, so there is no OACK
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == b"hello"
+    ));
+}
+
+/// Returns the acknowledged timeout and the time between the first OACK
+/// and its retransmission.
+fn oack_timeout(
+    builder: TftpServerBuilder<BytesHandler>,
+) -> (Option<u8>, Duration) {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        timeout: Some(2),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, _) = client.recv();
+    let now = Instant::now();
+    let oack = expect_oack(&data);
+
+    let (retransmitted, _) = client.recv();
+    assert_eq!(data, retransmitted);
+
+    (oack.timeout, now.elapsed())
+}
+
+#[test]
+fn client_timeout_is_honored() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(500));
+    let (timeout, elapsed) = oack_timeout(builder);
+
+    // Advertised timeout is the one that server uses
+    assert_eq!(timeout, Some(2));
+    assert!(elapsed >= Duration::from_millis(1900));
+}
+
+#[test]
+fn server_timeout_is_not_advertised() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(500))
+        .ignore_client_timeout();
+    let (timeout, elapsed) = oack_timeout(builder);
+
+    assert_eq!(timeout, None);
+    assert!(elapsed >= Duration::from_millis(400));
+    assert!(elapsed < Duration::from_millis(1500));
+}
+
+#[test]
+fn no_oack() {
+    let content = vec![0x55; 2000];
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(content)).no_oack();
+    let addr = spawn_server(builder);
+
+    let opts = Opts {
+        block_size: Some(1024),
+        window_size: Some(4),
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+
+    let client = RawClient::new();
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("test", opts.clone()), addr);
+    assert_eq!(oack, None);
+    assert_eq!(blocks[0].len(), 512);
+    assert_eq!(blocks.concat().len(), 2000);
+
+    // Write requests are acknowledged with ACK 0
+    let client = RawClient::new();
+    let oack = wrq_transfer(&client, rw_req("test", opts), addr, &[b"up"]);
+    assert_eq!(oack, None);
+}
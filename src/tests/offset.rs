@@ -0,0 +1,276 @@
+use futures_lite::io::{sink, Cursor, Sink};
+use futures_lite::AsyncRead;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tempfile::tempdir;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::{ChainHandler, DirHandler, DirHandlerMode};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Request `content` with `offset` and return the ids and payloads of
+/// the received DATA packets.
+fn rrq_offset(content: &[u8], opts: Opts) -> Vec<(u16, Vec<u8>)> {
+    rrq_offset_from(BytesHandler::new(content), opts)
+}
+
+fn rrq_offset_from<H>(handler: H, opts: Opts) -> Vec<(u16, Vec<u8>)>
+where
+    H: Handler + 'static,
+{
+    let builder = TftpServerBuilder::with_handler(handler);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let block_size = opts.block_size.map(usize::from).unwrap_or(512);
+    client.send_rrq("test", opts.clone(), addr);
+
+    let (data, peer) = client.recv();
+    assert_eq!(expect_oack(&data), opts);
+    client.send(&Packet::Ack(0), peer);
+
+    let mut blocks = Vec::new();
+
+    loop {
+        let (data, _) = client.recv();
+
+        match Packet::decode(&data) {
+            Ok(Packet::Data(block_id, payload)) => {
+                let is_last_block = payload.len() < block_size;
+                blocks.push((block_id, payload.to_vec()));
+                client.send(&Packet::Ack(block_id), peer);
+
+                if is_last_block {
+                    return blocks;
+                }
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+}
+
+#[test]
+fn rrq_offset_skips_blocks() {
+    let content: Vec<u8> = (0..8 * 5 + 3).map(|x| x as u8).collect();
+    let opts = Opts {
+        block_size: Some(8),
+        offset: Some(3),
+        ..Opts::default()
+    };
+
+    let blocks = rrq_offset(&content, opts);
+    let ids: Vec<u16> = blocks.iter().map(|(id, _)| *id).collect();
+    assert_eq!(ids, [4, 5, 6]);
+
+    let data: Vec<u8> = blocks.into_iter().flat_map(|(_, b)| b).collect();
+    assert_eq!(data, content[8 * 3..]);
+}
+
+#[test]
+fn rrq_offset_rollover() {
+    let content: Vec<u8> = (0..8 * 65537 + 3).map(|x| x as u8).collect();
+    let opts = Opts {
+        block_size: Some(8),
+        rollover: Some(1),
+        offset: Some(65534),
+        ..Opts::default()
+    };
+
+    let blocks = rrq_offset(&content, opts);
+    let ids: Vec<u16> = blocks.iter().map(|(id, _)| *id).collect();
+    assert_eq!(ids, [65535, 1, 2, 3]);
+}
+
+#[test]
+fn rrq_offset_past_end() {
+    let opts = Opts {
+        offset: Some(10),
+        ..Opts::default()
+    };
+
+    let blocks = rrq_offset(b"hello", opts);
+    assert_eq!(blocks, [(1, Vec::new())]);
+}
+
+/// Handler that counts the bytes that are read from its file, which it
+/// can seek or not.
+struct CountingHandler {
+    content: Vec<u8>,
+    seekable: bool,
+    read: Arc<AtomicUsize>,
+}
+
+impl CountingHandler {
+    fn new(content: &[u8], seekable: bool) -> Self {
+        CountingHandler {
+            content: content.to_vec(),
+            seekable,
+            read: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+struct CountingReader {
+    inner: Cursor<Vec<u8>>,
+    read: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(len)) = &poll {
+            self.read.fetch_add(*len, Ordering::SeqCst);
+        }
+
+        poll
+    }
+}
+
+impl Handler for CountingHandler {
+    type Reader = CountingReader;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        let reader = CountingReader {
+            inner: Cursor::new(self.content.clone()),
+            read: Arc::clone(&self.read),
+        };
+        Ok((reader, Some(self.content.len() as u64)))
+    }
+
+    async fn read_req_seek(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        reader: &mut Self::Reader,
+        pos: u64,
+    ) -> io::Result<bool> {
+        if self.seekable {
+            reader.inner.set_position(pos);
+        }
+        Ok(self.seekable)
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+}
+
+#[test]
+fn rrq_offset_seek() {
+    let content: Vec<u8> = (0..8 * 5 + 3).map(|x| x as u8).collect();
+    let opts = Opts {
+        block_size: Some(8),
+        offset: Some(3),
+        ..Opts::default()
+    };
+
+    let discarding = CountingHandler::new(&content, false);
+    let discarded = discarding.read.clone();
+    let seeking = CountingHandler::new(&content, true);
+    let seeked = seeking.read.clone();
+
+    let blocks = rrq_offset_from(discarding, opts.clone());
+    assert_eq!(rrq_offset_from(seeking, opts), blocks);
+
+    // Skipped blocks are not read when the reader can seek
+    assert_eq!(discarded.load(Ordering::SeqCst), content.len());
+    assert_eq!(seeked.load(Ordering::SeqCst), content.len() - 8 * 3);
+}
+
+#[test]
+fn rrq_offset_seek_numbering() {
+    let rollover: Vec<u8> = (0..8 * 65537 + 3).map(|x| x as u8).collect();
+    let cases = [
+        (&b"hello"[..], Some(512), 10),
+        (&b"exactly8"[..], Some(8), 1),
+        (&b"exactly8"[..], Some(8), 2),
+        (&rollover[..], Some(8), 65534),
+    ];
+
+    for (content, block_size, offset) in cases {
+        let opts = Opts {
+            block_size,
+            rollover: Some(1),
+            offset: Some(offset),
+            ..Opts::default()
+        };
+
+        let seeking = CountingHandler::new(content, true);
+        assert_eq!(
+            rrq_offset_from(seeking, opts.clone()),
+            rrq_offset(content, opts),
+            "offset {}",
+            offset
+        );
+    }
+}
+
+#[test]
+fn rrq_offset_dir_handler() {
+    let content: Vec<u8> = (0..8 * 5 + 3).map(|x| x as u8).collect();
+    let dir = tempdir().unwrap();
+    fs::write(dir.path().join("test"), &content).unwrap();
+    let opts = Opts {
+        block_size: Some(8),
+        offset: Some(3),
+        ..Opts::default()
+    };
+
+    let handler =
+        DirHandler::new(dir.path(), DirHandlerMode::ReadOnly).unwrap();
+    assert_eq!(
+        rrq_offset_from(handler, opts.clone()),
+        rrq_offset(&content, opts)
+    );
+}
+
+#[test]
+fn rrq_offset_seek_boxed() {
+    let content: Vec<u8> = (0..8 * 5 + 3).map(|x| x as u8).collect();
+    let opts = Opts {
+        block_size: Some(8),
+        offset: Some(3),
+        ..Opts::default()
+    };
+
+    let boxed = CountingHandler::new(&content, true);
+    let boxed_read = boxed.read.clone();
+    let chained = CountingHandler::new(&content, true);
+    let chained_read = chained.read.clone();
+
+    let blocks = rrq_offset(&content, opts.clone());
+    assert_eq!(rrq_offset_from(boxed.boxed(), opts.clone()), blocks);
+    assert_eq!(
+        rrq_offset_from(ChainHandler::new().with_handler(chained), opts),
+        blocks
+    );
+
+    // Seeks reach the handler that opened the reader
+    assert_eq!(boxed_read.load(Ordering::SeqCst), content.len() - 8 * 3);
+    assert_eq!(chained_read.load(Ordering::SeqCst), content.len() - 8 * 3);
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::octal_escapes)]
+
 use bytes::{Bytes, BytesMut};
 
 use crate::error::Error;
@@ -62,7 +64,8 @@ fn check_rrq() {
                         opts: Opts {
                             block_size: Some(123),
                             timeout: Some(3),
-                            transfer_size: Some(5556)
+                            transfer_size: Some(5556),
+                            ..Opts::default()
                         }
                     }
     ));
@@ -82,7 +85,13 @@ fn check_rrq() {
                     if req == &RwReq {
                         filename: "abc".to_string(),
                         mode: Mode::Netascii,
-                        opts: Opts::default()
+                        opts: Opts {
+                            unknown: vec![(
+                                "blksizeX".to_string(),
+                                "123".to_string()
+                            )],
+                            ..Opts::default()
+                        }
                     }
     ));
 }
@@ -133,7 +142,8 @@ fn check_wrq() {
                         opts: Opts {
                             block_size: Some(123),
                             timeout: Some(3),
-                            transfer_size: Some(5556)
+                            transfer_size: Some(5556),
+                            ..Opts::default()
                         }
                     }
     ));
@@ -148,7 +158,13 @@ fn check_wrq() {
                     if req == &RwReq {
                         filename: "abc".to_string(),
                         mode: Mode::Octet,
-                        opts: Opts::default()
+                        opts: Opts {
+                            unknown: vec![(
+                                "blksizeX".to_string(),
+                                "123".to_string()
+                            )],
+                            ..Opts::default()
+                        }
                     }
     ));
 }
@@ -156,14 +172,12 @@ fn check_wrq() {
 #[test]
 fn check_data() {
     let packet = Packet::decode(b"\x00\x03\x00\x09abcde");
-    assert!(
-        matches!(packet, Ok(Packet::Data(9, ref data)) if &data[..] == b"abcde")
-    );
+    assert!(matches!(packet, Ok(Packet::Data(9, data)) if data == b"abcde"));
 
     assert_eq!(packet_to_bytes(&packet.unwrap()), b"\x00\x03\x00\x09abcde"[..]);
 
     let packet = Packet::decode(b"\x00\x03\x00\x09");
-    assert!(matches!(packet, Ok(Packet::Data(9, ref data)) if data.is_empty()));
+    assert!(matches!(packet, Ok(Packet::Data(9, data)) if data.is_empty()));
     assert_eq!(packet_to_bytes(&packet.unwrap()), b"\x00\x03\x00\x09"[..]);
 }
 
@@ -222,7 +236,8 @@ fn check_oack() {
                     if opts == &Opts {
                         block_size: Some(123),
                         timeout: None,
-                        transfer_size: None
+                        transfer_size: None,
+                        ..Opts::default()
                     }
     ));
 
@@ -231,7 +246,8 @@ fn check_oack() {
                     if opts == &Opts {
                         block_size: None,
                         timeout: Some(3),
-                        transfer_size: None
+                        transfer_size: None,
+                        ..Opts::default()
                     }
     ));
 
@@ -241,6 +257,7 @@ fn check_oack() {
                         block_size: None,
                         timeout: None,
                         transfer_size: Some(5556),
+                        ..Opts::default()
                     }
     ));
 
@@ -251,6 +268,7 @@ fn check_oack() {
                         block_size: Some(123),
                         timeout: Some(3),
                         transfer_size: Some(5556),
+                        ..Opts::default()
                     }
     ));
 }
@@ -341,3 +359,201 @@ fn check_timeout_boundaries() {
         }
     );
 }
+
+#[test]
+fn check_windowsize_boundaries() {
+    let opts = parse_opts(b"windowsize\00\0").unwrap();
+    assert_eq!(
+        opts,
+        Opts {
+            window_size: None,
+            ..Opts::default()
+        }
+    );
+
+    let opts = parse_opts(b"windowsize\01\0").unwrap();
+    assert_eq!(
+        opts,
+        Opts {
+            window_size: Some(1),
+            ..Opts::default()
+        }
+    );
+
+    let opts = parse_opts(b"windowsize\065535\0").unwrap();
+    assert_eq!(
+        opts,
+        Opts {
+            window_size: Some(65535),
+            ..Opts::default()
+        }
+    );
+
+    let opts = parse_opts(b"windowsize\065536\0").unwrap();
+    assert_eq!(
+        opts,
+        Opts {
+            window_size: None,
+            ..Opts::default()
+        }
+    );
+}
+
+#[test]
+fn check_windowsize_encode() {
+    let packet = Packet::OAck(Opts {
+        window_size: Some(16),
+        ..Opts::default()
+    });
+    assert_eq!(packet_to_bytes(&packet), b"\x00\x06windowsize\x0016\0"[..]);
+}
+
+#[test]
+fn check_rollover() {
+    let opts = parse_opts(b"rollover\00\0").unwrap();
+    assert_eq!(opts.rollover, Some(0));
+
+    let opts = parse_opts(b"rollover\01\0").unwrap();
+    assert_eq!(opts.rollover, Some(1));
+
+    let opts = parse_opts(b"rollover\02\0").unwrap();
+    assert_eq!(opts.rollover, None);
+
+    let packet = Packet::OAck(Opts {
+        rollover: Some(1),
+        ..Opts::default()
+    });
+    assert_eq!(packet_to_bytes(&packet), b"\x00\x06rollover\x001\0"[..]);
+}
+
+#[test]
+fn check_offset() {
+    let opts = parse_opts(b"offset\0123\0").unwrap();
+    assert_eq!(opts.offset, Some(123));
+    assert!(opts.unknown.is_empty());
+
+    let opts = parse_opts(b"offset\0-1\0").unwrap();
+    assert_eq!(opts.offset, None);
+
+    let packet = Packet::OAck(Opts {
+        offset: Some(123),
+        ..Opts::default()
+    });
+    assert_eq!(packet_to_bytes(&packet), b"\x00\x06offset\x00123\0"[..]);
+}
+
+#[test]
+fn check_unknown_opts() {
+    let opts = parse_opts(b"blksize2\x00999\x00blksize\x001024\0").unwrap();
+    assert_eq!(opts.block_size, Some(1024));
+    assert_eq!(opts.unknown, [("blksize2".to_string(), "999".to_string())]);
+
+    // Unknown options are never encoded
+    let packet = Packet::OAck(opts);
+    assert_eq!(packet_to_bytes(&packet), b"\x00\x06blksize\x001024\0"[..]);
+}
+
+#[test]
+fn public_decode() {
+    let data = b"\x00\x01abc\0octet\0blksize\x001024\0".to_vec();
+    let packet = packet::decode(&data).unwrap();
+    drop(data);
+
+    assert_eq!(
+        packet,
+        packet::OwnedPacket::Rrq(RwReq::new(
+            "abc",
+            Mode::Octet,
+            Opts {
+                block_size: Some(1024),
+                ..Opts::default()
+            }
+        ))
+    );
+    assert_eq!(packet.to_bytes(), b"\x00\x01abc\0octet\0blksize\x001024\0");
+
+    let packet = packet::decode(b"\x00\x03\x00\x09abcde").unwrap();
+    assert_eq!(packet, packet::OwnedPacket::Data(9, b"abcde".to_vec()));
+    assert_eq!(packet.to_bytes(), b"\x00\x03\x00\x09abcde");
+
+    assert!(matches!(
+        packet::decode(b"\x00\x04\x00"),
+        Err(Error::InvalidPacket)
+    ));
+}
+
+#[test]
+fn public_decode_random_input() {
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = rand::rngs::SmallRng::seed_from_u64(0);
+
+    for _ in 0..10000 {
+        let len = rng.gen_range(0..64);
+        let mut data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+
+        // Make most inputs start with a valid opcode
+        if len >= 2 {
+            data[0] = 0;
+            data[1] %= 8;
+        }
+
+        if let Ok(packet) = packet::decode(&data) {
+            // Encoding a decoded packet must not panic either
+            packet.to_bytes();
+        }
+    }
+}
+
+#[test]
+fn check_empty_filename() {
+    let packet = Packet::decode(b"\x00\x01\x00octet\x00");
+    assert!(matches!(packet, Err(Error::InvalidPacket)));
+
+    let packet = Packet::decode(b"\x00\x02\x00octet\x00");
+    assert!(matches!(packet, Err(Error::InvalidPacket)));
+}
+
+#[test]
+fn error_display() {
+    let errors = [
+        (packet::Error::Msg("Custom".to_string()), 0, "Custom"),
+        (packet::Error::UnknownError, 0, "Unknown error"),
+        (packet::Error::FileNotFound, 1, "File not found"),
+        (packet::Error::PermissionDenied, 2, "Permission denied"),
+        (packet::Error::DiskFull, 3, "Disk is full"),
+        (packet::Error::IllegalOperation, 4, "Illegal operation"),
+        (packet::Error::UnknownTransferId, 5, "Unknown transfer ID"),
+        (packet::Error::FileAlreadyExists, 6, "File already exists"),
+        (packet::Error::NoSuchUser, 7, "No such user"),
+        (
+            packet::Error::OptionNegotiationFailed,
+            8,
+            "Option negotiation failed",
+        ),
+    ];
+
+    for (error, code, message) in errors {
+        assert_eq!(error.code(), code);
+        assert_eq!(error.message(), message);
+        assert_eq!(error.to_string(), message);
+    }
+
+    // Usable as a boxed error
+    let boxed: Box<dyn std::error::Error> = packet::Error::DiskFull.into();
+    assert_eq!(boxed.to_string(), "Disk is full");
+}
+
+#[test]
+fn error_message_utf8_boundary() {
+    let error = packet::Error::Msg("é".repeat(200));
+    let mut buf = BytesMut::new();
+    Packet::encode_error(&error, 255, &mut buf);
+
+    // Two-byte characters fit only up to 254 bytes
+    assert_eq!(buf.len(), 4 + 254 + 1);
+    assert!(matches!(
+        Packet::decode(&buf),
+        Ok(Packet::Error(packet::Error::Msg(msg))) if msg == "é".repeat(127)
+    ));
+}
@@ -0,0 +1,66 @@
+use std::net::UdpSocket;
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn free_port() -> u16 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port()
+}
+
+#[test]
+fn transfer_port_range() {
+    let port = free_port();
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .transfer_port_range(port..=port);
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+
+    // The port is released after the transfer
+    wait_for_result(&results).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    let (_, peer) = client.recv();
+    assert_eq!(peer.port(), port);
+}
+
+#[test]
+fn transfer_port_range_exhausted() {
+    let port = free_port();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .transfer_port_range(port..=port);
+    let addr = spawn_server(builder);
+
+    // First transfer waits for the ACK of its first block
+    let first = RawClient::new();
+    first.send_rrq("test", Opts::default(), addr);
+    let (data, first_peer) = first.recv();
+    assert_eq!(first_peer.port(), port);
+
+    // Second transfer has no port left, the error comes from the
+    // listening socket
+    let second = RawClient::new();
+    second.send_rrq("test", Opts::default(), addr);
+    let (err, peer) = second.recv();
+    assert_eq!(peer, addr);
+    assert!(matches!(Packet::decode(&err), Ok(Packet::Error(_))));
+
+    // First transfer is not affected
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == b"hello"
+    ));
+    first.send(&Packet::Ack(1), first_peer);
+}
@@ -0,0 +1,107 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::packet::Opts;
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+const RATE: u64 = 20_000;
+
+#[test]
+fn rrq_rate_limit() {
+    let content = vec![0x55; 10_000];
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .rate_limit(RATE);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let now = Instant::now();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    let elapsed = now.elapsed();
+    assert_eq!(blocks.concat(), content);
+
+    // 0.5s for the file, minus the initial burst of 0.1s
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+}
+
+#[test]
+fn rrq_rate_limit_is_shared() {
+    let content = vec![0x55; 5_000];
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .rate_limit(RATE);
+    let addr = spawn_server(builder);
+
+    let now = Instant::now();
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                let client = RawClient::new();
+                rrq_transfer(&client, rw_req("test", Opts::default()), addr)
+            })
+        })
+        .collect();
+
+    for client in clients {
+        let (_, blocks) = client.join().unwrap();
+        assert_eq!(blocks.concat().len(), 5_000);
+    }
+
+    // Both transfers together take as long as a single one of 10000
+    // bytes.
+    let elapsed = now.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+}
+
+#[test]
+fn rrq_per_client_rate_limit() {
+    let content = vec![0x55; 5_000];
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .per_client_rate_limit(RATE / 2);
+    let addr = spawn_server(builder);
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            thread::spawn(move || {
+                let client = RawClient::new();
+                let now = Instant::now();
+                let (_, blocks) = rrq_transfer(
+                    &client,
+                    rw_req("test", Opts::default()),
+                    addr,
+                );
+                (blocks.concat().len(), now.elapsed())
+            })
+        })
+        .collect();
+
+    // Each transfer has its own limit of 0.5s for the file, minus the
+    // initial burst of 0.1s
+    for client in clients {
+        let (len, elapsed) = client.join().unwrap();
+        assert_eq!(len, 5_000);
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(2000), "{:?}", elapsed);
+    }
+}
+
+#[test]
+fn rrq_rate_limits_compose() {
+    let content = vec![0x55; 5_000];
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .rate_limit(RATE)
+            .per_client_rate_limit(RATE / 2);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // Per-client limit is the tighter one
+    let now = Instant::now();
+    rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    let elapsed = now.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+}
@@ -0,0 +1,165 @@
+use futures_lite::io::{sink, Sink};
+use futures_lite::AsyncRead;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Reader of `len` bytes that fails after them if `fail` is set.
+struct CountingReader {
+    len: usize,
+    fail: bool,
+    read: Arc<AtomicUsize>,
+}
+
+impl AsyncRead for CountingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let read = self.read.load(Ordering::SeqCst);
+        let len = buf.len().min(self.len - read);
+
+        if len == 0 && self.fail {
+            return Poll::Ready(Err(io::ErrorKind::PermissionDenied.into()));
+        }
+
+        buf[..len].fill(0x42);
+        self.read.fetch_add(len, Ordering::SeqCst);
+        Poll::Ready(Ok(len))
+    }
+}
+
+/// Handler that serves `CountingReader`s and shares how many bytes they
+/// read.
+struct CountingHandler {
+    len: usize,
+    fail: bool,
+    read: Arc<AtomicUsize>,
+}
+
+impl Handler for CountingHandler {
+    type Reader = CountingReader;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        let reader = CountingReader {
+            len: self.len,
+            fail: self.fail,
+            read: Arc::clone(&self.read),
+        };
+        Ok((reader, None))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+}
+
+/// Returns how many blocks are read while the server waits for the ACK of
+/// the first block, and the blocks of the whole transfer.
+fn blocks_read_during_ack_wait(read_ahead: u16) -> (usize, Vec<Vec<u8>>) {
+    let read = Arc::new(AtomicUsize::new(0));
+    let handler = CountingHandler {
+        len: 512 * 10 + 100,
+        fail: false,
+        read: Arc::clone(&read),
+    };
+    let builder =
+        TftpServerBuilder::with_handler(handler).read_ahead(read_ahead);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    thread::sleep(Duration::from_millis(200));
+    let in_ack_wait = read.load(Ordering::SeqCst) / 512 - 1;
+
+    // Finish the transfer
+    let mut blocks = vec![data[4..].to_vec()];
+    let mut block_id = 1;
+
+    loop {
+        client.send(&Packet::Ack(block_id), peer);
+
+        if blocks.last().unwrap().len() < 512 {
+            break;
+        }
+
+        let (data, _) = client.recv();
+        match Packet::decode(&data) {
+            Ok(Packet::Data(id, payload)) if id == block_id + 1 => {
+                blocks.push(payload.to_vec());
+                block_id = id;
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    (in_ack_wait, blocks)
+}
+
+#[test]
+fn read_ahead_during_ack_wait() {
+    let (without, blocks) = blocks_read_during_ack_wait(0);
+    assert_eq!(without, 0);
+    assert_eq!(blocks.len(), 11);
+
+    let (with, read_ahead_blocks) = blocks_read_during_ack_wait(4);
+    assert_eq!(with, 4);
+    assert_eq!(read_ahead_blocks, blocks);
+}
+
+#[test]
+fn read_ahead_error_after_prefetched_blocks() {
+    let handler = CountingHandler {
+        len: 512 * 2,
+        fail: true,
+        read: Arc::new(AtomicUsize::new(0)),
+    };
+    let builder = TftpServerBuilder::with_handler(handler).read_ahead(4);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    // Blocks that were read before the error are still sent
+    for block_id in 1..=2 {
+        let (data, peer) = client.recv();
+        assert!(matches!(
+            Packet::decode(&data),
+            Ok(Packet::Data(id, _)) if id == block_id
+        ));
+        client.send(&Packet::Ack(block_id), peer);
+    }
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::PermissionDenied))
+    ));
+}
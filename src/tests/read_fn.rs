@@ -0,0 +1,55 @@
+use futures_lite::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+#[test]
+fn serve_read_fn() {
+    let clients = Arc::new(Mutex::new(Vec::<SocketAddr>::new()));
+    let clients_clone = Arc::clone(&clients);
+
+    let builder = TftpServerBuilder::serve_read_fn(move |client, path| {
+        clients_clone.lock().unwrap().push(client);
+
+        async move {
+            if path.starts_with("missing") {
+                return Err(packet::Error::FileNotFound);
+            }
+
+            let content = format!("generated {}", path.display());
+            let size = content.len() as u64;
+            Ok((Cursor::new(content), Some(size)))
+        }
+    });
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    let opts = Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("a/b", opts), addr);
+    assert_eq!(oack.unwrap().transfer_size, Some(13));
+    assert_eq!(blocks.concat(), b"generated a/b");
+
+    let client = RawClient::new();
+    client.send_rrq("missing", Opts::default(), addr);
+    assert!(matches!(
+        Packet::decode(&client.recv().0),
+        Ok(Packet::Error(packet::Error::FileNotFound))
+    ));
+
+    // Write requests are rejected
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("upload", Opts::default())), addr);
+    assert!(matches!(
+        Packet::decode(&client.recv().0),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+
+    assert_eq!(clients.lock().unwrap().len(), 2);
+}
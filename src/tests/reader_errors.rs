@@ -0,0 +1,151 @@
+use futures_lite::io::{sink, Sink};
+use futures_lite::AsyncRead;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Reader that replays a script of reads.
+struct ScriptedReader(VecDeque<io::Result<&'static [u8]>>);
+
+impl AsyncRead for ScriptedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.pop_front() {
+            Some(Ok(data)) => {
+                buf[..data.len()].copy_from_slice(data);
+                Poll::Ready(Ok(data.len()))
+            }
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            None => Poll::Ready(Ok(0)),
+        }
+    }
+}
+
+/// Handler that serves every read request with a `ScriptedReader`.
+struct ScriptedHandler(fn() -> Vec<io::Result<&'static [u8]>>);
+
+impl Handler for ScriptedHandler {
+    type Reader = ScriptedReader;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Ok((ScriptedReader((self.0)().into()), None))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        Ok(sink())
+    }
+}
+
+fn interrupted() -> io::Result<&'static [u8]> {
+    Err(io::Error::from(io::ErrorKind::Interrupted))
+}
+
+#[test]
+fn interrupted_read_is_retried() {
+    let handler = ScriptedHandler(|| vec![interrupted(), Ok(b"hello")]);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, [b"hello"]);
+}
+
+#[test]
+fn interrupted_read_in_netascii() {
+    let handler = ScriptedHandler(|| vec![interrupted(), Ok(b"a\nb")]);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let mut req = rw_req("test", Opts::default());
+    req.mode = packet::Mode::Netascii;
+    let (_, blocks) = rrq_transfer(&client, req, addr);
+    assert_eq!(blocks, [b"a\r\nb"]);
+}
+
+#[test]
+fn unexpected_eof_ends_transfer() {
+    let handler = ScriptedHandler(|| {
+        vec![
+            Ok(&[b'x'; 512][..]),
+            Ok(b"abc"),
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        ]
+    });
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    // Data that were read before the error are sent as the last block
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, [&[b'x'; 512][..], b"abc"]);
+}
+
+#[test]
+fn other_read_errors_abort_transfer() {
+    let handler = ScriptedHandler(|| {
+        vec![Err(io::Error::from(io::ErrorKind::PermissionDenied))]
+    });
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::PermissionDenied))
+    ));
+}
+
+#[test]
+fn read_error_mid_stream_aborts_transfer() {
+    let handler = ScriptedHandler(|| {
+        vec![
+            Ok(&[b'x'; 512][..]),
+            Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+        ]
+    });
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, peer) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == [b'x'; 512]
+    ));
+    client.send(&Packet::Ack(1), peer);
+
+    // Error without an OS error code is reported as unknown, which is
+    // code 0 on the wire.
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::Msg(msg))) if msg == "Unknown error"
+    ));
+}
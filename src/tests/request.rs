@@ -0,0 +1,194 @@
+use futures_lite::io::{sink, Cursor, Sink};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::packet::{self, Mode, Opts, Packet, RwReq};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Handler that keeps the requests it opens.
+struct RecordingHandler {
+    reqs: Arc<Mutex<Vec<RwReq>>>,
+}
+
+impl Handler for RecordingHandler {
+    type Reader = Cursor<&'static [u8]>;
+    type Writer = Sink;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        req: &RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        self.reqs.lock().unwrap().push(req.clone());
+        Ok((Cursor::new(&b"hello"[..]), None))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        req: &RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        self.reqs.lock().unwrap().push(req.clone());
+        Ok(sink())
+    }
+}
+
+#[test]
+fn handler_sees_request() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    // Server acknowledges less than client requests
+    let builder = TftpServerBuilder::with_handler(handler)
+        .block_size_limit(512)
+        .window_size_limit(2);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1428),
+        window_size: Some(8),
+        ..Opts::default()
+    };
+    rrq_transfer(&client, rw_req("file.bin", opts.clone()), addr);
+
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("upload.bin", opts.clone()), addr, &[b"hi"]);
+
+    let reqs = reqs.lock().unwrap();
+    assert_eq!(reqs.len(), 2);
+
+    assert_eq!(reqs[0].filename(), "file.bin");
+    assert_eq!(reqs[0].mode(), Mode::Octet);
+    assert_eq!(reqs[0].opts(), &opts);
+
+    assert_eq!(reqs[1].filename(), "upload.bin");
+    assert_eq!(reqs[1].opts(), &opts);
+}
+
+#[test]
+fn handler_sees_mode() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let mut req = rw_req("test", Opts::default());
+    req.mode = Mode::Netascii;
+    client.send(&Packet::Rrq(req), addr);
+    client.recv();
+
+    assert_eq!(reqs.lock().unwrap()[0].mode(), Mode::Netascii);
+}
+
+#[test]
+fn empty_filename_rejected() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+    client.set_timeout(Duration::from_millis(200));
+
+    // Invalid packets are ignored
+    client.send_raw(b"\x00\x01\x00octet\x00", addr);
+    assert!(client.try_recv().is_none());
+    client.send_raw(b"\x00\x02\x00octet\x00", addr);
+    assert!(client.try_recv().is_none());
+
+    assert!(reqs.lock().unwrap().is_empty());
+}
+
+#[test]
+fn long_filename_rejected() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let filename = "a".repeat(4000);
+
+    for packet in [
+        Packet::Rrq(rw_req(&filename, Opts::default())),
+        Packet::Wrq(rw_req(&filename, Opts::default())),
+    ] {
+        let client = RawClient::new();
+        client.send(&packet, addr);
+
+        let (data, peer) = client.recv();
+        assert_eq!(peer, addr);
+        assert!(matches!(
+            Packet::decode(&data),
+            Ok(Packet::Error(packet::Error::IllegalOperation))
+        ));
+    }
+
+    assert!(reqs.lock().unwrap().is_empty());
+}
+
+#[test]
+fn max_filename_len() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    let builder = TftpServerBuilder::with_handler(handler).max_filename_len(8);
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("12345678", Opts::default()), addr);
+    assert_eq!(blocks, [b"hello"]);
+
+    let client = RawClient::new();
+    client.send_rrq("123456789", Opts::default(), addr);
+    assert!(matches!(
+        Packet::decode(&client.recv().0),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+
+    assert_eq!(reqs.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn unknown_size_omits_tsize() {
+    let reqs = Arc::new(Mutex::new(Vec::new()));
+    let handler = RecordingHandler {
+        reqs: Arc::clone(&reqs),
+    };
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+
+    // Other options are still acknowledged
+    let client = RawClient::new();
+    let opts = Opts {
+        block_size: Some(1024),
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+    let oack = oack.unwrap();
+    assert_eq!(oack.block_size, Some(1024));
+    assert_eq!(oack.transfer_size, None);
+    assert_eq!(blocks, [b"hello"]);
+
+    // Nothing is left to acknowledge, so data are sent right away
+    let client = RawClient::new();
+    let opts = Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+    assert!(oack.is_none());
+    assert_eq!(blocks, [b"hello"]);
+}
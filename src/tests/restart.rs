@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::packet::{Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn data() -> Vec<u8> {
+    (0..1500).map(|i| i as u8).collect()
+}
+
+/// Send a read request and expect the first block from a new transfer.
+fn start_rrq(client: &RawClient, addr: SocketAddr) {
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+}
+
+fn restarted_rrq(builder: TftpServerBuilder<BytesHandler>) {
+    let addr = spawn_server(builder.restart_on_new_request());
+    let client = RawClient::new();
+
+    // Client crashes after the first block and requests the file again
+    start_rrq(&client, addr);
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks.concat(), data());
+}
+
+#[test]
+fn rrq_restart_on_new_request() {
+    let handler = BytesHandler::new(data());
+    let results = handler.results();
+    // Long timeout, so the previous transfer can only end by cancelling.
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_secs(10));
+
+    restarted_rrq(builder);
+
+    // Only the new transfer is done
+    wait_for_result(&results).unwrap();
+    assert!(results.lock().unwrap().is_empty());
+}
+
+#[test]
+fn rrq_restart_frees_task_slot() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(data()))
+        .timeout(Duration::from_secs(10))
+        .max_tasks(1);
+
+    restarted_rrq(builder);
+}
+
+#[test]
+fn rrq_new_request_ignored_by_default() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(data()))
+        .timeout(Duration::from_secs(10));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    start_rrq(&client, addr);
+    client.send_rrq("test", Opts::default(), addr);
+
+    client.set_timeout(Duration::from_millis(500));
+    assert!(client.try_recv().is_none());
+}
@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::packet::{Opts, Packet};
+use crate::server::{TftpServerBuilder, TransferError};
+
+use super::utils::*;
+
+fn server() -> TftpServerBuilder<BytesHandler> {
+    TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_millis(50))
+}
+
+/// Count the packets that are equal to the first one, until an ERROR is
+/// received.
+fn count_until_error(client: &RawClient) -> usize {
+    let (first, _) = client.recv();
+    let mut count = 1;
+
+    loop {
+        let (data, _) = client.recv();
+        if let Ok(Packet::Error(_)) = Packet::decode(&data) {
+            return count;
+        }
+        assert_eq!(data, first);
+        count += 1;
+    }
+}
+
+fn rrq_sends(builder: TftpServerBuilder<BytesHandler>) -> usize {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    count_until_error(&client)
+}
+
+fn wrq_acks(builder: TftpServerBuilder<BytesHandler>) -> usize {
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+    count_until_error(&client)
+}
+
+#[test]
+fn read_max_retries() {
+    let builder = server().max_send_retries(5).read_max_retries(1);
+    // First DATA and a single retransmission
+    assert_eq!(rrq_sends(builder), 2);
+
+    let builder = server().max_send_retries(5).read_max_retries(1);
+    assert_eq!(wrq_acks(builder), 6);
+}
+
+#[test]
+fn write_max_retries() {
+    let builder = server().max_send_retries(1).write_max_retries(3);
+    // First ACK and three retransmissions
+    assert_eq!(wrq_acks(builder), 4);
+
+    let builder = server().max_send_retries(1).write_max_retries(3);
+    assert_eq!(rrq_sends(builder), 2);
+}
+
+#[test]
+fn max_send_retries_is_shared() {
+    assert_eq!(rrq_sends(server().max_send_retries(2)), 3);
+    assert_eq!(wrq_acks(server().max_send_retries(2)), 3);
+}
+
+/// Time between the first DATA packet and each of its retransmissions.
+fn rrq_retransmit_waits(
+    builder: TftpServerBuilder<BytesHandler>,
+    retries: u32,
+) -> Vec<Duration> {
+    let addr = spawn_server(builder.read_max_retries(retries));
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (first, _) = client.recv();
+    let mut last = Instant::now();
+    let mut waits = Vec::new();
+
+    for _ in 0..retries {
+        let (data, _) = client.recv();
+        assert_eq!(data, first);
+        waits.push(last.elapsed());
+        last = Instant::now();
+    }
+
+    waits
+}
+
+#[test]
+fn timeout_jitter() {
+    let jitter = Duration::from_millis(100);
+    let waits = rrq_retransmit_waits(server().timeout_jitter(jitter), 8);
+
+    for wait in &waits {
+        assert!(*wait >= Duration::from_millis(45), "{:?}", wait);
+        assert!(*wait < Duration::from_millis(200), "{:?}", wait);
+    }
+
+    // Waits differ within the jitter band
+    let min = waits.iter().min().unwrap();
+    let max = waits.iter().max().unwrap();
+    assert!(*max - *min >= Duration::from_millis(10), "{:?}", waits);
+}
+
+#[test]
+fn max_total_retransmits() {
+    let handler = BytesHandler::new(vec![0x55; 512 * 10]);
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(50))
+        .max_total_retransmits(3);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    // Every block is acknowledged only after its first retransmission,
+    // which is within the retries of a block.
+    let mut acked = 0;
+    loop {
+        let (first, peer) = client.recv();
+        if let Ok(Packet::Error(_)) = Packet::decode(&first) {
+            break;
+        }
+
+        let (data, _) = client.recv();
+        match Packet::decode(&data) {
+            Ok(Packet::Data(id, _)) => {
+                assert_eq!(data, first);
+                client.send(&Packet::Ack(id), peer);
+                acked += 1;
+            }
+            Ok(Packet::Error(_)) => break,
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    assert_eq!(acked, 3);
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Timeout)));
+}
+
+#[test]
+fn on_retransmit() {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let calls_clone = Arc::clone(&calls);
+
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(vec![0x55; 1100]))
+            .timeout(Duration::from_millis(100))
+            .on_retransmit(move |_, block_id, attempt| {
+                calls_clone.lock().unwrap().push((block_id, attempt));
+            });
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (_, peer) = client.recv();
+    client.send(&Packet::Ack(1), peer);
+
+    // Lose the first DATA of block 2
+    let (first, _) = client.recv();
+    let (data, _) = client.recv();
+    assert_eq!(data, first);
+    client.send(&Packet::Ack(2), peer);
+
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(3, _))));
+    client.send(&Packet::Ack(3), peer);
+
+    assert_eq!(*calls.lock().unwrap(), [(2, 1)]);
+}
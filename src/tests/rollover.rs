@@ -0,0 +1,45 @@
+use crate::packet::Opts;
+use crate::server::TftpServerBuilder;
+use crate::utils::block_id_add;
+
+use super::utils::*;
+
+#[test]
+fn block_id_rollover() {
+    assert_eq!(block_id_add(1, 1, 0), 2);
+    assert_eq!(block_id_add(65535, 1, 0), 0);
+    assert_eq!(block_id_add(65535, 1, 1), 1);
+    assert_eq!(block_id_add(65530, 8, 0), 2);
+    assert_eq!(block_id_add(65530, 8, 1), 3);
+}
+
+fn rrq_rollover(rollover: u16) {
+    // More than 65535 blocks
+    let content: Vec<u8> = (0..8 * 65540 + 3).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(8),
+        window_size: Some(16),
+        rollover: Some(rollover),
+        ..Opts::default()
+    };
+
+    let (oack, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+    assert_eq!(oack.unwrap().rollover, Some(rollover));
+    assert_eq!(blocks.len(), 65541);
+    assert_eq!(blocks.concat(), content);
+}
+
+#[test]
+fn rrq_rollover_0() {
+    rrq_rollover(0);
+}
+
+#[test]
+fn rrq_rollover_1() {
+    rrq_rollover(1);
+}
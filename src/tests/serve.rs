@@ -0,0 +1,236 @@
+use futures_lite::future::block_on;
+use std::io;
+
+use crate::server::TftpServerBuilder;
+use crate::utils::{is_socket_closed, is_transient_error};
+
+use super::utils::*;
+
+#[test]
+fn closed_socket_errors() {
+    assert!(is_socket_closed(&io::ErrorKind::NotConnected.into()));
+    assert!(!is_socket_closed(&io::ErrorKind::TimedOut.into()));
+    assert!(!is_socket_closed(&io::ErrorKind::ConnectionRefused.into()));
+}
+
+#[test]
+fn transient_errors() {
+    assert!(is_transient_error(&io::ErrorKind::ConnectionRefused.into()));
+    assert!(is_transient_error(&io::ErrorKind::ConnectionReset.into()));
+    assert!(!is_transient_error(&io::ErrorKind::NotConnected.into()));
+    assert!(!is_transient_error(&io::ErrorKind::InvalidInput.into()));
+}
+
+#[cfg(unix)]
+#[test]
+fn serve_returns_ok_on_closed_socket() {
+    use std::fs::File;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let fd = socket.as_raw_fd();
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .std_socket(socket)
+            .unwrap()
+            .build(),
+    )
+    .unwrap();
+
+    // Replace the socket behind the server's back, in one step so no other
+    // thread can take its descriptor. Receiving fails with ENOTSOCK and the
+    // server closes the file when it drops.
+    let null = File::open("/dev/null").unwrap();
+    assert_eq!(unsafe { libc::dup2(null.as_raw_fd(), fd) }, fd);
+    drop(null);
+
+    assert!(block_on(tftpd.serve()).is_ok());
+}
+
+#[cfg(unix)]
+#[test]
+fn into_std_socket_round_trip() {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let fd = socket.as_raw_fd();
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .std_socket(socket)
+            .unwrap()
+            .build(),
+    )
+    .unwrap();
+
+    // Same descriptor comes back
+    let socket = tftpd.into_std_socket().unwrap();
+    assert_eq!(socket.as_raw_fd(), fd);
+    assert_eq!(socket.local_addr().unwrap(), addr);
+
+    // and it can be used by a new server.
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .std_socket(socket)
+            .unwrap()
+            .build(),
+    )
+    .unwrap();
+    assert_eq!(tftpd.listen_addr().unwrap(), addr);
+}
+
+#[cfg(unix)]
+#[test]
+fn socket_handle_after_shutdown() {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+
+    use crate::packet::Opts;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let fd = socket.as_raw_fd();
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .std_socket(socket)
+            .unwrap()
+            .build(),
+    )
+    .unwrap();
+    let handle = tftpd.handle();
+    let socket_handle = tftpd.socket_handle();
+    let thread = thread::spawn(move || block_on(tftpd.serve()));
+
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks.concat(), b"hello");
+
+    handle.shutdown();
+    thread.join().unwrap().unwrap();
+
+    // Same descriptor comes back after the transfers are done
+    let socket = block_on(socket_handle.into_std_socket()).unwrap();
+    assert_eq!(socket.as_raw_fd(), fd);
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("again"))
+            .std_socket(socket)
+            .unwrap()
+            .build(),
+    )
+    .unwrap();
+    assert_eq!(tftpd.listen_addr().unwrap(), addr);
+    thread::spawn(move || block_on(tftpd.serve()));
+
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks.concat(), b"again");
+}
+
+#[test]
+fn socket_handle_of_dropped_server() {
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .bind("127.0.0.1:0".parse().unwrap())
+            .build(),
+    )
+    .unwrap();
+    let socket_handle = tftpd.socket_handle();
+
+    drop(tftpd);
+    assert!(block_on(socket_handle.into_std_socket()).is_err());
+}
+
+// Linux routes the whole 127.0.0.0/8 to loopback, so we can use a second
+// address of the host without any setup.
+#[cfg(target_os = "linux")]
+#[test]
+fn transfers_reply_from_local_ip() {
+    use std::thread;
+
+    use crate::packet::{Opts, Packet};
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .bind("127.0.0.2:0".parse().unwrap())
+            .build(),
+    )
+    .unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    thread::spawn(move || block_on(tftpd.serve()));
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    let (_, peer) = client.recv();
+    assert_eq!(peer.ip(), addr.ip());
+
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+    let (_, peer) = client.recv();
+    assert_eq!(peer.ip(), addr.ip());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn serve_survives_refused_errors() {
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::packet::{self, Opts, Packet};
+    use crate::server::UnexpectedPacketPolicy;
+
+    // With IP_RECVERR the ICMP errors of the datagrams that the listening
+    // socket sends are reported by its next receive.
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let on: libc::c_int = 1;
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_RECVERR,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    assert_eq!(rc, 0);
+
+    let tftpd = block_on(
+        TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+            .std_socket(socket)
+            .unwrap()
+            .on_unexpected_packet(UnexpectedPacketPolicy::Error(
+                packet::Error::UnknownTransferId,
+            ))
+            .build(),
+    )
+    .unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+
+    // Client goes away before the server replies with an ERROR
+    let gone = RawClient::new();
+    gone.send(&Packet::Ack(1), addr);
+    drop(gone);
+
+    let serve = thread::spawn(move || block_on(tftpd.serve()));
+    thread::sleep(Duration::from_millis(100));
+    assert!(!serve.is_finished());
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Data(1, block)) if block == b"hello"
+    ));
+}
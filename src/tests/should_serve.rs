@@ -0,0 +1,98 @@
+use futures_lite::io::Cursor;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{Handler, TftpServerBuilder};
+
+use super::utils::*;
+
+/// Refuses window sizes above 16.
+struct WindowLimitHandler(BytesHandler);
+
+impl Handler for WindowLimitHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = BytesWriter;
+
+    async fn should_serve(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(), packet::Error> {
+        match req.opts().window_size {
+            Some(size) if size > 16 => {
+                Err(packet::Error::OptionNegotiationFailed)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn read_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        self.0.read_req_open(client, path, req).await
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        self.0.write_req_open(client, path, req, size).await
+    }
+}
+
+fn window_opts(window_size: u16) -> Opts {
+    Opts {
+        window_size: Some(window_size),
+        ..Opts::default()
+    }
+}
+
+#[test]
+fn should_serve_rejects() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+    let builder = TftpServerBuilder::with_handler(WindowLimitHandler(handler));
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    client.send_rrq("test", window_opts(32), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+    assert_eq!(opens.load(Ordering::SeqCst), 0);
+
+    let client = RawClient::new();
+    client.send(&Packet::Wrq(rw_req("test", window_opts(32))), addr);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+}
+
+#[test]
+fn should_serve_accepts() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+    let builder = TftpServerBuilder::with_handler(WindowLimitHandler(handler));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", window_opts(16)), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+    assert_eq!(opens.load(Ordering::SeqCst), 1);
+}
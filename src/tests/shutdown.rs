@@ -0,0 +1,217 @@
+use futures_lite::future::block_on;
+use std::net::SocketAddr;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::packet::{self, Opts, Packet};
+use crate::server::{ShutdownHandle, TftpServerBuilder};
+
+use super::utils::*;
+
+fn spawn_server_with_handle(
+    content: &[u8],
+) -> (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>) {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content));
+    spawn_builder_with_handle(builder)
+}
+
+fn spawn_builder_with_handle(
+    builder: TftpServerBuilder<BytesHandler>,
+) -> (SocketAddr, ShutdownHandle, JoinHandle<Result<()>>) {
+    let builder = builder.bind("127.0.0.1:0".parse().unwrap());
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    let handle = tftpd.handle();
+
+    let thread = thread::spawn(move || block_on(tftpd.serve()));
+
+    (addr, handle, thread)
+}
+
+fn wait_for_finish(thread: &JoinHandle<Result<()>>) -> bool {
+    for _ in 0..100 {
+        if thread.is_finished() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    false
+}
+
+#[test]
+fn shutdown_idle() {
+    let (_, handle, thread) = spawn_server_with_handle(b"hello");
+
+    handle.shutdown();
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_before_serve() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .bind("127.0.0.1:0".parse().unwrap());
+    let tftpd = block_on(builder.build()).unwrap();
+
+    tftpd.handle().shutdown_now();
+    block_on(tftpd.serve()).unwrap();
+}
+
+#[test]
+fn shutdown_before_queued_requests() {
+    let (tx, rx) = async_channel::unbounded();
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .bind("127.0.0.1:0".parse().unwrap())
+        .events_channel(tx);
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+
+    // Requests are ready as soon as the server starts, but they must not
+    // delay the shutdown.
+    let client = RawClient::new();
+    for _ in 0..20 {
+        client.send_rrq("test", Opts::default(), addr);
+    }
+    thread::sleep(Duration::from_millis(50));
+
+    tftpd.handle().shutdown_now();
+    block_on(tftpd.serve()).unwrap();
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn shutdown_waits_for_transfers() {
+    let content = vec![0x11; 1000];
+    let (addr, handle, thread) = spawn_server_with_handle(&content);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    handle.shutdown();
+    assert!(!wait_for_finish(&thread));
+
+    // New requests are refused.
+    let other_client = RawClient::new();
+    other_client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = other_client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::Msg(msg)))
+            if msg == "Server is shutting down"
+    ));
+
+    client.send(&Packet::Ack(1), peer);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(2, _))));
+    client.send(&Packet::Ack(2), peer);
+
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_now_cancels_transfers() {
+    let content = vec![0x11; 1000];
+    let (addr, handle, thread) = spawn_server_with_handle(&content);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    handle.shutdown_now();
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_now_during_graceful_shutdown() {
+    let content = vec![0x11; 1000];
+    let (addr, handle, thread) = spawn_server_with_handle(&content);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    client.recv();
+
+    handle.shutdown();
+    assert!(!wait_for_finish(&thread));
+
+    handle.shutdown_now();
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn single_port_shutdown_waits_for_transfers() {
+    let content = vec![0x11; 1000];
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content))
+        .bind("127.0.0.1:0".parse().unwrap())
+        .single_port_mode();
+    let tftpd = block_on(builder.build()).unwrap();
+    let addr = tftpd.listen_addr().unwrap();
+    let handle = tftpd.handle();
+    let thread = thread::spawn(move || block_on(tftpd.serve()));
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    handle.shutdown();
+    assert!(!wait_for_finish(&thread));
+
+    // ACKs still reach the transfer through the listening socket
+    client.send(&Packet::Ack(1), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(2, _))));
+    client.send(&Packet::Ack(2), addr);
+
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn requests_dropped_on_shutdown() {
+    let content = vec![0x11; 1000];
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content))
+        .drop_requests_on_shutdown();
+    let (addr, handle, thread) = spawn_builder_with_handle(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    handle.shutdown();
+    assert!(!wait_for_finish(&thread));
+
+    let other_client = RawClient::new();
+    other_client.set_timeout(Duration::from_millis(100));
+    other_client.send_rrq("test", Opts::default(), addr);
+    assert!(other_client.try_recv().is_none());
+
+    client.send(&Packet::Ack(1), peer);
+    client.recv();
+    client.send(&Packet::Ack(2), peer);
+
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn shutdown_now_while_tasks_are_saturated() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .max_tasks(1);
+    let (addr, handle, thread) = spawn_builder_with_handle(builder);
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    // The accept loop waits for a task slot for this request
+    let other_client = RawClient::new();
+    other_client.send_rrq("test", Opts::default(), addr);
+    thread::sleep(Duration::from_millis(100));
+
+    handle.shutdown_now();
+    assert!(wait_for_finish(&thread));
+    thread.join().unwrap().unwrap();
+}
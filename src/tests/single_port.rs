@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+/// Receive a packet and check that it comes from the listening port.
+fn recv_from_listen_port(client: &RawClient, addr: SocketAddr) -> Vec<u8> {
+    let (data, peer) = client.recv();
+    assert_eq!(peer, addr);
+    data
+}
+
+fn rrq_on_listen_port(client: &RawClient, addr: SocketAddr) -> Vec<u8> {
+    client.send_rrq("test", Opts::default(), addr);
+
+    let mut content = Vec::new();
+    let mut expected_block_id = 1;
+
+    loop {
+        let data = recv_from_listen_port(client, addr);
+        match Packet::decode(&data) {
+            Ok(Packet::Data(block_id, block)) => {
+                assert_eq!(block_id, expected_block_id);
+                content.extend_from_slice(block);
+                client.send(&Packet::Ack(block_id), addr);
+
+                if block.len() < 512 {
+                    return content;
+                }
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+
+        expected_block_id += 1;
+    }
+}
+
+#[test]
+fn rrq_single_port() {
+    let content: Vec<u8> = (0..1300).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .single_port_mode();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    assert_eq!(rrq_on_listen_port(&client, addr), content);
+}
+
+#[test]
+fn rrq_single_port_concurrent() {
+    let content: Vec<u8> = (0..5000).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .single_port_mode();
+    let addr = spawn_server(builder);
+
+    let clients: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || {
+                let client = RawClient::new();
+                rrq_on_listen_port(&client, addr)
+            })
+        })
+        .collect();
+
+    for client in clients {
+        assert_eq!(client.join().unwrap(), content);
+    }
+}
+
+#[test]
+fn rrq_single_port_window() {
+    let content: Vec<u8> = (0..100).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .single_port_mode();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(8),
+        window_size: Some(4),
+        ..Opts::default()
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+    assert_eq!(oack.unwrap().window_size, Some(4));
+    assert_eq!(blocks.concat(), content);
+}
+
+#[test]
+fn wrq_single_port() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler).single_port_mode();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // Large blocks need to fit in the buffer of the listening socket
+    let opts = Opts {
+        block_size: Some(8192),
+        ..Opts::default()
+    };
+    client.send(&Packet::Wrq(rw_req("test", opts)), addr);
+    expect_oack(&recv_from_listen_port(&client, addr));
+
+    let blocks: [&[u8]; 2] = [&[0x55; 8192], b"end"];
+    for (i, block) in blocks.iter().enumerate() {
+        let block_id = i as u16 + 1;
+        client.send(&Packet::Data(block_id, block), addr);
+
+        let ack = recv_from_listen_port(&client, addr);
+        assert!(
+            matches!(Packet::decode(&ack), Ok(Packet::Ack(id)) if id == block_id)
+        );
+    }
+
+    wait_for_len(&data, 8195);
+    assert_eq!(*data.lock().unwrap(), blocks.concat());
+}
+
+#[test]
+fn single_port_error_from_listen_port() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .ignore_client_timeout()
+        .strict_options()
+        .single_port_mode();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        timeout: Some(5),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let data = recv_from_listen_port(&client, addr);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+}
+
+#[test]
+fn single_port_packets_after_transfer() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .single_port_mode();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    assert_eq!(rrq_on_listen_port(&client, addr), b"hello");
+
+    // A duplicate ACK after the transfer is handled by the accept loop,
+    // which ignores it, and the next request is served.
+    client.send(&Packet::Ack(1), addr);
+    client.set_timeout(Duration::from_millis(200));
+    assert!(client.try_recv().is_none());
+    client.set_timeout(Duration::from_secs(5));
+
+    assert_eq!(rrq_on_listen_port(&client, addr), b"hello");
+}
+
+#[test]
+fn single_port_max_tasks() {
+    let content: Vec<u8> = (0..1300).map(|x| x as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .single_port_mode()
+            .max_tasks(1);
+    let addr = spawn_server(builder);
+
+    let running = RawClient::new();
+    running.send_rrq("test", Opts::default(), addr);
+    let data = recv_from_listen_port(&running, addr);
+    let block = match Packet::decode(&data) {
+        Ok(Packet::Data(1, block)) => block.to_vec(),
+        p => panic!("unexpected packet: {:?}", p),
+    };
+
+    // The accept loop does not wait for the running transfer
+    let busy = RawClient::new();
+    busy.send_rrq("test", Opts::default(), addr);
+    let data = recv_from_listen_port(&busy, addr);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::Msg(msg))) if msg == "Server is busy"
+    ));
+
+    // and keeps passing datagrams to it
+    let mut received = block;
+    let mut block_id = 1;
+
+    loop {
+        running.send(&Packet::Ack(block_id), addr);
+        if received.len() == content.len() {
+            break;
+        }
+
+        block_id += 1;
+        let data = recv_from_listen_port(&running, addr);
+        match Packet::decode(&data) {
+            Ok(Packet::Data(id, block)) if id == block_id => {
+                received.extend_from_slice(block);
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    assert_eq!(received, content);
+}
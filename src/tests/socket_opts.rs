@@ -0,0 +1,45 @@
+use futures_lite::future::block_on;
+use socket2::SockRef;
+use std::net::UdpSocket;
+use std::thread;
+
+use crate::packet::Opts;
+use crate::server::{bind_transfer_socket, TftpServerBuilder};
+
+use super::utils::*;
+
+// Within the default limits of Linux, that doubles the values for
+// bookkeeping overhead.
+const RECV_BUFFER_SIZE: usize = 96 * 1024;
+const SEND_BUFFER_SIZE: usize = 48 * 1024;
+
+fn check_buffer_sizes(socket: &UdpSocket) {
+    let socket = SockRef::from(socket);
+    assert!(socket.recv_buffer_size().unwrap() >= RECV_BUFFER_SIZE);
+    assert!(socket.send_buffer_size().unwrap() >= SEND_BUFFER_SIZE);
+}
+
+#[test]
+fn buffer_sizes() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .bind("127.0.0.1:0".parse().unwrap())
+        .recv_buffer_size(RECV_BUFFER_SIZE)
+        .send_buffer_size(SEND_BUFFER_SIZE);
+    let tftpd = block_on(builder.build()).unwrap();
+
+    check_buffer_sizes(tftpd.socket.get_ref());
+
+    let peer = "127.0.0.1:6969".parse().unwrap();
+    let socket =
+        bind_transfer_socket(tftpd.local_ip, peer, &tftpd.config).unwrap();
+    check_buffer_sizes(socket.get_ref());
+
+    // Transfers work as usual
+    let addr = tftpd.listen_addr().unwrap();
+    thread::spawn(move || block_on(tftpd.serve()));
+
+    let client = RawClient::new();
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks, vec![b"hello".to_vec()]);
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+#[test]
+fn max_tasks_backpressure() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+
+    let builder = TftpServerBuilder::with_handler(handler).max_tasks(2);
+    let addr = spawn_server(builder);
+
+    // Flood the server with requests that we never acknowledge.
+    let clients: Vec<_> = (0..10).map(|_| RawClient::new()).collect();
+    for client in &clients {
+        client.send_rrq("test", Opts::default(), addr);
+    }
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(opens.load(Ordering::SeqCst), 2);
+
+    // Finish one transfer to free a slot.
+    let finished = clients.iter().any(|client| {
+        client.set_timeout(Duration::from_millis(100));
+
+        match client.try_recv() {
+            Some((data, peer)) => {
+                assert!(matches!(
+                    Packet::decode(&data),
+                    Ok(Packet::Data(1, _))
+                ));
+                client.send(&Packet::Ack(1), peer);
+                true
+            }
+            None => false,
+        }
+    });
+    assert!(finished);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(opens.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn max_pending_tasks_rejects() {
+    let handler = BytesHandler::new("hello");
+    let opens = handler.opens();
+
+    let builder = TftpServerBuilder::with_handler(handler)
+        .max_tasks(2)
+        .max_pending_tasks(3);
+    let addr = spawn_server(builder);
+
+    // Flood the server with requests that we never acknowledge.
+    let clients: Vec<_> = (0..10).map(|_| RawClient::new()).collect();
+    for client in &clients {
+        client.set_timeout(Duration::from_millis(500));
+        client.send_rrq("test", Opts::default(), addr);
+    }
+
+    let mut running = Vec::new();
+    let mut pending = Vec::new();
+    let mut rejected = 0;
+
+    for client in &clients {
+        match client.try_recv() {
+            Some((data, peer)) => match Packet::decode(&data) {
+                Ok(Packet::Data(1, _)) => running.push((client, peer)),
+                Ok(Packet::Error(packet::Error::Msg(msg))) => {
+                    assert_eq!(msg, "Server is busy");
+                    rejected += 1;
+                }
+                p => panic!("unexpected packet: {:?}", p),
+            },
+            None => pending.push(client),
+        }
+    }
+
+    // Two transfers run, three wait and the rest are rejected.
+    assert_eq!(running.len(), 2);
+    assert_eq!(pending.len(), 3);
+    assert_eq!(rejected, 5);
+    assert_eq!(opens.load(Ordering::SeqCst), 2);
+
+    // Finish one transfer to start a pending one.
+    let (client, peer) = running[0];
+    client.send(&Packet::Ack(1), peer);
+
+    let started = pending.iter().filter(|client| client.try_recv().is_some());
+    assert_eq!(started.count(), 1);
+    assert_eq!(opens.load(Ordering::SeqCst), 3);
+}
+
+#[test]
+fn max_tasks_zero() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .max_tasks(0);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (_, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(blocks.concat(), b"hello");
+}
@@ -0,0 +1,156 @@
+#![cfg(feature = "tracing")]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+use crate::packet::Opts;
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+type Fields = HashMap<String, String>;
+
+/// Subscriber that keeps the name and the fields of every span.
+#[derive(Clone, Default)]
+struct SpanRecorder {
+    next_id: Arc<AtomicU64>,
+    spans: Arc<Mutex<HashMap<u64, (String, Fields)>>>,
+    metadata: Arc<Mutex<HashMap<u64, &'static Metadata<'static>>>>,
+}
+
+thread_local! {
+    // Spans that are entered in this thread.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+impl SpanRecorder {
+    /// Fields of the span with `name` that has `filename`.
+    fn find(&self, name: &str, filename: &str) -> Option<Fields> {
+        let spans = self.spans.lock().unwrap();
+        spans
+            .values()
+            .find(|(n, fields)| {
+                n == name
+                    && fields.get("filename").map(String::as_str)
+                        == Some(filename)
+            })
+            .map(|(_, fields)| fields.clone())
+    }
+}
+
+struct FieldVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+}
+
+impl Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut fields = Fields::new();
+        span.record(&mut FieldVisitor(&mut fields));
+
+        let name = span.metadata().name().to_string();
+        self.spans.lock().unwrap().insert(id, (name, fields));
+        self.metadata.lock().unwrap().insert(id, span.metadata());
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record) {
+        if let Some((_, fields)) =
+            self.spans.lock().unwrap().get_mut(&span.into_u64())
+        {
+            values.record(&mut FieldVisitor(fields));
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, _span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().pop());
+    }
+
+    fn current_span(&self) -> Current {
+        let id = match ENTERED.with(|entered| entered.borrow().last().copied())
+        {
+            Some(id) => id,
+            None => return Current::none(),
+        };
+
+        match self.metadata.lock().unwrap().get(&id) {
+            Some(metadata) => Current::new(Id::from_u64(id), metadata),
+            None => Current::none(),
+        }
+    }
+}
+
+/// Wait until the span with `name` and `filename` has `window_size`.
+fn wait_for_span(
+    recorder: &SpanRecorder,
+    name: &str,
+    filename: &str,
+) -> Fields {
+    for _ in 0..100 {
+        match recorder.find(name, filename) {
+            Some(fields) if fields.contains_key("window_size") => {
+                return fields
+            }
+            _ => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    panic!("no {} span of {}", name, filename);
+}
+
+#[test]
+fn transfer_spans() {
+    let recorder = SpanRecorder::default();
+    tracing::subscriber::set_global_default(recorder.clone()).unwrap();
+
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    let opts = Opts {
+        block_size: Some(1024),
+        window_size: Some(4),
+        ..Opts::default()
+    };
+    rrq_transfer(&client, rw_req("span_rrq", opts), addr);
+
+    let fields = wait_for_span(&recorder, "RRQ", "span_rrq");
+    assert_eq!(fields["peer"], client.socket.local_addr().unwrap().to_string());
+    assert_eq!(fields["block_size"], "1024");
+    assert_eq!(fields["window_size"], "4");
+
+    let client = RawClient::new();
+    wrq_transfer(&client, rw_req("span_wrq", Opts::default()), addr, &[b"up"]);
+
+    let fields = wait_for_span(&recorder, "WRQ", "span_wrq");
+    assert_eq!(fields["block_size"], "512");
+    assert_eq!(fields["window_size"], "1");
+}
@@ -0,0 +1,472 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{TftpServerBuilder, TransferError};
+
+use super::utils::*;
+
+#[test]
+fn rrq_transfer_done() {
+    let handler = BytesHandler::new(vec![0x11; 1000]);
+    let results = handler.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.bytes, 1000);
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.retransmits, 0);
+}
+
+#[test]
+fn wrq_transfer_done() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let block = vec![0x11; 512];
+    wrq_transfer(
+        &client,
+        rw_req("test", Opts::default()),
+        addr,
+        &[&block, b"hello"],
+    );
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.bytes, 517);
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.retransmits, 0);
+}
+
+#[test]
+fn rrq_negotiated_opts() {
+    let handler = BytesHandler::new(vec![0x11; 1000]);
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler).window_size_limit(4);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(100),
+        window_size: Some(8),
+        timeout: Some(2),
+        ..Opts::default()
+    };
+    rrq_transfer(&client, rw_req("test", opts), addr);
+
+    // Window size is limited by the server
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.block_size, 100);
+    assert_eq!(stats.window_size, 4);
+    assert_eq!(stats.timeout, Duration::from_secs(2));
+    assert_eq!(stats.blocks, 11);
+}
+
+#[test]
+fn wrq_negotiated_opts() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(700));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        window_size: Some(8),
+        ..Opts::default()
+    };
+    wrq_transfer(&client, rw_req("test", opts), addr, &[b"hello"]);
+
+    // Server timeout is used when client does not request one
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.block_size, 1024);
+    assert_eq!(stats.window_size, 8);
+    assert_eq!(stats.timeout, Duration::from_millis(700));
+}
+
+#[test]
+fn rrq_idle_timeout() {
+    let handler = BytesHandler::new(vec![0x11; 2000]);
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(100))
+        .idle_timeout(Duration::from_millis(300));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let now = Instant::now();
+    client.send_rrq("test", Opts::default(), addr);
+
+    // Client stalls after the first block
+    let (_, peer) = client.recv();
+    client.send(&Packet::Ack(1), peer);
+
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Timeout)));
+    let elapsed = now.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(2));
+}
+
+#[test]
+fn wrq_idle_timeout() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(100))
+        .idle_timeout(Duration::from_millis(300));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let now = Instant::now();
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Timeout)));
+    let elapsed = now.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(2));
+}
+
+#[test]
+fn rrq_retransmits() {
+    let content: Vec<u8> = (0..40).collect();
+    let handler = BytesHandler::new(&content[..]);
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(100));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(8),
+        window_size: Some(4),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send(&Packet::Ack(0), peer);
+
+    // Drop the first window
+    for _ in 0..4 {
+        client.recv();
+    }
+
+    let mut last_block_id = 0;
+    while last_block_id != 6 {
+        let (data, _) = client.recv();
+        match Packet::decode(&data) {
+            Ok(Packet::Data(block_id, _)) => last_block_id = block_id,
+            p => panic!("unexpected packet: {:?}", p),
+        }
+        if last_block_id % 4 == 0 || last_block_id == 6 {
+            client.send(&Packet::Ack(last_block_id), peer);
+        }
+    }
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.blocks, 6);
+    assert_eq!(stats.retransmits, 4);
+    assert!(stats.duration >= Duration::from_millis(100));
+}
+
+#[test]
+fn wrq_retransmits() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(100));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    // Drop the first ACK
+    let (first, peer) = client.recv();
+    let (second, _) = client.recv();
+    assert_eq!(first, second);
+
+    client.send(&Packet::Data(1, b"hello"), peer);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(1))));
+
+    let stats = wait_for_result(&results).unwrap();
+    assert_eq!(stats.retransmits, 1);
+}
+
+#[test]
+fn rrq_client_aborted() {
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let aborts = handler.aborts();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let opts = Opts {
+        block_size: Some(1024),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+
+    let error = packet::Error::OptionNegotiationFailed;
+    client.send(&Packet::Error(error), peer);
+
+    assert!(matches!(
+        wait_for_result(&results),
+        Err(TransferError::ClientAborted(
+            packet::Error::OptionNegotiationFailed
+        ))
+    ));
+    assert!(matches!(
+        aborts.lock().unwrap()[..],
+        [packet::Error::OptionNegotiationFailed]
+    ));
+
+    // Errors are never answered.
+    client.set_timeout(Duration::from_millis(100));
+    assert!(client.try_recv().is_none());
+}
+
+#[test]
+fn rrq_size_probe() {
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let aborts = handler.aborts();
+    let probes = handler.probes();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let opts = Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts.clone(), addr);
+
+    let (data, peer) = client.recv();
+    assert_eq!(expect_oack(&data).transfer_size, Some(5));
+
+    // Client got the size and aborts
+    client.send(&Packet::Error(packet::Error::Msg("probe".into())), peer);
+
+    for _ in 0..100 {
+        if !probes.lock().unwrap().is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(*probes.lock().unwrap(), [rw_req("test", opts)]);
+    assert!(aborts.lock().unwrap().is_empty());
+    assert!(results.lock().unwrap().is_empty());
+}
+
+#[test]
+fn rrq_aborted_after_data_is_not_probe() {
+    let handler = BytesHandler::new(vec![0x55; 1000]);
+    let results = handler.results();
+    let probes = handler.probes();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let opts = Opts {
+        transfer_size: Some(0),
+        ..Opts::default()
+    };
+    client.send_rrq("test", opts, addr);
+
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send(&Packet::Ack(0), peer);
+    client.recv();
+    client.send(&Packet::Error(packet::Error::DiskFull), peer);
+
+    assert!(matches!(
+        wait_for_result(&results),
+        Err(TransferError::ClientAborted(packet::Error::DiskFull))
+    ));
+    assert!(probes.lock().unwrap().is_empty());
+}
+
+#[test]
+fn wrq_client_aborted() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let aborts = handler.aborts();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(0))));
+
+    client.send(&Packet::Error(packet::Error::DiskFull), peer);
+
+    assert!(matches!(
+        wait_for_result(&results),
+        Err(TransferError::ClientAborted(packet::Error::DiskFull))
+    ));
+    assert!(matches!(aborts.lock().unwrap()[..], [packet::Error::DiskFull]));
+}
+
+#[test]
+fn wrq_client_aborted_mid_upload() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_secs(5));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(0))));
+
+    for block_id in 1..=2 {
+        client.send(&Packet::Data(block_id, &[0x11; 512]), peer);
+        let (data, _) = client.recv();
+        assert!(
+            matches!(Packet::decode(&data), Ok(Packet::Ack(id)) if id == block_id)
+        );
+    }
+
+    let now = Instant::now();
+    client.send(&Packet::Error(packet::Error::DiskFull), peer);
+
+    assert!(matches!(
+        wait_for_result(&results),
+        Err(TransferError::ClientAborted(packet::Error::DiskFull))
+    ));
+    assert!(now.elapsed() < Duration::from_secs(1));
+
+    // Errors are never answered.
+    client.set_timeout(Duration::from_millis(100));
+    assert!(client.try_recv().is_none());
+}
+
+fn client_errors() -> Vec<packet::Error> {
+    vec![
+        packet::Error::Msg("aborted by user".to_string()),
+        packet::Error::FileNotFound,
+        packet::Error::PermissionDenied,
+        packet::Error::DiskFull,
+        packet::Error::IllegalOperation,
+        packet::Error::UnknownTransferId,
+        packet::Error::FileAlreadyExists,
+        packet::Error::NoSuchUser,
+        packet::Error::OptionNegotiationFailed,
+    ]
+}
+
+#[test]
+fn rrq_any_client_error_aborts() {
+    for error in client_errors() {
+        let handler = BytesHandler::new(vec![0x11; 1000]);
+        let results = handler.results();
+        let aborts = handler.aborts();
+        let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+        let client = RawClient::new();
+
+        client.send_rrq("test", Opts::default(), addr);
+        let (data, peer) = client.recv();
+        assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+        client.send(&Packet::Error(error.clone()), peer);
+
+        match wait_for_result(&results) {
+            Err(TransferError::ClientAborted(e)) => assert_eq!(e, error),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(aborts.lock().unwrap()[..], [error]);
+    }
+}
+
+#[test]
+fn wrq_any_client_error_aborts() {
+    for error in client_errors() {
+        let handler = BytesHandler::new("");
+        let results = handler.results();
+        let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+        let client = RawClient::new();
+
+        client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+        let (data, peer) = client.recv();
+        assert!(matches!(Packet::decode(&data), Ok(Packet::Ack(0))));
+
+        client.send(&Packet::Error(error.clone()), peer);
+
+        match wait_for_result(&results) {
+            Err(TransferError::ClientAborted(e)) => assert_eq!(e, error),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
+
+#[test]
+fn rrq_timeout() {
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let aborts = handler.aborts();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(50))
+        .max_send_retries(1);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Timeout)));
+    assert!(aborts.lock().unwrap().is_empty());
+}
+
+fn block_size_512() -> Opts {
+    Opts {
+        block_size: Some(512),
+        ..Opts::default()
+    }
+}
+
+#[test]
+fn rrq_init_failed() {
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler).block_size_min(1024);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // Handler opened the file before the options are rejected.
+    client.send_rrq("test", block_size_512(), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Io(_))));
+}
+
+#[test]
+fn wrq_init_failed() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler).block_size_min(1024);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", block_size_512())), addr);
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::OptionNegotiationFailed))
+    ));
+
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Io(_))));
+}
@@ -0,0 +1,215 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::{TftpServerBuilder, TransferError, UnexpectedPacketPolicy};
+
+use super::utils::*;
+
+#[test]
+fn data_on_listen_port_ignored() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    client.set_timeout(Duration::from_millis(500));
+
+    client.send(&Packet::Data(1, b"data"), addr);
+    assert!(client.try_recv().is_none());
+}
+
+#[test]
+fn data_on_listen_port_error() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .on_unexpected_packet(UnexpectedPacketPolicy::Error(
+            packet::Error::Msg("unexpected packet type".to_string()),
+        ));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Data(1, b"data"), addr);
+    let (data, peer) = client.recv();
+    assert_eq!(peer, addr);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::Msg(ref msg)))
+            if msg == "unexpected packet type"
+    ));
+
+    // Errors are never answered.
+    client.set_timeout(Duration::from_millis(500));
+    client.send(&Packet::Error(packet::Error::UnknownError), addr);
+    assert!(client.try_recv().is_none());
+}
+
+#[test]
+fn unexpected_packet_replies_limited() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .on_unexpected_packet(UnexpectedPacketPolicy::Error(
+            packet::Error::UnknownTransferId,
+        ));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    for _ in 0..200 {
+        client.send(&Packet::Ack(1), addr);
+    }
+
+    client.set_timeout(Duration::from_millis(500));
+    let mut replies = 0;
+    while client.try_recv().is_some() {
+        replies += 1;
+    }
+
+    // Only the burst of the rate limiter is answered.
+    assert!(replies > 0);
+    assert!(replies < 50, "{} replies", replies);
+}
+
+#[test]
+fn ack_on_listen_port_unknown_tid() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .on_unexpected_packet(UnexpectedPacketPolicy::Error(
+            packet::Error::UnknownTransferId,
+        ));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Ack(3), addr);
+    let (data, peer) = client.recv();
+    assert_eq!(peer, addr);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::UnknownTransferId))
+    ));
+}
+
+#[test]
+fn rrq_packets_from_other_address_ignored() {
+    let content = vec![0x11; 1000];
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content))
+        .timeout(Duration::from_millis(200));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    let intruder = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    // Neither the ACK nor the ERROR reach the transfer
+    intruder.send(&Packet::Ack(1), peer);
+    intruder.send(&Packet::Error(packet::Error::UnknownError), peer);
+
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    client.send(&Packet::Ack(1), peer);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(2, _))));
+    client.send(&Packet::Ack(2), peer);
+
+    intruder.set_timeout(Duration::from_millis(200));
+    assert!(intruder.try_recv().is_none());
+}
+
+#[test]
+fn wrq_packets_from_other_address_ignored() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+    let intruder = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+    let (ack, peer) = client.recv();
+    assert!(matches!(Packet::decode(&ack), Ok(Packet::Ack(0))));
+
+    intruder.send(&Packet::Data(1, b"evil"), peer);
+    client.send(&Packet::Data(1, b"hello"), peer);
+
+    let (ack, _) = client.recv();
+    assert!(matches!(Packet::decode(&ack), Ok(Packet::Ack(1))));
+
+    wait_for_len(&data, 5);
+    assert_eq!(&data.lock().unwrap()[..], b"hello");
+}
+
+#[test]
+fn closed_client_port_retried() {
+    let handler = BytesHandler::new("hello");
+    let results = handler.results();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(50))
+        .max_send_retries(3);
+    let addr = spawn_server(builder);
+
+    let client = RawClient::new();
+    client.send_rrq("test", Opts::default(), addr);
+    client.recv();
+
+    // Datagrams to the closed port are refused, which is handled as a
+    // lost ACK.
+    drop(client);
+    assert!(matches!(wait_for_result(&results), Err(TransferError::Timeout)));
+}
+
+fn expect_unknown_tid(client: &RawClient, transfer: SocketAddr) {
+    let (data, peer) = client.recv();
+    assert_eq!(peer, transfer);
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::UnknownTransferId))
+    ));
+}
+
+#[test]
+fn rrq_reply_unknown_tid() {
+    let content = vec![0x11; 1000];
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(content))
+        .reply_unknown_tid();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    let intruder = RawClient::new();
+
+    client.send_rrq("test", Opts::default(), addr);
+    let (data, peer) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(1, _))));
+
+    intruder.send(&Packet::Ack(1), peer);
+    expect_unknown_tid(&intruder, peer);
+
+    // Errors are never answered
+    intruder.set_timeout(Duration::from_millis(200));
+    intruder.send(&Packet::Error(packet::Error::UnknownError), peer);
+    assert!(intruder.try_recv().is_none());
+
+    // The transfer continues
+    client.send(&Packet::Ack(1), peer);
+    let (data, _) = client.recv();
+    assert!(matches!(Packet::decode(&data), Ok(Packet::Data(2, _))));
+    client.send(&Packet::Ack(2), peer);
+}
+
+#[test]
+fn wrq_reply_unknown_tid() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler).reply_unknown_tid();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    let intruder = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+    let (ack, peer) = client.recv();
+    assert!(matches!(Packet::decode(&ack), Ok(Packet::Ack(0))));
+
+    intruder.send(&Packet::Data(1, b"evil"), peer);
+    expect_unknown_tid(&intruder, peer);
+
+    client.send(&Packet::Data(1, b"hello"), peer);
+    let (ack, _) = client.recv();
+    assert!(matches!(Packet::decode(&ack), Ok(Packet::Ack(1))));
+
+    wait_for_len(&data, 5);
+    assert_eq!(&data.lock().unwrap()[..], b"hello");
+}
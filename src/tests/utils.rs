@@ -0,0 +1,361 @@
+use futures_lite::future::block_on;
+use futures_lite::io::Cursor;
+use futures_lite::AsyncWrite;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use crate::packet::{self, Mode, Opts, Packet, RwReq};
+use crate::server::{Handler, TftpServerBuilder, TransferError, TransferStats};
+use crate::utils::block_id_add;
+
+pub type TransferResults =
+    Arc<Mutex<Vec<Result<TransferStats, TransferError>>>>;
+
+/// Handler that keeps a single in-memory file. Every read request is
+/// served with its content and every write request replaces it.
+pub struct BytesHandler {
+    data: Arc<Mutex<Vec<u8>>>,
+    opens: Arc<AtomicUsize>,
+    results: TransferResults,
+    aborts: Arc<Mutex<Vec<packet::Error>>>,
+    probes: Arc<Mutex<Vec<RwReq>>>,
+}
+
+impl BytesHandler {
+    pub fn new(data: impl Into<Vec<u8>>) -> Self {
+        BytesHandler {
+            data: Arc::new(Mutex::new(data.into())),
+            opens: Arc::new(AtomicUsize::new(0)),
+            results: Arc::new(Mutex::new(Vec::new())),
+            aborts: Arc::new(Mutex::new(Vec::new())),
+            probes: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Counter of `read_req_open` calls.
+    pub fn opens(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.opens)
+    }
+
+    /// Shared content of the file.
+    pub fn data(&self) -> Arc<Mutex<Vec<u8>>> {
+        Arc::clone(&self.data)
+    }
+
+    /// Results that were passed to `transfer_done`.
+    pub fn results(&self) -> TransferResults {
+        Arc::clone(&self.results)
+    }
+
+    /// Errors that were passed to `client_aborted`.
+    pub fn aborts(&self) -> Arc<Mutex<Vec<packet::Error>>> {
+        Arc::clone(&self.aborts)
+    }
+
+    /// Requests that were passed to `size_probe`.
+    pub fn probes(&self) -> Arc<Mutex<Vec<RwReq>>> {
+        Arc::clone(&self.probes)
+    }
+}
+
+impl Handler for BytesHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = BytesWriter;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        self.opens.fetch_add(1, Ordering::SeqCst);
+        let data = self.data.lock().unwrap().clone();
+        let size = data.len() as u64;
+        Ok((Cursor::new(data), Some(size)))
+    }
+
+    async fn write_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+        _size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        self.data.lock().unwrap().clear();
+        Ok(BytesWriter(Arc::clone(&self.data)))
+    }
+
+    async fn client_aborted(
+        &mut self,
+        _client: &SocketAddr,
+        error: &packet::Error,
+    ) {
+        self.aborts.lock().unwrap().push(error.clone());
+    }
+
+    async fn size_probe(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        req: &packet::RwReq,
+    ) {
+        self.probes.lock().unwrap().push(req.clone());
+    }
+
+    async fn transfer_done(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        result: Result<TransferStats, TransferError>,
+    ) {
+        self.results.lock().unwrap().push(result);
+    }
+}
+
+/// Wait until `transfer_done` is called and return its result.
+pub fn wait_for_result(
+    results: &TransferResults,
+) -> Result<TransferStats, TransferError> {
+    for _ in 0..500 {
+        if let Some(result) = results.lock().unwrap().pop() {
+            return result;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("transfer_done was not called");
+}
+
+/// Wait until the shared buffer reaches `len` bytes.
+///
+/// Server acknowledges a block before it writes it, so the last block
+/// may reach the handler after the client is done.
+pub fn wait_for_len(data: &Mutex<Vec<u8>>, len: usize) {
+    for _ in 0..100 {
+        if data.lock().unwrap().len() >= len {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Writer that appends to a shared buffer.
+pub struct BytesWriter(Arc<Mutex<Vec<u8>>>);
+
+impl AsyncWrite for BytesWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _cx: &mut Context,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Build server on a random loopback port and serve it in a new thread.
+pub fn spawn_server<H>(builder: TftpServerBuilder<H>) -> SocketAddr
+where
+    H: Handler + 'static,
+{
+    spawn_server_at(builder, "127.0.0.1:0".parse().unwrap())
+}
+
+/// Build server on `addr` and serve it in a new thread.
+pub fn spawn_server_at<H>(
+    builder: TftpServerBuilder<H>,
+    addr: SocketAddr,
+) -> SocketAddr
+where
+    H: Handler + 'static,
+{
+    let tftpd =
+        block_on(builder.bind(addr).build()).expect("failed to build server");
+    let addr = tftpd.listen_addr().unwrap();
+
+    thread::spawn(move || block_on(tftpd.serve()));
+
+    addr
+}
+
+/// Minimal blocking TFTP client that speaks raw packets.
+pub struct RawClient {
+    pub socket: UdpSocket,
+}
+
+impl RawClient {
+    pub fn new() -> Self {
+        RawClient::bind("127.0.0.1:0")
+    }
+
+    pub fn bind(addr: &str) -> Self {
+        let socket = UdpSocket::bind(addr).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        RawClient {
+            socket,
+        }
+    }
+
+    pub fn send(&self, packet: &Packet, addr: SocketAddr) {
+        self.send_raw(&packet.to_bytes(), addr);
+    }
+
+    pub fn send_raw(&self, data: &[u8], addr: SocketAddr) {
+        self.socket.send_to(data, addr).unwrap();
+    }
+
+    pub fn send_rrq(&self, filename: &str, opts: Opts, addr: SocketAddr) {
+        self.send(&Packet::Rrq(rw_req(filename, opts)), addr);
+    }
+
+    /// Receive a datagram. Panics on timeout.
+    pub fn recv(&self) -> (Vec<u8>, SocketAddr) {
+        self.try_recv().expect("timed out waiting for a packet")
+    }
+
+    /// Receive a datagram, returns `None` on timeout.
+    pub fn try_recv(&self) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = vec![0u8; 65536];
+        let (len, peer) = self.socket.recv_from(&mut buf).ok()?;
+        buf.truncate(len);
+        Some((buf, peer))
+    }
+
+    pub fn set_timeout(&self, dur: Duration) {
+        self.socket.set_read_timeout(Some(dur)).unwrap();
+    }
+}
+
+/// Run a read request to the end and return the OACK options (if any)
+/// and the payloads of all received DATA packets.
+///
+/// Data are acknowledged as RFC 7440 clients do, i.e. once per window.
+pub fn rrq_transfer(
+    client: &RawClient,
+    req: RwReq,
+    addr: SocketAddr,
+) -> (Option<Opts>, Vec<Vec<u8>>) {
+    client.send(&Packet::Rrq(req), addr);
+
+    let mut oack = None;
+    let mut block_size = 512;
+    let mut window_size = 1;
+    let mut rollover = 0;
+    let mut blocks = Vec::new();
+    let mut last_block_id: u16 = 0;
+
+    loop {
+        let (data, peer) = client.recv();
+
+        match Packet::decode(&data) {
+            Ok(Packet::OAck(opts)) => {
+                block_size = opts.block_size.map(usize::from).unwrap_or(512);
+                window_size = opts.window_size.map(usize::from).unwrap_or(1);
+                rollover = opts.rollover.unwrap_or(0);
+                oack = Some(opts);
+                client.send(&Packet::Ack(0), peer);
+            }
+            Ok(Packet::Data(block_id, payload)) => {
+                // Ignore retransmitted blocks
+                if block_id != block_id_add(last_block_id, 1, rollover) {
+                    continue;
+                }
+
+                let is_last_block = payload.len() < block_size;
+                blocks.push(payload.to_vec());
+                last_block_id = block_id;
+
+                if is_last_block || blocks.len() % window_size == 0 {
+                    client.send(&Packet::Ack(block_id), peer);
+                }
+
+                if is_last_block {
+                    break;
+                }
+            }
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    (oack, blocks)
+}
+
+/// Run a write request that uploads `blocks` and return the OACK
+/// options (if any).
+///
+/// Blocks are sent one window at a time and the server is expected to
+/// acknowledge the last block of each window.
+///
+/// The last block must be shorter than the negotiated block size.
+pub fn wrq_transfer(
+    client: &RawClient,
+    req: RwReq,
+    addr: SocketAddr,
+    blocks: &[&[u8]],
+) -> Option<Opts> {
+    client.send(&Packet::Wrq(req), addr);
+
+    let (data, peer) = client.recv();
+    let oack = match Packet::decode(&data) {
+        Ok(Packet::OAck(opts)) => Some(opts),
+        Ok(Packet::Ack(0)) => None,
+        p => panic!("unexpected packet: {:?}", p),
+    };
+
+    let window_size =
+        oack.as_ref().and_then(|o| o.window_size).map(usize::from).unwrap_or(1);
+
+    for (i, window) in blocks.chunks(window_size).enumerate() {
+        let first_id = i * window_size + 1;
+
+        for (j, block) in window.iter().enumerate() {
+            let block_id = (first_id + j) as u16;
+            client.send(&Packet::Data(block_id, block), peer);
+        }
+
+        let last_id = (first_id + window.len() - 1) as u16;
+        let (data, _) = client.recv();
+        match Packet::decode(&data) {
+            Ok(Packet::Ack(id)) if id == last_id => {}
+            p => panic!("unexpected packet: {:?}", p),
+        }
+    }
+
+    oack
+}
+
+pub fn rw_req(filename: &str, opts: Opts) -> RwReq {
+    RwReq {
+        filename: filename.to_string(),
+        mode: Mode::Octet,
+        opts,
+    }
+}
+
+/// Expect an OACK and return its options.
+pub fn expect_oack(data: &[u8]) -> Opts {
+    match Packet::decode(data) {
+        Ok(Packet::OAck(opts)) => opts,
+        p => panic!("expected OACK, got: {:?}", p),
+    }
+}
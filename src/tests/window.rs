@@ -0,0 +1,391 @@
+use std::time::{Duration, Instant};
+
+use crate::packet::{Opts, Packet};
+use crate::server::TftpServerBuilder;
+
+use super::utils::*;
+
+fn window_opts(block_size: u16, window_size: u16) -> Opts {
+    Opts {
+        block_size: Some(block_size),
+        window_size: Some(window_size),
+        ..Opts::default()
+    }
+}
+
+fn expect_data(data: &[u8]) -> (u16, Vec<u8>) {
+    match Packet::decode(data) {
+        Ok(Packet::Data(block_id, payload)) => (block_id, payload.to_vec()),
+        p => panic!("expected DATA, got: {:?}", p),
+    }
+}
+
+#[test]
+fn rrq_window_transfer() {
+    let content: Vec<u8> = (0..100).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("test", window_opts(8, 4)), addr);
+    let oack = oack.unwrap();
+    assert_eq!(oack.window_size, Some(4));
+    assert_eq!(oack.block_size, Some(8));
+    assert_eq!(blocks.len(), 13);
+    assert_eq!(blocks.concat(), content);
+}
+
+#[test]
+fn rrq_window_sent_without_acks() {
+    let content: Vec<u8> = (0..40).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .timeout(Duration::from_millis(300));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", window_opts(8, 4), addr);
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send(&Packet::Ack(0), peer);
+
+    // Whole window is sent at once
+    for i in 1..=4 {
+        let (block_id, payload) = expect_data(&client.recv().0);
+        assert_eq!(block_id, i);
+        assert_eq!(payload, &content[(i as usize - 1) * 8..][..8]);
+    }
+
+    // Client acknowledges only the first two blocks, so the window
+    // slides by two.
+    client.send(&Packet::Ack(2), peer);
+    for i in 3..=5 {
+        let (block_id, _) = expect_data(&client.recv().0);
+        assert_eq!(block_id, i);
+    }
+
+    // Last block is empty since the file size is a multiple of 8.
+    let (block_id, payload) = expect_data(&client.recv().0);
+    assert_eq!(block_id, 6);
+    assert!(payload.is_empty());
+}
+
+#[test]
+fn rrq_window_size_limit() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .window_size_limit(8);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", window_opts(512, 16)), addr);
+    assert_eq!(oack.unwrap().window_size, Some(8));
+}
+
+#[test]
+fn rrq_ignore_client_window_size() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .ignore_client_window_size();
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", window_opts(512, 16)), addr);
+    assert_eq!(oack.unwrap().window_size, None);
+}
+
+#[test]
+fn rrq_default_window_size() {
+    let content: Vec<u8> = (0..100).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .default_window_size(4);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    // OACK is sent even though the client requested no options
+    let (oack, blocks) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(oack.unwrap().window_size, Some(4));
+    assert_eq!(blocks.concat(), content);
+
+    // Requested window size is preferred
+    let client = RawClient::new();
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", window_opts(512, 2)), addr);
+    assert_eq!(oack.unwrap().window_size, Some(2));
+}
+
+#[test]
+fn rrq_default_window_size_limit() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .default_window_size(16)
+        .window_size_limit(8);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let (oack, _) =
+        rrq_transfer(&client, rw_req("test", Opts::default()), addr);
+    assert_eq!(oack.unwrap().window_size, Some(8));
+}
+
+#[test]
+fn wrq_default_window_size_ignored() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(""))
+        .default_window_size(4);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let req = rw_req("test", Opts::default());
+    let oack = wrq_transfer(&client, req, addr, &[b"hello"]);
+    assert!(oack.is_none());
+}
+
+#[test]
+fn rrq_window_duplicate_ack_retransmit() {
+    let content: Vec<u8> = (0..40).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]))
+            .timeout(Duration::from_secs(5));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", window_opts(8, 4), addr);
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send(&Packet::Ack(0), peer);
+
+    for i in 1..=4 {
+        assert_eq!(expect_data(&client.recv().0).0, i);
+    }
+
+    // Window is lost, client repeats the ACK of the previous block
+    let now = Instant::now();
+    client.send(&Packet::Ack(0), peer);
+
+    for i in 1..=4 {
+        assert_eq!(expect_data(&client.recv().0).0, i);
+    }
+    assert!(now.elapsed() < Duration::from_secs(1));
+
+    client.send(&Packet::Ack(4), peer);
+    assert_eq!(expect_data(&client.recv().0).0, 5);
+}
+
+#[test]
+fn rrq_lockstep_duplicate_ack_ignored() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new("hello"))
+        .timeout(Duration::from_secs(5));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send_rrq("test", window_opts(8, 1), addr);
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+    client.send(&Packet::Ack(0), peer);
+    assert_eq!(expect_data(&client.recv().0).0, 1);
+
+    // Duplicate ACKs of a lock-step transfer are not answered
+    client.send(&Packet::Ack(0), peer);
+    client.set_timeout(Duration::from_millis(500));
+    assert!(client.try_recv().is_none());
+}
+
+fn expect_ack(data: &[u8]) -> u16 {
+    match Packet::decode(data) {
+        Ok(Packet::Ack(block_id)) => block_id,
+        p => panic!("expected ACK, got: {:?}", p),
+    }
+}
+
+#[test]
+fn wrq_window_transfer() {
+    let content: Vec<u8> = (0..100).collect();
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let blocks: Vec<&[u8]> = content.chunks(8).collect();
+    let oack =
+        wrq_transfer(&client, rw_req("test", window_opts(8, 4)), addr, &blocks);
+    assert_eq!(oack.unwrap().window_size, Some(4));
+
+    wait_for_len(&data, content.len());
+    assert_eq!(*data.lock().unwrap(), content);
+}
+
+#[test]
+fn wrq_window_size_limit() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(""))
+        .window_size_limit(2);
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let blocks: [&[u8]; 3] = [b"01234567", b"89abcdef", b"end"];
+    let oack =
+        wrq_transfer(&client, rw_req("test", window_opts(8, 8)), addr, &blocks);
+    assert_eq!(oack.unwrap().window_size, Some(2));
+}
+
+#[test]
+fn wrq_window_lost_block() {
+    let content: Vec<u8> = (0..40).collect();
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_secs(5));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let req = rw_req("test", window_opts(8, 4));
+    client.send(&Packet::Wrq(req), addr);
+    let (oack, peer) = client.recv();
+    expect_oack(&oack);
+
+    let blocks: Vec<&[u8]> = content.chunks(8).collect();
+
+    // Block 2 is lost, server acknowledges the last block in sequence
+    let now = Instant::now();
+    for (i, block) in blocks[..4].iter().enumerate() {
+        if i != 1 {
+            client.send(&Packet::Data(i as u16 + 1, block), peer);
+        }
+    }
+    assert_eq!(expect_ack(&client.recv().0), 1);
+    assert!(now.elapsed() < Duration::from_secs(1));
+
+    // Rest of the window does not trigger more ACKs
+    client.set_timeout(Duration::from_millis(300));
+    assert!(client.try_recv().is_none());
+    client.set_timeout(Duration::from_secs(5));
+
+    // Client continues after the acknowledged block
+    for (i, block) in blocks[1..].iter().enumerate() {
+        client.send(&Packet::Data(i as u16 + 2, block), peer);
+    }
+    assert_eq!(expect_ack(&client.recv().0), 5);
+
+    // Empty last block
+    client.send(&Packet::Data(6, b""), peer);
+    assert_eq!(expect_ack(&client.recv().0), 6);
+
+    wait_for_len(&data, content.len());
+    assert_eq!(*data.lock().unwrap(), content);
+}
+
+#[test]
+fn wrq_window_timeout_acks_last_block() {
+    let builder = TftpServerBuilder::with_handler(BytesHandler::new(""))
+        .timeout(Duration::from_millis(200));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let req = rw_req("test", window_opts(8, 4));
+    client.send(&Packet::Wrq(req), addr);
+    let (oack, peer) = client.recv();
+    expect_oack(&oack);
+
+    // End of the window is lost
+    client.send(&Packet::Data(1, b"01234567"), peer);
+    client.send(&Packet::Data(2, b"01234567"), peer);
+    assert_eq!(expect_ack(&client.recv().0), 2);
+
+    client.send(&Packet::Data(3, b"end"), peer);
+    assert_eq!(expect_ack(&client.recv().0), 3);
+}
+
+#[test]
+fn rrq_partial_last_window() {
+    for window_size in 2..=16 {
+        // Last window has half of the blocks, the last of them is short
+        let len = 8 * (2 * window_size + window_size / 2) - 5;
+        let content: Vec<u8> = (0..len).map(|x| x as u8).collect();
+        let handler = BytesHandler::new(&content[..]);
+        let results = handler.results();
+        let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+        let client = RawClient::new();
+
+        let opts = window_opts(8, window_size as u16);
+        let (_, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+        assert_eq!(blocks.concat(), content, "window size {}", window_size);
+
+        let stats = wait_for_result(&results).unwrap();
+        assert_eq!(stats.blocks, blocks.len() as u64);
+        assert_eq!(stats.bytes, len as u64);
+    }
+}
+
+#[test]
+fn rrq_partial_last_window_acks() {
+    for window_size in 2..=16u16 {
+        // A full window and a last window of a single block
+        let content: Vec<u8> =
+            (0..8 * window_size + 3).map(|x| x as u8).collect();
+        let handler = BytesHandler::new(&content[..]);
+        let results = handler.results();
+        let builder = TftpServerBuilder::with_handler(handler)
+            .timeout(Duration::from_secs(5));
+        let addr = spawn_server(builder);
+        let client = RawClient::new();
+
+        client.send_rrq("test", window_opts(8, window_size), addr);
+        let (data, peer) = client.recv();
+        expect_oack(&data);
+        client.send(&Packet::Ack(0), peer);
+
+        for i in 1..=window_size {
+            assert_eq!(expect_data(&client.recv().0).0, i);
+        }
+        client.send(&Packet::Ack(window_size), peer);
+
+        let last_block_id = window_size + 1;
+        let (block_id, payload) = expect_data(&client.recv().0);
+        assert_eq!(block_id, last_block_id);
+        assert_eq!(payload.len(), 3);
+
+        // ACK of the end of a full window is past the last block, it does
+        // not acknowledge anything.
+        client.send(&Packet::Ack(2 * window_size), peer);
+        client.set_timeout(Duration::from_millis(200));
+        assert!(client.try_recv().is_none());
+        assert!(results.lock().unwrap().is_empty());
+
+        client.send(&Packet::Ack(last_block_id), peer);
+        let stats = wait_for_result(&results).unwrap();
+        assert_eq!(stats.blocks, u64::from(last_block_id));
+        assert_eq!(stats.bytes, content.len() as u64);
+        assert_eq!(stats.retransmits, 0);
+    }
+}
+
+fn rrq_window_across_wrap(rollover: Option<u16>) {
+    // Block id wraps after 65535 blocks of 8 bytes
+    let content: Vec<u8> = (0..8 * 65600).map(|x| (x / 8) as u8).collect();
+    let builder =
+        TftpServerBuilder::with_handler(BytesHandler::new(&content[..]));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    let opts = Opts {
+        rollover,
+        ..window_opts(8, 8)
+    };
+    let (oack, blocks) = rrq_transfer(&client, rw_req("test", opts), addr);
+    assert_eq!(oack.unwrap().rollover, rollover);
+    assert_eq!(blocks.len(), 65601);
+    assert!(blocks.concat() == content);
+}
+
+#[test]
+fn rrq_window_wrap() {
+    rrq_window_across_wrap(None);
+}
+
+#[test]
+fn rrq_window_wrap_rollover() {
+    rrq_window_across_wrap(Some(1));
+}
@@ -0,0 +1,374 @@
+use futures_lite::io::{BufWriter, Cursor};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::tempdir;
+
+use crate::packet::{self, Opts, Packet};
+use crate::server::handlers::{DirHandler, DirHandlerMode, PreallocateMode};
+use crate::server::{Handler, TftpServerBuilder, TransferError, TransferStats};
+
+use super::utils::*;
+
+fn expect_ack(data: &[u8], block_id: u16) {
+    match Packet::decode(data) {
+        Ok(Packet::Ack(id)) if id == block_id => {}
+        p => panic!("expected ACK({}), got: {:?}", block_id, p),
+    }
+}
+
+#[test]
+fn wrq_ack_retransmit_on_timeout() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_millis(100));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    // Drop the first ACK
+    let (first, _) = client.recv();
+    let (second, peer) = client.recv();
+    expect_ack(&first, 0);
+    expect_ack(&second, 0);
+
+    client.send(&Packet::Data(1, b"hello"), peer);
+    let (ack, _) = client.recv();
+    expect_ack(&ack, 1);
+
+    wait_for_len(&data, 5);
+    assert_eq!(&data.lock().unwrap()[..], b"hello");
+}
+
+#[test]
+fn wrq_ack_retransmit_on_resent_request() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    // Long timeout, so only the resent request can trigger a retransmission.
+    let builder = TftpServerBuilder::with_handler(handler)
+        .timeout(Duration::from_secs(10));
+    let addr = spawn_server(builder);
+    let client = RawClient::new();
+    let req = Packet::Wrq(rw_req("test", Opts::default()));
+
+    client.send(&req, addr);
+
+    // Drop the first ACK and send the request again
+    let (first, _) = client.recv();
+    expect_ack(&first, 0);
+    client.send(&req, addr);
+
+    client.set_timeout(Duration::from_secs(1));
+    let (second, peer) = client.recv();
+    expect_ack(&second, 0);
+
+    client.send(&Packet::Data(1, b"hello"), peer);
+    let (ack, _) = client.recv();
+    expect_ack(&ack, 1);
+
+    wait_for_len(&data, 5);
+    assert_eq!(&data.lock().unwrap()[..], b"hello");
+}
+
+fn tsize_opts(tsize: u64) -> Opts {
+    Opts {
+        transfer_size: Some(tsize),
+        ..Opts::default()
+    }
+}
+
+#[test]
+fn wrq_tsize_exact() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let oack =
+        wrq_transfer(&client, rw_req("test", tsize_opts(5)), addr, &[b"hello"]);
+    assert_eq!(oack.unwrap().transfer_size, Some(5));
+
+    wait_for_len(&data, 5);
+    assert_eq!(&data.lock().unwrap()[..], b"hello");
+}
+
+#[test]
+fn wrq_tsize_exceeded() {
+    let addr =
+        spawn_server(TftpServerBuilder::with_handler(BytesHandler::new("")));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", tsize_opts(5))), addr);
+
+    let (data, peer) = client.recv();
+    expect_oack(&data);
+
+    client.send(&Packet::Data(1, b"hello world"), peer);
+
+    let (data, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&data),
+        Ok(Packet::Error(packet::Error::DiskFull))
+    ));
+}
+
+#[test]
+fn wrq_oversized_block() {
+    let handler = BytesHandler::new("");
+    let data = handler.data();
+    let results = handler.results();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", Opts::default())), addr);
+
+    let (reply, peer) = client.recv();
+    expect_ack(&reply, 0);
+
+    // One byte more than the default block size
+    client.send(&Packet::Data(1, &[0x55; 513]), peer);
+
+    let (reply, _) = client.recv();
+    assert!(matches!(
+        Packet::decode(&reply),
+        Ok(Packet::Error(packet::Error::IllegalOperation))
+    ));
+    assert!(wait_for_result(&results).is_err());
+    assert!(data.lock().unwrap().is_empty());
+}
+
+/// Metadata of the file of a write request of `size` bytes, right after
+/// it is opened.
+fn wrq_file_metadata(
+    configure: impl FnOnce(DirHandler) -> DirHandler,
+    size: u64,
+) -> fs::Metadata {
+    let dir = tempdir().unwrap();
+    let handler =
+        DirHandler::new(dir.path(), DirHandlerMode::WriteOnly).unwrap();
+    let addr =
+        spawn_server(TftpServerBuilder::with_handler(configure(handler)));
+    let client = RawClient::new();
+
+    client.send(&Packet::Wrq(rw_req("test", tsize_opts(size))), addr);
+
+    // File is opened before the server replies
+    let (data, _) = client.recv();
+    expect_oack(&data);
+
+    fs::metadata(dir.path().join("test")).unwrap()
+}
+
+#[test]
+fn wrq_no_preallocate() {
+    assert_eq!(wrq_file_metadata(|h| h, 1000).len(), 0);
+}
+
+#[test]
+fn wrq_preallocate() {
+    assert_eq!(wrq_file_metadata(|h| h.preallocate(), 1000).len(), 1000);
+}
+
+#[cfg(unix)]
+#[test]
+fn wrq_preallocate_mode() {
+    use std::os::unix::fs::MetadataExt;
+
+    const SIZE: u64 = 1 << 20;
+
+    let meta =
+        wrq_file_metadata(|h| h.preallocate_mode(PreallocateMode::None), SIZE);
+    assert_eq!(meta.len(), 0);
+
+    let meta = wrq_file_metadata(
+        |h| h.preallocate_mode(PreallocateMode::Sparse),
+        SIZE,
+    );
+    assert_eq!(meta.len(), SIZE);
+    assert!(meta.blocks() * 512 < SIZE);
+
+    let meta =
+        wrq_file_metadata(|h| h.preallocate_mode(PreallocateMode::Full), SIZE);
+    assert_eq!(meta.len(), SIZE);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "freebsd"
+    ))]
+    assert!(meta.blocks() * 512 >= SIZE);
+}
+
+/// Handler with a writer that keeps everything in its buffer until it is
+/// flushed. It records what reached the file when the sync and the end of
+/// the transfer are reported.
+struct BufferedHandler {
+    inner: BytesHandler,
+    // Length of the file when `write_req_sync` and `transfer_done` are
+    // called.
+    lens: Arc<Mutex<Vec<usize>>>,
+}
+
+impl BufferedHandler {
+    fn file_len(&self) -> usize {
+        self.inner.data().lock().unwrap().len()
+    }
+}
+
+impl Handler for BufferedHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = BufWriter<BytesWriter>;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Err(packet::Error::IllegalOperation)
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        let writer = self.inner.write_req_open(client, path, req, size).await?;
+        Ok(BufWriter::with_capacity(64 * 1024, writer))
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        self.lens.lock().unwrap().push(self.file_len());
+        Ok(())
+    }
+
+    async fn transfer_done(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _result: Result<TransferStats, TransferError>,
+    ) {
+        self.lens.lock().unwrap().push(self.file_len());
+    }
+}
+
+#[test]
+fn wrq_flushed_before_done() {
+    let lens = Arc::new(Mutex::new(Vec::new()));
+    let handler = BufferedHandler {
+        inner: BytesHandler::new(""),
+        lens: Arc::clone(&lens),
+    };
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let blocks: [&[u8]; 3] = [&[0x55; 512], &[0x66; 512], b"end"];
+    wrq_transfer(&client, rw_req("test", Opts::default()), addr, &blocks);
+
+    for _ in 0..100 {
+        if lens.lock().unwrap().len() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Everything reached the file before the sync
+    assert_eq!(*lens.lock().unwrap(), [1027, 1027]);
+}
+
+/// Handler that fails to sync.
+struct SyncErrorHandler(BytesHandler);
+
+impl Handler for SyncErrorHandler {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = BytesWriter;
+
+    async fn read_req_open(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _req: &packet::RwReq,
+    ) -> Result<(Self::Reader, Option<u64>), packet::Error> {
+        Err(packet::Error::IllegalOperation)
+    }
+
+    async fn write_req_open(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        req: &packet::RwReq,
+        size: Option<u64>,
+    ) -> Result<Self::Writer, packet::Error> {
+        self.0.write_req_open(client, path, req, size).await
+    }
+
+    async fn write_req_sync(
+        &mut self,
+        _client: &SocketAddr,
+        _path: &Path,
+        _writer: &mut Self::Writer,
+    ) -> io::Result<()> {
+        Err(io::ErrorKind::StorageFull.into())
+    }
+
+    async fn transfer_done(
+        &mut self,
+        client: &SocketAddr,
+        path: &Path,
+        result: Result<TransferStats, TransferError>,
+    ) {
+        self.0.transfer_done(client, path, result).await
+    }
+}
+
+#[test]
+fn wrq_sync_error() {
+    let handler = BytesHandler::new("");
+    let results = handler.results();
+    let handler = SyncErrorHandler(handler);
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    wrq_transfer(&client, rw_req("test", Opts::default()), addr, &[b"end"]);
+
+    match wait_for_result(&results) {
+        Err(TransferError::Io(e)) => {
+            assert_eq!(e.kind(), io::ErrorKind::StorageFull)
+        }
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn wrq_sync_writes() {
+    let dir = tempdir().unwrap();
+    let handler = DirHandler::new(dir.path(), DirHandlerMode::WriteOnly)
+        .unwrap()
+        .sync_writes();
+    let addr = spawn_server(TftpServerBuilder::with_handler(handler));
+    let client = RawClient::new();
+
+    let blocks: [&[u8]; 2] = [&[0x55; 512], b"end"];
+    wrq_transfer(&client, rw_req("test", Opts::default()), addr, &blocks);
+
+    let path = dir.path().join("test");
+    for _ in 0..100 {
+        if fs::read(&path).unwrap() == blocks.concat() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("file was not written");
+}
@@ -1,7 +1,11 @@
-use async_io::Timer;
+use async_io::{Async, Timer};
 use futures_lite::future;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::hash_map::RandomState;
 use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::net::{SocketAddr, UdpSocket};
 use std::time::Duration;
 
 pub async fn io_timeout<T>(
@@ -14,3 +18,475 @@ pub async fn io_timeout<T>(
     })
     .await
 }
+
+/// Random delays of up to `max`, added to retransmit timeouts.
+///
+/// This is a xorshift generator, it does not need to be more than fast and
+/// different for each transfer.
+pub struct Jitter {
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    pub fn new(max: Duration) -> Self {
+        // Keys of `RandomState` are random and differ on each call.
+        let seed = RandomState::new().build_hasher().finish();
+
+        Jitter {
+            max,
+            state: seed | 1,
+        }
+    }
+
+    /// Random delay in `[0, max)`.
+    pub fn next_delay(&mut self) -> Duration {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        // Top 53 bits make a uniform f64 in [0, 1).
+        let fraction = (self.state >> 11) as f64 / (1u64 << 53) as f64;
+        self.max.mul_f64(fraction)
+    }
+}
+
+/// Add `n` to a block id.
+///
+/// After block 65535 the numbering rolls over to `rollover`, which is
+/// either 0 or 1.
+pub fn block_id_add(block_id: u16, n: u16, rollover: u16) -> u16 {
+    let sum = u32::from(block_id) + u32::from(n);
+
+    if sum > u32::from(u16::MAX) {
+        (sum - 0x10000 + u32::from(rollover)) as u16
+    } else {
+        sum as u16
+    }
+}
+
+/// Options of the UDP sockets that are bound by the server.
+///
+/// `None` leaves the default value of the OS.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOpts {
+    pub dual_stack: bool,
+    pub ttl: Option<u32>,
+    pub dscp: Option<u8>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+/// Bind a UDP socket to `addr`.
+///
+/// If `dual_stack` is set and `addr` is an IPv6 address, the socket
+/// accepts IPv4 traffic too, as IPv4-mapped IPv6 addresses.
+pub fn bind_udp(
+    addr: SocketAddr,
+    opts: &SocketOpts,
+) -> io::Result<Async<UdpSocket>> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+
+    if addr.is_ipv6() && opts.dual_stack {
+        socket.set_only_v6(false)?;
+    }
+
+    set_socket_opts(&socket, addr.is_ipv6(), opts)?;
+    socket.bind(&addr.into())?;
+    Async::new(socket.into())
+}
+
+/// Set TTL (hop limit for IPv6) and DSCP of outgoing datagrams, and the
+/// sizes of the socket buffers.
+pub fn set_socket_opts(
+    socket: &Socket,
+    ipv6: bool,
+    opts: &SocketOpts,
+) -> io::Result<()> {
+    if let Some(ttl) = opts.ttl {
+        if ipv6 {
+            socket.set_unicast_hops_v6(ttl)?;
+        } else {
+            socket.set_ttl(ttl)?;
+        }
+    }
+
+    if let Some(dscp) = opts.dscp {
+        // DSCP is the upper 6 bits of the TOS / traffic class field.
+        let tos = u32::from(dscp & 0x3f) << 2;
+
+        if ipv6 {
+            set_tclass_v6(socket, tos)?;
+        } else {
+            socket.set_tos(tos)?;
+        }
+    }
+
+    if let Some(size) = opts.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+
+    if let Some(size) = opts.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_tclass_v6(socket: &Socket, tclass: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let tclass = tclass as libc::c_int;
+
+    // SAFETY: The fd is valid while `socket` is borrowed and the option
+    // value is a `c_int`, as IPV6_TCLASS expects.
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_TCLASS,
+            &tclass as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_tclass_v6(_socket: &Socket, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP of IPv6 sockets is not supported on this platform",
+    ))
+}
+
+/// Returns the MTU that the OS knows for the route to `peer`.
+///
+/// On Linux this connects a UDP socket to `peer`, which looks up the route
+/// without sending anything, and reads `IP_MTU` (`IPV6_MTU` for IPv6). This
+/// is the MTU of the egress interface, or a lower path MTU that the kernel
+/// learned from ICMP. Returns `None` on other platforms or on errors.
+#[cfg(target_os = "linux")]
+pub fn route_mtu(peer: SocketAddr) -> Option<u32> {
+    use std::os::unix::io::AsRawFd;
+
+    let socket = Socket::new(
+        Domain::for_address(peer),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )
+    .ok()?;
+    socket.connect(&peer.into()).ok()?;
+
+    let (level, name) = match peer {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_MTU),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_MTU),
+    };
+    let mut mtu: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    // SAFETY: The fd is valid while `socket` is alive and the option value
+    // is a `c_int` of `len` bytes, as IP_MTU and IPV6_MTU expect.
+    let rc = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &mut mtu as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match rc {
+        0 => u32::try_from(mtu).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn route_mtu(_peer: SocketAddr) -> Option<u32> {
+    None
+}
+
+/// Send `packets` to `peer`, in order.
+///
+/// On Linux they are sent in batches with `sendmmsg(2)`, elsewhere with a
+/// `send_to` per packet.
+pub async fn send_packets(
+    socket: &Async<UdpSocket>,
+    packets: &[&[u8]],
+    peer: SocketAddr,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        send_packets_mmsg(socket, packets, peer).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        send_packets_each(socket, packets, peer).await
+    }
+}
+
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+async fn send_packets_each(
+    socket: &Async<UdpSocket>,
+    packets: &[&[u8]],
+    peer: SocketAddr,
+) -> io::Result<()> {
+    for packet in packets {
+        socket.send_to(packet, peer).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn send_packets_mmsg(
+    socket: &Async<UdpSocket>,
+    packets: &[&[u8]],
+    peer: SocketAddr,
+) -> io::Result<()> {
+    let peer = socket2::SockAddr::from(peer);
+    let mut sent = 0;
+
+    while sent < packets.len() {
+        sent +=
+            socket.write_with(|s| sendmmsg(s, &packets[sent..], &peer)).await?;
+    }
+
+    Ok(())
+}
+
+/// Send as many of `packets` as possible with a single syscall and
+/// return how many were sent.
+#[cfg(target_os = "linux")]
+fn sendmmsg(
+    socket: &UdpSocket,
+    packets: &[&[u8]],
+    peer: &socket2::SockAddr,
+) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut iovecs: Vec<libc::iovec> = packets
+        .iter()
+        .map(|packet| libc::iovec {
+            iov_base: packet.as_ptr() as *mut libc::c_void,
+            iov_len: packet.len(),
+        })
+        .collect();
+
+    let mut msgs: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .map(|iovec| {
+            // SAFETY: `msghdr` is a plain C struct, for which zero is a
+            // valid value.
+            let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_name = peer.as_ptr() as *mut libc::c_void;
+            hdr.msg_namelen = peer.len();
+            hdr.msg_iov = iovec;
+            hdr.msg_iovlen = 1;
+
+            libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    // SAFETY: The fd is valid while `socket` is borrowed. Headers point to
+    // `peer`, `iovecs` and `packets`, which outlive the call, and the
+    // kernel only reads them, apart from `msg_len`.
+    let rc = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            0,
+        )
+    };
+
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(rc as usize)
+    }
+}
+
+/// Returns `None` for a `ConnectionRefused` error.
+///
+/// Connected UDP sockets report ICMP errors of previous datagrams (e.g.
+/// port unreachable) on a later send or receive. Transfers are retried on
+/// them, as they are when datagrams are lost.
+pub fn ignore_refused<T>(res: io::Result<T>) -> io::Result<Option<T>> {
+    match res {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns `true` if a receive error leaves the socket usable.
+///
+/// Some systems report ICMP errors of earlier datagrams on the next
+/// receive, e.g. `ECONNREFUSED` on Linux with `IP_RECVERR` or
+/// `WSAECONNRESET` on Windows.
+pub fn is_transient_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Returns `true` if the error indicates that the socket was closed.
+pub fn is_socket_closed(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CLOSED_ERRORS: &[i32] = &[libc::EBADF, libc::ENOTSOCK];
+
+    #[cfg(windows)]
+    const CLOSED_ERRORS: &[i32] = &[
+        10038, // WSAENOTSOCK
+        10058, // WSAESHUTDOWN
+    ];
+
+    #[cfg(not(any(unix, windows)))]
+    const CLOSED_ERRORS: &[i32] = &[];
+
+    err.kind() == io::ErrorKind::NotConnected
+        || matches!(err.raw_os_error(), Some(rc) if CLOSED_ERRORS.contains(&rc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+    use socket2::SockRef;
+
+    #[test]
+    fn jitter_within_range() {
+        let max = Duration::from_millis(100);
+        let mut jitter = Jitter::new(max);
+        let delays: Vec<_> = (0..100).map(|_| jitter.next_delay()).collect();
+
+        assert!(delays.iter().all(|&d| d < max));
+        assert!(delays.iter().any(|&d| d < max / 2));
+        assert!(delays.iter().any(|&d| d >= max / 2));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn closed_socket_os_errors() {
+        let closed = |rc| is_socket_closed(&io::Error::from_raw_os_error(rc));
+
+        assert!(closed(libc::EBADF));
+        assert!(closed(libc::ENOTSOCK));
+        assert!(!closed(libc::ECONNREFUSED));
+        assert!(!closed(libc::EAGAIN));
+    }
+
+    #[test]
+    fn bind_udp_ip_opts() {
+        let opts = SocketOpts {
+            ttl: Some(7),
+            dscp: Some(46),
+            ..SocketOpts::default()
+        };
+
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &opts).unwrap();
+        let socket = SockRef::from(socket.get_ref());
+        assert_eq!(socket.ttl().unwrap(), 7);
+        assert_eq!(socket.tos().unwrap(), 46 << 2);
+
+        let socket = bind_udp("[::1]:0".parse().unwrap(), &opts).unwrap();
+        let socket = SockRef::from(socket.get_ref());
+        assert_eq!(socket.unicast_hops_v6().unwrap(), 7);
+    }
+
+    #[test]
+    fn bind_udp_buffer_sizes() {
+        // Within the default limits of Linux, `net.core.rmem_max` and
+        // `net.core.wmem_max`.
+        let opts = SocketOpts {
+            recv_buffer_size: Some(96 * 1024),
+            send_buffer_size: Some(48 * 1024),
+            ..SocketOpts::default()
+        };
+
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), &opts).unwrap();
+        let socket = SockRef::from(socket.get_ref());
+        // Linux doubles the values for bookkeeping overhead.
+        assert!(socket.recv_buffer_size().unwrap() >= 96 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 48 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn route_mtu_loopback() {
+        // Usually 65536, but it is configurable
+        let mtu = route_mtu("127.0.0.1:69".parse().unwrap()).unwrap();
+        assert!(mtu >= 1500);
+
+        let mtu = route_mtu("[::1]:69".parse().unwrap()).unwrap();
+        assert!(mtu >= 1500);
+    }
+
+    #[test]
+    fn bind_udp_default_opts() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let socket = bind_udp(addr, &SocketOpts::default()).unwrap();
+        let socket = SockRef::from(socket.get_ref());
+        assert_eq!(socket.tos().unwrap(), 0);
+    }
+
+    /// Receive datagrams until none arrives for a while.
+    fn recv_all(socket: &UdpSocket) -> Vec<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        let mut datagrams = Vec::new();
+
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+        while let Ok(len) = socket.recv(&mut buf) {
+            datagrams.push(buf[..len].to_vec());
+        }
+
+        datagrams
+    }
+
+    #[test]
+    fn send_packets_parity() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = receiver.local_addr().unwrap();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let socket = bind_udp(addr, &SocketOpts::default()).unwrap();
+
+        let packets: Vec<Vec<u8>> =
+            (0..40u8).map(|i| vec![i; 1 + usize::from(i) * 30]).collect();
+        let packets: Vec<&[u8]> = packets.iter().map(|p| &p[..]).collect();
+
+        block_on(send_packets(&socket, &packets, peer)).unwrap();
+        let batched = recv_all(&receiver);
+
+        block_on(send_packets_each(&socket, &packets, peer)).unwrap();
+        let each = recv_all(&receiver);
+
+        assert_eq!(batched, packets);
+        assert_eq!(batched, each);
+
+        block_on(send_packets(&socket, &[], peer)).unwrap();
+        assert!(recv_all(&receiver).is_empty());
+    }
+}